@@ -4,8 +4,20 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Action {
     LoadParts,
+    /// Look at the object already at the destination before starting.
+    CheckDestination {
+        attempt: u32,
+    },
+    ListUploads {
+        attempt: u32,
+    },
     StartUpload {
         attempt: u32,
+        existing: Vec<String>,
+    },
+    Abandon {
+        attempt: u32,
+        existing: Vec<String>,
     },
     Terminate,
     Abort {
@@ -18,10 +30,54 @@ pub enum Action {
         attempt: u32,
         parts: Vec<Part>,
     },
+    HashPart {
+        index: usize,
+        attempt: u32,
+        part: Part,
+    },
     UploadPart {
         upload_id: String,
         index: usize,
         attempt: u32,
         part: Part,
     },
+    /// Wait for workers to report uploading parts.
+    CollectParts {
+        upload_id: String,
+        index: usize,
+        attempt: u32,
+    },
+    /// Read the completed object back part by part, checking each against
+    /// its md5.
+    Verify {
+        attempt: u32,
+        version_id: Option<String>,
+        parts: Vec<Part>,
+    },
+    /// Put the index of the source files in place, unless the object's
+    /// metadata already holds it.
+    WriteIndex {
+        attempt: u32,
+        parts: Vec<Part>,
+    },
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::LoadParts => "load_parts",
+            Action::CheckDestination { .. } => "check_destination",
+            Action::ListUploads { .. } => "list_uploads",
+            Action::StartUpload { .. } => "start_upload",
+            Action::Abandon { .. } => "abandon",
+            Action::Terminate => "terminate",
+            Action::Abort { .. } => "abort",
+            Action::Complete { .. } => "complete",
+            Action::HashPart { .. } => "hash_part",
+            Action::UploadPart { .. } => "upload_part",
+            Action::CollectParts { .. } => "collect_parts",
+            Action::Verify { .. } => "verify",
+            Action::WriteIndex { .. } => "write_index",
+        }
+    }
 }
@@ -1,72 +1,350 @@
 use crate::actions::*;
+use crate::archive::Archive;
+use crate::backend::{
+    self, Backend, CompletedPart, Completion, ObjectInfo, ObjectSettings, S3Error, MIN_PART_SIZE,
+};
+use crate::compress::{self, Codec};
+use crate::coordinate::{self, Claims, Coordination, Job, Report};
+use crate::error::Error;
+use crate::events::{Event, EventKind, EventSink};
+use crate::index::{self, Index};
+use crate::metrics::Metrics;
+use crate::progress::Progress;
 use crate::result::Result;
 use crate::state::*;
-use crate::upload;
+use crate::throttle::Throttle;
+use crate::upload::{self, Order, PartData, Transfer};
 use crate::wal::*;
-use rusoto_s3::S3Client;
+use futures::future;
+use futures::StreamExt;
+use std::cmp;
 use std::mem;
-use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::time::delay_for;
 
-pub struct App {
-    pub s3client: S3Client,
+/// The error code of a compressed part too small to upload, as S3 gives
+/// when completing with one.
+static TOO_SMALL: &str = "EntityTooSmall";
+
+/// Asks a running upload to stop. The upload finishes the request in flight
+/// and logs its result, then stops before the next action, so running again
+/// with the same log resumes it.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop at once, for when the upload is no longer this process's to
+    /// run: part bodies being sent fail, and the request in flight is
+    /// neither logged nor followed by another.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+}
+
+/// Copies the log to an object next to the upload's after every few
+/// operations, so another host can resume the upload if this one is lost.
+pub struct Mirror {
+    pub key: String,
+    pub every: usize,
+    pending: usize,
+}
+
+impl Mirror {
+    pub fn new(key: &str, every: usize) -> Self {
+        Mirror {
+            key: Mirror::sidecar_key(key),
+            every,
+            pending: 0,
+        }
+    }
+
+    /// The key of the object holding the mirrored log of an upload to `key`.
+    pub fn sidecar_key(key: &str) -> String {
+        format!("{}.s3mu-wal", key)
+    }
+}
+
+pub struct App<B: Backend> {
+    pub backend: B,
     pub bucket: String,
     pub key: String,
     pub max_attempts: u32,
-    pub log: Wal<Operation>,
+    pub log: Box<dyn Storage<Operation>>,
     pub state: State,
     pub pattern: String,
+    pub order: Order,
+    /// Pack the files into parts of this size instead of a part per file.
+    pub pack: Option<u64>,
+    /// Upload a directory as an archive instead of files matching the pattern.
+    pub archive: Option<Archive>,
+    /// Compress each part with this codec as it is uploaded.
+    pub compress: Option<Codec>,
+    pub buffer_threshold: u64,
+    pub throttle: Arc<Throttle>,
+    pub progress: Arc<Progress>,
+    pub events: Vec<EventSink>,
+    pub metrics: Option<Arc<Metrics>>,
+    pub cancel: CancelToken,
+    pub mirror: Option<Mirror>,
+    /// Set when workers upload the parts instead.
+    pub coordination: Option<Coordination>,
+    /// Stop instead of completing or aborting the upload, leaving that to a
+    /// process that can, e.g. one holding credentials.
+    pub handoff: bool,
+    /// Hash the parts before starting, and skip the upload if the object
+    /// at the key is already the one it would create.
+    pub skip_identical: bool,
+    /// Check there is no object at the key before starting, complete only
+    /// if there still isn't, and if there is, stop in the refused state
+    /// instead of replacing it. See `Backend::complete_upload_if_absent`
+    /// for how far stores guarantee that.
+    pub no_overwrite: bool,
+    /// Once completed, read the object back part by part and check each
+    /// part's md5.
+    pub verify: bool,
+    /// Index the source files in the object, in its metadata when small
+    /// enough and else in a sidecar object.
+    pub index: bool,
+    /// The size every part but the last must reach, checked before sending
+    /// compressed parts, whose size isn't known until then.
+    pub min_part_size: u64,
+    /// A part already read and hashed, ready to upload: the one just hashed,
+    /// or the next one, read while the last was uploading.
+    buffered: Option<(usize, PartData)>,
+    /// The upload whose parts have been assigned to workers.
+    assigned: Option<String>,
+    claims: Claims,
 }
 
-impl App {
+impl<B: Backend> App<B> {
     pub async fn new(
-        s3client: S3Client,
+        backend: B,
         bucket: &str,
         key: &str,
         max_attempts: u32,
-        log_file: &Path,
+        log: Box<dyn Storage<Operation>>,
         pattern: &str,
+        buffer_threshold: u64,
     ) -> Result<Self> {
-        let log: Wal<Operation> = Wal::open(log_file).await?;
         let mut state = State::new();
 
-        for entry in log.entries.iter() {
+        for entry in log.entries().iter() {
             state = state.apply(entry.action.to_owned())?;
         }
 
         Ok(App {
-            s3client,
+            backend,
             bucket: bucket.to_owned(),
             key: key.to_owned(),
             max_attempts,
             log,
             state,
             pattern: pattern.to_owned(),
+            order: Order::default(),
+            pack: None,
+            archive: None,
+            compress: None,
+            buffer_threshold,
+            throttle: Arc::new(Throttle::unlimited()),
+            progress: Arc::new(Progress::new()),
+            events: vec![],
+            metrics: None,
+            cancel: CancelToken::new(),
+            mirror: None,
+            coordination: None,
+            handoff: false,
+            skip_identical: false,
+            no_overwrite: false,
+            verify: false,
+            index: false,
+            buffered: None,
+            min_part_size: MIN_PART_SIZE,
+            assigned: None,
+            claims: Claims::default(),
         })
     }
 
     pub async fn apply(&mut self, op: Operation) -> Result<()> {
-        self.log.append(WalEntry::new(op.clone())).await?;
+        let entry = WalEntry::new(op.clone());
+        // the upload id and the final outcome are mirrored straight away
+        let urgent = matches!(
+            op,
+            Operation::Started { .. }
+                | Operation::Completed { .. }
+                | Operation::Skipped { .. }
+                | Operation::Refused { .. }
+                | Operation::Aborted
+                | Operation::Verified
+                | Operation::VerificationFailed { .. }
+                | Operation::Indexed { .. }
+        );
 
         let mut temp = State::Aborted;
         mem::swap(&mut temp, &mut self.state);
 
+        let configured = matches!(op, Operation::ConfiguredParts(_));
+        let from = temp.name();
+        let applied = self.describe_operation(&temp, &op);
+
         temp = temp.apply(op)?;
         mem::swap(&mut temp, &mut self.state);
 
+        self.log.append(entry, self.state.name()).await?;
+
+        if let Some(ref mut mirror) = self.mirror {
+            mirror.pending += 1;
+        }
+        self.mirror_log(urgent).await;
+
+        if configured {
+            if let Some(parts) = self.state.parts() {
+                self.progress.set_parts(parts);
+            }
+        }
+
+        for event in applied {
+            self.emit(event);
+        }
+        let to = self.state.name();
+        if from != to {
+            self.emit(EventKind::StateTransition { from, to });
+        }
+
         Ok(())
     }
 
+    pub fn emit(&mut self, kind: EventKind) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.observe(&kind);
+        }
+        if !self.events.is_empty() {
+            let event = Event::new(kind);
+            for sink in self.events.iter_mut() {
+                sink.emit(&event);
+            }
+        }
+    }
+
+    /// The events describing an operation applied in a given state.
+    fn describe_operation(&self, state: &State, op: &Operation) -> Vec<EventKind> {
+        let part = |index: usize| state.parts().and_then(|parts| parts.get(index));
+        let number = |index: usize| part(index).map(|part| part.number);
+
+        let (part_number, bytes, etag) = match *op {
+            Operation::HashedPart { index, size, .. } => (number(index), Some(size), None),
+            Operation::UploadedPart { index, ref etag, size, .. } => (
+                number(index),
+                size.or_else(|| part(index).and_then(|part| part.size)),
+                Some(etag.to_owned()),
+            ),
+            Operation::FailedPart { index, .. } => (number(index), None, None),
+            Operation::VerificationFailed { part_number, .. } => (Some(part_number), None, None),
+            _ => (None, None, None),
+        };
+
+        let retry = match *op {
+            Operation::FailedStart { attempt, ref msg, ref code } => Some(("start", attempt, msg, code)),
+            Operation::FailedPart { attempt, ref msg, ref code, .. } => Some(("upload_part", attempt, msg, code)),
+            Operation::FailedComplete { attempt, ref msg, ref code } => Some(("complete", attempt, msg, code)),
+            Operation::FailedAbort { attempt, ref msg, ref code } => Some(("abort", attempt, msg, code)),
+            Operation::FailedVerify { attempt, ref msg, ref code } => Some(("verify", attempt, msg, code)),
+            Operation::FailedIndex { attempt, ref msg, ref code } => Some(("index", attempt, msg, code)),
+            _ => None,
+        };
+
+        let mut events = vec![EventKind::OperationApplied {
+            operation: op.name(),
+            part_number,
+            bytes,
+            etag,
+            error_code: retry.and_then(|(_, _, _, code)| code.to_owned()),
+        }];
+
+        // the last attempt's failure isn't retried
+        let retried = retry.filter(|&(_, attempt, _, _)| attempt + 1 < self.max_attempts);
+        if let Some((stage, attempt, msg, code)) = retried {
+            events.push(EventKind::Retry {
+                stage,
+                part_number,
+                attempt: attempt + 1,
+                error_code: code.to_owned(),
+                error: msg.to_owned(),
+            });
+        }
+
+        events
+    }
+
+    fn summary(&self) -> EventKind {
+        let progress = self.progress.snapshot();
+
+        EventKind::Summary {
+            state: self.state.name(),
+            bucket: self.bucket.to_owned(),
+            key: self.key.to_owned(),
+            upload_id: self.state.upload_id().map(|id| id.to_owned()),
+            parts: progress.total_parts,
+            bytes: progress.total_bytes,
+            etag: self.state.completion().and_then(|c| c.etag.to_owned()),
+            version_id: self.state.completion().and_then(|c| c.version_id.to_owned()),
+        }
+    }
+
     pub fn next_action(&self) -> Action {
         match self.state {
             State::Init => Action::LoadParts,
-            State::Starting { ref parts, attempt } => {
-                if attempt == self.max_attempts {
-                    Action::Terminate
-                } else {
-                    Action::StartUpload {
-                        attempt: attempt,
-                    }
+            State::Starting {
+                ref parts,
+                attempt,
+                checked: false,
+                ..
+            } if self.checks_destination() && attempt < self.max_attempts => {
+                match parts.iter().position(|part| part.md5.is_none()) {
+                    Some(index) if self.skip_identical => Action::HashPart {
+                        index,
+                        attempt,
+                        part: parts[index].to_owned(),
+                    },
+                    _ => Action::CheckDestination { attempt },
                 }
+            }
+            State::Starting {
+                attempt,
+                ref existing,
+                ..
+            } => match existing {
+                None if attempt == self.max_attempts => Action::Terminate,
+                None => Action::ListUploads { attempt },
+                Some(existing) if attempt == self.max_attempts => Action::Abandon {
+                    attempt: 0,
+                    existing: existing.to_owned(),
+                },
+                Some(existing) => Action::StartUpload {
+                    attempt,
+                    existing: existing.to_owned(),
+                },
             },
             State::Uploading {
                 ref parts,
@@ -74,29 +352,44 @@ impl App {
                 index,
                 attempt,
             } => {
+                let part = parts.get(index).unwrap().to_owned();
                 log::info!(
                     "uploading part {} attempt {} of {}",
-                    index + 1,
+                    part.number,
                     attempt,
                     self.max_attempts,
                 );
-                if attempt == self.max_attempts {
+                if attempt == self.max_attempts && self.handoff {
+                    Action::Terminate
+                } else if attempt == self.max_attempts {
                     Action::Abort {
                         upload_id: upload_id.to_owned(),
                         msg: format!(
                             "{} out of {} failures uploading part {}",
                             attempt,
                             self.max_attempts,
-                            index + 1,
+                            part.number,
                         ),
-                        attempt: 1,
+                        attempt: 0,
+                    }
+                } else if self.coordination.is_some() {
+                    Action::CollectParts {
+                        upload_id: upload_id.to_owned(),
+                        index,
+                        attempt,
+                    }
+                } else if part.md5.is_none() {
+                    Action::HashPart {
+                        index,
+                        attempt,
+                        part,
                     }
                 } else {
                     Action::UploadPart {
                         upload_id: upload_id.to_owned(),
                         index,
-                        attempt: attempt,
-                        part: parts.get(index).unwrap().to_owned(),
+                        attempt,
+                        part,
                     }
                 }
             }
@@ -109,27 +402,65 @@ impl App {
                     "completing upload attempt {} of {}",
                     attempt, self.max_attempts
                 );
-                if attempt == self.max_attempts {
+                if self.handoff {
+                    Action::Terminate
+                } else if attempt == self.max_attempts {
                     Action::Abort {
                         upload_id: upload_id.to_owned(),
                         msg: format!(
                             "{} out of {} failures completing upload",
                             attempt, self.max_attempts
                         ),
-                        attempt: 1,
+                        attempt: 0,
                     }
                 } else {
                     Action::Complete {
                         upload_id: upload_id.to_owned(),
-                        attempt: attempt,
+                        attempt,
+                        parts: parts.to_owned(),
+                    }
+                }
+            }
+            State::Completed {
+                ref completion,
+                ref parts,
+                attempt,
+                verification: None,
+                ..
+            } if self.verify => {
+                log::info!("verifying upload attempt {} of {}", attempt, self.max_attempts);
+                if attempt == self.max_attempts {
+                    Action::Terminate
+                } else {
+                    Action::Verify {
+                        attempt,
+                        version_id: completion.version_id.to_owned(),
                         parts: parts.to_owned(),
                     }
                 }
             }
-            State::Completed => Action::Terminate,
+            State::Completed {
+                ref parts,
+                attempt,
+                verification: None | Some(Verification::Verified),
+                indexed: false,
+                ..
+            } if self.index => {
+                log::info!("indexing upload attempt {} of {}", attempt, self.max_attempts);
+                if attempt == self.max_attempts {
+                    Action::Terminate
+                } else {
+                    Action::WriteIndex {
+                        attempt,
+                        parts: parts.to_owned(),
+                    }
+                }
+            }
+            State::Completed { .. } => Action::Terminate,
             State::Aborting {
                 ref upload_id,
                 attempt,
+                ..
             } => {
                 log::info!(
                     "aborting upload attempt {} of {}",
@@ -144,84 +475,340 @@ impl App {
                             "{} out of {} failures completing upload",
                             attempt, self.max_attempts
                         ),
-                        attempt: attempt,
+                        attempt,
+                    }
+                }
+            }
+            State::Aborted | State::Refused => Action::Terminate,
+            State::Abandoning {
+                attempt,
+                ref existing,
+            } => {
+                if attempt == self.max_attempts {
+                    Action::Terminate
+                } else {
+                    Action::Abandon {
+                        attempt,
+                        existing: existing.to_owned(),
                     }
                 }
             }
-            State::Aborted => Action::Terminate,
         }
     }
 
     pub async fn run(&mut self) -> Result<()> {
+        if let Some(parts) = self.state.parts() {
+            self.progress.set_parts(parts);
+        }
+        if let Some(ref metrics) = self.metrics {
+            metrics.set_state(self.state.name());
+        }
+
+        let result = self.run_actions().await;
+        self.mirror_log(true).await;
+        if let Some(ref coordination) = self.coordination {
+            if let State::Completed { .. } | State::Aborted | State::Refused = self.state {
+                if let Err(err) = coordinate::finish(&coordination.dir).await {
+                    log::warn!("{}", err);
+                }
+            }
+        }
+        self.progress.finish();
+        if let Some(ref metrics) = self.metrics {
+            metrics.finish();
+        }
+        let summary = self.summary();
+        self.emit(summary);
+        result
+    }
+
+    async fn run_actions(&mut self) -> Result<()> {
         loop {
+            if self.cancel.is_cancelled() {
+                log::info!("cancelled in the {} state", self.state.name());
+                break;
+            }
+
             let next_action = self.next_action();
 
             log::info!("action: {:?}", next_action);
 
+            let (part_number, attempt) = match next_action {
+                Action::ListUploads { attempt }
+                | Action::CheckDestination { attempt }
+                | Action::StartUpload { attempt, .. }
+                | Action::Abandon { attempt, .. } => (None, Some(attempt)),
+                Action::HashPart { attempt, ref part, .. }
+                | Action::UploadPart { attempt, ref part, .. } => (Some(part.number), Some(attempt)),
+                Action::Complete { attempt, .. }
+                | Action::Abort { attempt, .. }
+                | Action::CollectParts { attempt, .. }
+                | Action::Verify { attempt, .. }
+                | Action::WriteIndex { attempt, .. } => (None, Some(attempt)),
+                Action::LoadParts | Action::Terminate => (None, None),
+            };
+            self.emit(EventKind::ActionStarted {
+                action: next_action.name(),
+                part_number,
+                attempt,
+            });
+
+            let request = match next_action {
+                Action::ListUploads { .. }
+                | Action::CheckDestination { .. }
+                | Action::StartUpload { .. }
+                | Action::Abandon { .. }
+                | Action::UploadPart { .. }
+                | Action::Complete { .. }
+                | Action::Abort { .. }
+                | Action::Verify { .. }
+                | Action::WriteIndex { .. } => Some(next_action.name()),
+                _ => None,
+            };
+            let started = Instant::now();
+
             let op = match next_action {
                 Action::Terminate => {
                     break;
                 },
+                Action::LoadParts if self.coordination.is_some() => {
+                    let manifest = self.coordination.as_ref().map(|c| c.manifest.to_owned()).unwrap_or_default();
+                    Operation::ConfiguredParts(coordinate::read_manifest(&manifest)?)
+                },
+                Action::LoadParts if self.archive.is_some() => {
+                    let parts = self.archive.as_ref().map(Archive::parts).unwrap_or_else(|| Ok(vec![]));
+                    Operation::ConfiguredParts(parts.map_err(|err| format!("archive error: {}", err))?)
+                },
                 Action::LoadParts => {
-                    let mut parts = vec![];
-                    let paths = upload::get_parts(&self.pattern).map_err(|err| format!("get part files error: {}", err))?;
-                    let mut i = 1;
-                    for path in paths {
-                        parts.push(Part::new(i, path.to_str().ok_or_else(|| format!("error handling non utf8 path"))?.to_owned()));
-                        i += 1;
+                    let mut parts = upload::get_parts(&self.pattern, &self.order).map_err(|err| format!("get part files error: {}", err))?;
+                    if let Some(size) = self.pack {
+                        parts = upload::pack(&parts, size).map_err(|err| format!("pack part files error: {}", err))?;
                     }
                     Operation::ConfiguredParts(parts)
                 },
-                Action::UploadPart {
-                    ref upload_id,
+                Action::HashPart {
                     attempt,
                     index,
                     ref part,
                 } => {
-                    let part_number = (index + 1) as i64;
-
-                    match upload::upload_part(
-                        &self.s3client,
-                        &PathBuf::from(&part.path),
-                        &self.bucket,
-                        &self.key,
-                        upload_id,
-                        part_number,
-                    )
-                    .await
-                    .map_err(|err| format!("upload part error: {:?}", err))
-                    .and_then(|part| part.e_tag.ok_or_else(|| format!("missing etag in uploaded part")))
-                    {
-                        Ok(etag) => Operation::UploadedPart {
-                            index,
-                            etag: etag,
+                    let data = match self.take_buffered(index) {
+                        Some(data) => Ok(data),
+                        None => self.load_part(part, None).await,
+                    };
+                    match data {
+                        Ok(data) => {
+                            let op = Operation::HashedPart {
+                                index,
+                                size: data.len,
+                                md5: data.md5.to_owned(),
+                            };
+                            self.buffered = Some((index, data));
+                            op
                         },
                         Err(err) => Operation::FailedPart {
                             index,
                             attempt,
-                            msg: format!("{:?}", err),
+                            msg: format!("hash part error: {}", err),
+                            code: None,
+                        },
+                    }
+                },
+                Action::UploadPart {
+                    ref upload_id,
+                    attempt,
+                    index,
+                    ref part,
+                } => {
+                    let data = match self.take_buffered(index) {
+                        Some(data) => Ok(data),
+                        None => self.load_part(part, part.digest()).await,
+                    };
+                    // every part but the last must be large enough, which a
+                    // compressed part can only be told once compressed
+                    let last = self.state.parts().is_none_or(|parts| index + 1 == parts.len());
+                    let data = match data {
+                        Ok(ref data) if self.compress.is_some() && !last && data.len < self.min_part_size => {
+                            Err(S3Error::coded(
+                                TOO_SMALL,
+                                format!(
+                                    "part {} compresses to {} bytes, under the {} bytes of all parts but the last, \
+                                     so make the parts larger, e.g. with --part-size or --pack",
+                                    part.number, data.len, self.min_part_size
+                                ),
+                            )
+                            .into())
+                        }
+                        data => data,
+                    };
+                    // a part compressed again needn't come out as it did when
+                    // it was hashed, so what is sent is recorded
+                    let sent = match data {
+                        Ok(ref data) if self.compress.is_some() => Some((data.len, data.md5.to_owned())),
+                        _ => None,
+                    };
+
+                    let size = part.source_size().unwrap_or(0);
+                    let body_len = data.as_ref().map_or(size, |data| data.len);
+                    let transfer = Transfer {
+                        throttle: self.throttle.clone(),
+                        sent: self.progress.start_part(part.number, size, body_len),
+                        cancel: self.cancel.clone(),
+                    };
+
+                    let upload = async {
+                        match data {
+                            Ok(data) => upload::upload_part(
+                                &self.backend,
+                                data,
+                                &transfer,
+                                &self.bucket,
+                                &self.key,
+                                upload_id,
+                                part.number,
+                            )
+                            .await,
+                            Err(err) => Err(err),
+                        }
+                    };
+                    let (result, ahead) = future::join(upload, self.read_ahead(index)).await;
+                    if ahead.is_some() {
+                        self.buffered = ahead;
+                    }
+
+                    match result {
+                        Ok(etag) => {
+                            self.progress.part_done(part.number, size);
+                            let (size, md5) = sent.unzip();
+                            Operation::UploadedPart {
+                                index,
+                                etag,
+                                size,
+                                md5,
+                            }
+                        },
+                        Err(err) => {
+                            self.progress.part_failed(part.number);
+                            let code = backend::error_code(&err);
+                            // compressing the part again gives the same size,
+                            // so it fails as the last attempt
+                            let attempt = if code.as_deref() == Some(TOO_SMALL) {
+                                log::error!("{}", err);
+                                cmp::max(attempt, self.max_attempts.saturating_sub(1))
+                            } else {
+                                attempt
+                            };
+                            Operation::FailedPart {
+                                index,
+                                attempt,
+                                msg: format!("upload part error: {}", err),
+                                code,
+                            }
                         },
                     }
                 },
+                Action::CollectParts { ref upload_id, .. } => {
+                    match self.collect_part(upload_id).await? {
+                        Some((reported, Report::Done { worker, etag, size, md5, .. })) => {
+                            if let Some(part) = self.state.parts().and_then(|parts| parts.get(reported)) {
+                                log::info!("part {} uploaded by {}", part.number, worker);
+                                self.progress.part_done(part.number, size);
+                            }
+                            Operation::UploadedPart {
+                                index: reported,
+                                etag,
+                                size: Some(size),
+                                md5: Some(md5),
+                            }
+                        },
+                        Some((reported, Report::Failed { worker, msg, code, .. })) => Operation::FailedPart {
+                            index: reported,
+                            attempt: self.failures(reported),
+                            msg: format!("worker {} failed: {}", worker, msg),
+                            code,
+                        },
+                        None => break,
+                    }
+                },
                 Action::Abort {
                     ref upload_id,
                     attempt,
-                    ref msg,
+                    ..
                 } => {
-                    match upload::abort_upload(&self.s3client, &self.bucket, &self.key, upload_id)
-                        .await
+                    match self.backend.abort_upload(&self.bucket, &self.key, upload_id).await
                     {
                         Ok(()) => Operation::Aborted,
+                        Err(ref err) if no_such_upload(err) => {
+                            log::info!("upload {} was already aborted", upload_id);
+                            Operation::Aborted
+                        },
                         Err(err) => Operation::FailedAbort {
                             msg: format!("error aborting upload: {}", err),
                             attempt,
+                            code: backend::error_code(&err),
+                        },
+                    }
+                },
+                Action::CheckDestination {
+                    attempt,
+                } => {
+                    let parts = self.state.parts().unwrap_or_default();
+                    match self.backend.head_object(&self.bucket, &self.key).await {
+                        Ok(Some(ref object)) if self.skip_identical && is_ours(object, parts) => {
+                            log::info!("{} already holds these parts, skipping the upload", self.key);
+                            Operation::Skipped {
+                                reason: "identical".to_owned(),
+                                etag: object.etag.to_owned(),
+                                version_id: object.version_id.to_owned(),
+                                size: Some(object.size),
+                            }
+                        },
+                        Ok(Some(_)) if self.no_overwrite => Operation::Refused {
+                            reason: format!("{} already exists", self.key),
+                        },
+                        Ok(_) => Operation::CheckedDestination,
+                        Err(err) => Operation::FailedStart {
+                            attempt,
+                            msg: format!("error checking destination: {}", err),
+                            code: backend::error_code(&err),
+                        },
+                    }
+                },
+                Action::ListUploads {
+                    attempt,
+                } => {
+                    match self.backend.list_uploads(&self.bucket, &self.key).await {
+                        Ok(upload_ids) => Operation::ListedUploads {
+                            upload_ids,
+                        },
+                        Err(err) => Operation::FailedStart {
+                            attempt,
+                            msg: format!("error listing uploads: {}", err),
+                            code: backend::error_code(&err),
+                        },
+                    }
+                },
+                Action::Abandon {
+                    attempt,
+                    ref existing,
+                } => {
+                    match self.abort_orphans(existing).await {
+                        Ok(()) => Operation::Aborted,
+                        Err(err) => Operation::FailedAbort {
+                            msg: format!("error aborting uploads: {}", err),
+                            attempt,
+                            code: backend::error_code(&err),
                         },
                     }
                 },
                 Action::StartUpload {
                     attempt,
+                    ref existing,
                 } => {
-                    match upload::start_upload(&self.s3client, &self.bucket, &self.key).await {
+                    let result = match self.abort_orphans(existing).await {
+                        Ok(()) => self.create_upload().await,
+                        Err(err) => Err(err),
+                    };
+                    match result {
                         Ok(upload_id) => {
                             Operation::Started {
                                 upload_id,
@@ -229,8 +816,9 @@ impl App {
                         },
                         Err(err) => {
                             Operation::FailedStart {
-                                attempt: attempt,
+                                attempt,
                                 msg: format!("error starting upload: {}", err),
+                                code: backend::error_code(&err),
                             }
                         }
                     }
@@ -240,27 +828,757 @@ impl App {
                     attempt,
                     ref parts,
                 } => {
-                    let completed_upload = rusoto_s3::CompletedMultipartUpload {
-                        parts: Some(parts.iter().map(|part| rusoto_s3::CompletedPart {
-                            e_tag: Some(part.etag.to_owned()),
-                            part_number: Some(part.number),
-                        }).collect()),
+                    let completed_parts = parts.iter().map(|part| CompletedPart {
+                        part_number: part.number,
+                        etag: part.etag.to_owned(),
+                    }).collect();
+                    let result = if self.no_overwrite {
+                        self.complete_if_absent(upload_id, completed_parts, parts).await
+                    } else {
+                        self.backend.complete_upload(&self.bucket, &self.key, upload_id, completed_parts).await
                     };
-                    match upload::complete_upload(&self.s3client, &self.bucket, &self.key, upload_id, completed_upload)
-                        .await
-                    {
-                        Ok(()) => Operation::Completed,
+                    match result {
+                        Ok(mut completion) => {
+                            if completion.size.is_none() {
+                                completion.size = parts.iter().map(|part| part.size).sum();
+                            }
+                            Operation::completed(completion)
+                        },
+                        Err(ref err) if backend::error_code(err).as_deref() == Some("PreconditionFailed") => {
+                            Operation::Refused {
+                                reason: format!("{} already exists", self.key),
+                            }
+                        },
+                        Err(ref err) if no_such_upload(err) => match self.find_completed(parts).await {
+                            Ok(Some(completion)) => {
+                                log::info!("upload {} was already completed", upload_id);
+                                Operation::completed(completion)
+                            },
+                            Ok(None) => Operation::FailedComplete {
+                                msg: format!("error completing upload: {}", err),
+                                attempt,
+                                code: backend::error_code(err),
+                            },
+                            Err(err) => Operation::FailedComplete {
+                                msg: format!("error checking for completed upload: {}", err),
+                                attempt,
+                                code: backend::error_code(&err),
+                            },
+                        },
                         Err(err) => Operation::FailedComplete {
-                            msg: format!("error aborting upload: {}", err),
+                            msg: format!("error completing upload: {}", err),
                             attempt,
+                            code: backend::error_code(&err),
+                        },
+                    }
+                },
+                Action::Verify {
+                    attempt,
+                    ref version_id,
+                    ref parts,
+                } => {
+                    match self.verify_parts(version_id.as_deref(), parts).await {
+                        Ok(None) => {
+                            log::info!("verified {} parts of {}", parts.len(), self.key);
+                            Operation::Verified
+                        },
+                        Ok(Some((part_number, reason))) => {
+                            log::warn!("part {} of {} failed verification: {}", part_number, self.key, reason);
+                            Operation::VerificationFailed {
+                                part_number,
+                                reason,
+                            }
+                        },
+                        Err(err) => Operation::FailedVerify {
+                            msg: format!("error verifying object: {}", err),
+                            attempt,
+                            code: backend::error_code(&err),
+                        },
+                    }
+                },
+                Action::WriteIndex {
+                    attempt,
+                    ref parts,
+                } => {
+                    match self.write_index(parts).await {
+                        Ok(key) => Operation::Indexed { key },
+                        Err(err) => Operation::FailedIndex {
+                            msg: format!("error writing index: {}", err),
+                            attempt,
+                            code: backend::error_code(&err),
                         },
                     }
                 }
             };
 
+            if let (Some(request), Some(metrics)) = (request, self.metrics.as_ref()) {
+                metrics.observe_request(request, started.elapsed());
+            }
+
+            if self.cancel.is_interrupted() {
+                log::warn!("interrupted in the {} state, not logging {}", self.state.name(), op.name());
+                break;
+            }
             self.apply(op).await?;
         }
 
         Ok(())
     }
+
+    fn checks_destination(&self) -> bool {
+        self.skip_identical || self.no_overwrite
+    }
+
+    /// Complete the upload unless another object has appeared at the key
+    /// since it started. An object already there is taken to be this
+    /// upload's, completed by an attempt whose response was lost, only if
+    /// its etag matches the parts.
+    async fn complete_if_absent(
+        &self,
+        upload_id: &str,
+        completed_parts: Vec<CompletedPart>,
+        parts: &[Part],
+    ) -> Result<Completion> {
+        let result = self
+            .backend
+            .complete_upload_if_absent(&self.bucket, &self.key, upload_id, completed_parts)
+            .await;
+        match result {
+            Err(ref err) if backend::error_code(err).as_deref() == Some("PreconditionFailed") => {
+                match self.find_completed(parts).await? {
+                    Some(completion) => {
+                        log::info!("{} is already the object these parts make", self.key);
+                        Ok(completion)
+                    }
+                    None => result,
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Read a part ready to upload, compressing it if the job compresses
+    /// parts. Compressing again may not give the md5 of an earlier attempt,
+    /// so `cached` only checks parts uploaded as they are.
+    async fn load_part(&self, part: &Part, cached: Option<(u64, String)>) -> Result<PartData> {
+        match self.compress {
+            Some(codec) => codec.compress_part(part, self.buffer_threshold).await,
+            None => upload::load_part(part, cached, self.buffer_threshold).await,
+        }
+    }
+
+    /// The part at `index`, if it has already been read.
+    fn take_buffered(&mut self, index: usize) -> Option<PartData> {
+        match self.buffered.take() {
+            Some((buffered, data)) if buffered == index => Some(data),
+            other => {
+                self.buffered = other;
+                None
+            }
+        }
+    }
+
+    /// Read and hash the part after `index` if it hasn't been, so that is
+    /// done while the part at `index` uploads rather than after. A part
+    /// that can't be read is left to fail in its own turn.
+    async fn read_ahead(&self, index: usize) -> Option<(usize, PartData)> {
+        let next = index + 1;
+        let part = self.state.parts()?.get(next)?;
+        if part.md5.is_some() || self.buffered.as_ref().is_some_and(|(buffered, _)| *buffered == next) {
+            return None;
+        }
+        match self.load_part(part, None).await {
+            Ok(data) => Some((next, data)),
+            Err(err) => {
+                log::debug!("error reading part {} ahead: {}", part.number, err);
+                None
+            }
+        }
+    }
+
+    /// Create the upload, with the index of the source files in its
+    /// metadata if the job indexes them and the index is small enough, and
+    /// the codec and encoding if it compresses them. Stores that don't keep
+    /// metadata get the index in a sidecar instead, and no encoding.
+    async fn create_upload(&self) -> Result<String> {
+        let parts = self.state.parts().unwrap_or(&[]);
+        let mut settings = ObjectSettings::default();
+        if self.index && Index::layout(parts)?.fits_metadata() {
+            if let Some(metadata) = Index::build(parts).await?.to_metadata() {
+                settings.metadata = metadata;
+            }
+        }
+        if let Some(codec) = self.compress {
+            settings.metadata.insert(compress::METADATA_KEY.to_owned(), codec.to_string());
+            settings.content_encoding = Some(codec.content_encoding().to_owned());
+        }
+
+        if settings != ObjectSettings::default() {
+            match self.backend.create_upload_with(&self.bucket, &self.key, settings).await {
+                Err(ref err) if backend::error_code(err).as_deref() == Some("NotImplemented") => {}
+                result => return result,
+            }
+        }
+        self.backend.create_upload(&self.bucket, &self.key).await
+    }
+
+    /// Write the index of the source files to the sidecar, returning its
+    /// key, unless the object's metadata already holds the same index.
+    async fn write_index(&self, parts: &[Part]) -> Result<Option<String>> {
+        let index = Index::build(parts).await?;
+        let json = serde_json::to_string(&index)?;
+
+        let object = self.backend.head_object(&self.bucket, &self.key).await?;
+        if object.is_some_and(|object| object.metadata.get(index::METADATA_KEY) == Some(&json)) {
+            log::info!("index of {} files is in the metadata of {}", index.files.len(), self.key);
+            return Ok(None);
+        }
+
+        let key = Index::sidecar_key(&self.key);
+        self.backend.put_object(&self.bucket, &key, json.into_bytes()).await?;
+        log::info!("wrote index of {} files to {}", index.files.len(), key);
+        Ok(Some(key))
+    }
+
+    /// Read each part of the completed object back and compare it with the
+    /// size and md5 it was uploaded with, returning the first part that
+    /// differs and how.
+    async fn verify_parts(&self, version_id: Option<&str>, parts: &[Part]) -> Result<Option<(i64, String)>> {
+        for (position, part) in (1..).zip(parts) {
+            let (size, md5) = match part.digest() {
+                Some(digest) => digest,
+                None => return Ok(Some((part.number, "no md5 was recorded for it".to_owned()))),
+            };
+
+            let stream = match self.backend.get_part(&self.bucket, &self.key, version_id, position).await {
+                Ok(Some(stream)) => stream,
+                Ok(None) => return Ok(Some((part.number, format!("{} is gone", self.key)))),
+                Err(ref err) if backend::error_code(err).as_deref() == Some("InvalidPartNumber") => {
+                    return Ok(Some((part.number, "the object has no such part".to_owned())))
+                }
+                Err(err) => return Err(err),
+            };
+
+            let mut digest = md5::Context::new();
+            let mut read = 0;
+            let mut stream = stream;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                digest.consume(&chunk);
+                read += chunk.len() as u64;
+            }
+
+            if read != size {
+                return Ok(Some((part.number, format!("read {} bytes, expected {}", read, size))));
+            }
+            let found = base64::encode(digest.compute().0);
+            if found != md5 {
+                return Ok(Some((part.number, format!("md5 {} differs from the uploaded {}", found, md5))));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Copy the log to the mirror if enough operations are waiting, or any
+    /// are and `now` is set. Failures are logged and retried with the next
+    /// batch, since a stale mirror only means repeating some work on resume.
+    async fn mirror_log(&mut self, now: bool) {
+        let key = match self.mirror {
+            Some(ref mirror) if mirror.pending > 0 && (now || mirror.pending >= mirror.every) => {
+                mirror.key.to_owned()
+            }
+            _ => return,
+        };
+
+        let mut data = vec![];
+        for entry in self.log.entries() {
+            if let Err(err) = serde_json::to_writer(&mut data, entry) {
+                log::warn!("error serialising log entry for mirror: {}", err);
+                return;
+            }
+            data.push(b'\n');
+        }
+
+        match self.backend.put_object(&self.bucket, &key, data).await {
+            Ok(()) => {
+                if let Some(ref mut mirror) = self.mirror {
+                    mirror.pending = 0;
+                }
+            }
+            Err(err) => log::warn!("error mirroring log to {}: {}", key, err),
+        }
+    }
+
+    /// How many times workers have reported failing a part of the current
+    /// upload. The state only counts the attempts at one part, and workers
+    /// report parts in any order, so failures are counted from the log.
+    fn failures(&self, index: usize) -> u32 {
+        let since_started = self
+            .log
+            .entries()
+            .iter()
+            .rev()
+            .take_while(|entry| !matches!(entry.action, Operation::Started { .. }));
+        since_started
+            .filter(|entry| matches!(entry.action, Operation::FailedPart { index: failed, .. } if failed == index))
+            .count() as u32
+    }
+
+    /// Assign the parts still to upload to workers, if not done already, and
+    /// wait for one of them to be reported. Returns `None` if cancelled.
+    async fn collect_part(&mut self, upload_id: &str) -> Result<Option<(usize, Report)>> {
+        let coordination = self.coordination.as_ref().ok_or("upload is not coordinated")?;
+        let parts = self.state.parts().unwrap_or_default();
+
+        if self.assigned.as_deref() != Some(upload_id) {
+            let job = Job {
+                store: coordination.store.to_owned(),
+                bucket: self.bucket.to_owned(),
+                key: self.key.to_owned(),
+                upload_id: upload_id.to_owned(),
+                claim_timeout: coordination.claim_timeout.as_secs(),
+            };
+            coordinate::assign(&coordination.dir, &job, parts).await?;
+            log::info!("assigned parts of upload {} in {:?}", upload_id, coordination.dir);
+            self.assigned = Some(upload_id.to_owned());
+        }
+
+        loop {
+            if let Some(report) = coordinate::collect(&coordination.dir, upload_id, parts).await? {
+                return Ok(Some(report));
+            }
+            self.claims
+                .release_stale(&coordination.dir, parts, coordination.claim_timeout)
+                .await?;
+            if self.cancel.is_cancelled() {
+                return Ok(None);
+            }
+            delay_for(coordination.poll).await;
+        }
+    }
+
+    /// Abort the uploads for the key that aren't in `existing`. While
+    /// starting, these can only have been created by earlier attempts whose
+    /// upload id never reached the log, because the response was lost or the
+    /// process stopped first.
+    async fn abort_orphans(&self, existing: &[String]) -> Result<()> {
+        for upload_id in self.backend.list_uploads(&self.bucket, &self.key).await? {
+            if existing.contains(&upload_id) {
+                continue;
+            }
+            log::info!("aborting upload {} left by an earlier attempt", upload_id);
+            match self.backend.abort_upload(&self.bucket, &self.key, &upload_id).await {
+                Err(ref err) if no_such_upload(err) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the object at the key is the one completing these parts
+    /// creates, for when a completion succeeded but its response was lost.
+    /// Stores that don't report etags never match.
+    async fn find_completed(&self, parts: &[Part]) -> Result<Option<Completion>> {
+        let object = match self.backend.head_object(&self.bucket, &self.key).await? {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+
+        if !is_ours(&object, parts) {
+            return Ok(None);
+        }
+
+        Ok(Some(Completion {
+            etag: object.etag,
+            version_id: object.version_id,
+            location: None,
+            size: Some(object.size),
+        }))
+    }
+}
+
+/// Whether an object is the one uploading these parts creates: the same
+/// size, and the etag made from the part md5s. A size alone could be
+/// anyone's, so an object without an etag, from a store that doesn't report
+/// them, never is.
+fn is_ours(object: &ObjectInfo, parts: &[Part]) -> bool {
+    let size = parts.iter().map(|part| part.size).sum::<Option<u64>>();
+    size == Some(object.size)
+        && match object.etag {
+            Some(ref found) => upload::composite_etag(parts).as_ref() == Some(found),
+            None => false,
+        }
+}
+
+fn no_such_upload(err: &Error) -> bool {
+    backend::error_code(err).as_deref() == Some("NoSuchUpload")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{FsBackend, MemoryBackend};
+    use crate::testing::scratch;
+    use std::path::Path;
+
+    static BUCKET: &str = "bucket";
+    static KEY: &str = "key";
+
+    /// An app uploading the files in `dir` to a store in memory taking
+    /// parts of a kilobyte, with its log in the directory too.
+    async fn app(dir: &Path, backend: MemoryBackend) -> App<MemoryBackend> {
+        let pattern = dir.join("part-*");
+        let log = Wal::open(&dir.join("log")).await.unwrap();
+        let mut app = App::new(backend, BUCKET, KEY, 3, Box::new(log), pattern.to_str().unwrap(), 1 << 20)
+            .await
+            .unwrap();
+        app.min_part_size = app.backend.min_part_size;
+        app
+    }
+
+    fn small_parts() -> MemoryBackend {
+        let mut backend = MemoryBackend::new();
+        backend.min_part_size = 1024;
+        backend
+    }
+
+    fn logged(app: &App<MemoryBackend>, name: &str) -> usize {
+        app.log.entries().iter().filter(|entry| entry.action.name() == name).count()
+    }
+
+    #[tokio::test]
+    async fn compressed_parts_too_small_abort_without_retrying() {
+        let dir = scratch("app-compressed-too-small");
+        std::fs::write(dir.join("part-1"), vec![0; 4096]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![0; 4096]).unwrap();
+
+        let mut app = app(&dir, small_parts()).await;
+        app.compress = Some("gzip".parse().unwrap());
+        app.run().await.unwrap();
+
+        assert!(matches!(app.state, State::Aborted), "{:?}", app.state);
+        assert_eq!(logged(&app, "failed_part"), 1);
+        assert_eq!(logged(&app, "uploaded_part"), 0);
+        assert!(app.backend.upload_ids().is_empty());
+        assert!(app.backend.object(BUCKET, KEY).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compressed_parts_large_enough_complete() {
+        let dir = scratch("app-compressed");
+        let noise: Vec<u8> = (0..256u32).flat_map(|i| md5::compute(i.to_le_bytes()).0.to_vec()).collect();
+        std::fs::write(dir.join("part-1"), noise).unwrap();
+        std::fs::write(dir.join("part-2"), vec![0; 4096]).unwrap();
+
+        let mut app = app(&dir, small_parts()).await;
+        app.compress = Some("gzip".parse().unwrap());
+        app.run().await.unwrap();
+
+        assert!(matches!(app.state, State::Completed { .. }), "{:?}", app.state);
+        assert_eq!(logged(&app, "failed_part"), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Log an upload of `part-1` and `part-2` in `dir` started, with
+    /// `hashed` of them hashed, as a run stopped then would have.
+    async fn started(dir: &Path, backend: &MemoryBackend, hashed: usize) {
+        let paths = [dir.join("part-1"), dir.join("part-2")];
+        let parts = (1..).zip(&paths).map(|(number, path)| Part::new(number, path.to_str().unwrap().to_owned()));
+        let upload_id = backend.create_upload(BUCKET, KEY).await.unwrap();
+        let mut ops = vec![Operation::ConfiguredParts(parts.collect()), Operation::Started { upload_id }];
+        for (index, path) in paths.iter().enumerate().take(hashed) {
+            let data = std::fs::read(path).unwrap();
+            let md5 = base64::encode(md5::compute(&data).0);
+            ops.push(Operation::HashedPart { index, size: data.len() as u64, md5 });
+        }
+
+        let mut log = Wal::open(&dir.join("log")).await.unwrap();
+        for op in ops {
+            log.append(WalEntry::new(op)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn resumes_with_the_md5s_in_the_log() {
+        let dir = scratch("app-cached-md5s");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let backend = small_parts();
+        started(&dir, &backend, 2).await;
+
+        let mut app = app(&dir, backend).await;
+        // streamed, as large parts are, with the md5 from the log
+        app.buffer_threshold = 16;
+        app.run().await.unwrap();
+
+        assert!(matches!(app.state, State::Completed { .. }), "{:?}", app.state);
+        assert_eq!(logged(&app, "hashed_part"), 2);
+        assert_eq!(logged(&app, "uploaded_part"), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_the_next_part_while_one_uploads() {
+        let dir = scratch("app-read-ahead");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let backend = small_parts();
+        started(&dir, &backend, 1).await;
+
+        let mut app = app(&dir, backend).await;
+        let (index, data) = app.read_ahead(0).await.unwrap();
+        assert_eq!((index, data.len), (1, 100));
+        app.buffered = Some((index, data));
+        assert!(app.read_ahead(0).await.is_none(), "already read");
+
+        // uploaded from what was read ahead
+        std::fs::remove_file(dir.join("part-2")).unwrap();
+        app.run().await.unwrap();
+        assert!(matches!(app.state, State::Completed { .. }), "{:?}", app.state);
+        assert_eq!(logged(&app, "hashed_part"), 2);
+        assert_eq!(app.backend.object(BUCKET, KEY).unwrap().data.len(), 2148);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_only_failures_that_are_retried() {
+        let dir = scratch("app-retries");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let backend = small_parts();
+        started(&dir, &backend, 0).await;
+        std::fs::remove_file(dir.join("part-2")).unwrap();
+
+        let mut app = app(&dir, backend).await;
+        let metrics = Arc::new(Metrics::new(BUCKET, KEY));
+        app.metrics = Some(metrics.clone());
+        app.run().await.unwrap();
+
+        assert!(matches!(app.state, State::Aborted), "{:?}", app.state);
+        assert_eq!(logged(&app, "failed_part"), 3);
+        let retries = format!("s3mu_retries_total{{bucket=\"{}\",key=\"{}\",code=\"unknown\"}} 2", BUCKET, KEY);
+        assert!(metrics.render().lines().any(|line| line == retries), "{}", metrics.render());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Upload the parts in `dir` to a new store, returning it with the log
+    /// removed, ready to upload them again.
+    async fn uploaded(dir: &Path) -> MemoryBackend {
+        let mut app = app(dir, small_parts()).await;
+        app.run().await.unwrap();
+        assert!(matches!(app.state, State::Completed { .. }), "{:?}", app.state);
+        drop(app.log);
+        std::fs::remove_file(dir.join("log")).unwrap();
+        app.backend
+    }
+
+    #[tokio::test]
+    async fn records_the_created_object_in_the_log() {
+        let dir = scratch("app-completion");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let mut app = app(&dir, small_parts()).await;
+        app.run().await.unwrap();
+        let backend = app.backend;
+        drop(app.log);
+
+        // read back from the log alone
+        let app = self::app(&dir, backend).await;
+        let object = app.backend.object(BUCKET, KEY).unwrap();
+        let expected = Completion {
+            etag: Some(object.etag.to_owned()),
+            version_id: Some(object.version_id.to_owned()),
+            location: Some(format!("memory://{}/{}", BUCKET, KEY)),
+            size: Some(2148),
+        };
+        assert_eq!(app.state.completion(), Some(&expected));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_completions_logged_without_the_object() {
+        let old: Operation = serde_json::from_str("\"Completed\"").unwrap();
+        assert_eq!(old, Operation::completed(Completion::default()));
+
+        let completion = Completion { etag: Some("\"etag\"".to_owned()), size: Some(5), ..Completion::default() };
+        let logged = serde_json::to_string(&Operation::completed(completion.to_owned())).unwrap();
+        assert_eq!(serde_json::from_str::<Operation>(&logged).unwrap(), Operation::completed(completion));
+    }
+
+    #[tokio::test]
+    async fn verifies_each_part_read_back() {
+        let dir = scratch("app-verify");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let mut app = app(&dir, small_parts()).await;
+        app.verify = true;
+        app.run().await.unwrap();
+
+        match app.state {
+            State::Completed { ref verification, .. } => assert_eq!(verification, &Some(Verification::Verified)),
+            ref state => panic!("{:?}", state),
+        }
+        assert_eq!(logged(&app, "verified"), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn finds_the_first_part_that_differs() {
+        let dir = scratch("app-verify-differs");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let mut app = app(&dir, small_parts()).await;
+        app.run().await.unwrap();
+        let mut parts = app.state.parts().unwrap().to_vec();
+        assert_eq!(app.verify_parts(None, &parts).await.unwrap(), None);
+
+        parts[1].md5 = Some(base64::encode(md5::compute(b"other").0));
+        let (number, reason) = app.verify_parts(None, &parts).await.unwrap().unwrap();
+        assert_eq!(number, 2);
+        assert!(reason.contains("differs"), "{}", reason);
+
+        parts[1].size = Some(99);
+        let (_, reason) = app.verify_parts(None, &parts).await.unwrap().unwrap();
+        assert_eq!(reason, "read 100 bytes, expected 99");
+
+        parts.push(Part { size: Some(1), md5: parts[1].md5.to_owned(), ..Part::new(3, String::new()) });
+        parts[1] = app.state.parts().unwrap()[1].to_owned();
+        let (number, reason) = app.verify_parts(None, &parts).await.unwrap().unwrap();
+        assert_eq!((number, reason.as_str()), (3, "the object has no such part"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_verifying_an_object_replaced_since() {
+        let dir = scratch("app-verify-replaced");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let mut app = app(&dir, small_parts()).await;
+        app.run().await.unwrap();
+        app.backend.put_object(BUCKET, KEY, vec![0; 2148]).await.unwrap();
+
+        app.verify = true;
+        app.run().await.unwrap();
+        match app.state {
+            State::Completed { verification: Some(Verification::Failed(ref reason)), .. } => {
+                assert_eq!(reason, &format!("{} is gone", KEY))
+            }
+            ref state => panic!("{:?}", state),
+        }
+        assert_eq!(logged(&app, "verification_failed"), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn skips_uploading_an_identical_object() {
+        let dir = scratch("app-skip-identical");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let backend = uploaded(&dir).await;
+        let etag = backend.object(BUCKET, KEY).unwrap().etag.to_owned();
+
+        let mut app = app(&dir, backend).await;
+        app.skip_identical = true;
+        app.run().await.unwrap();
+
+        match app.state {
+            State::Completed { ref completion, .. } => assert_eq!(completion.etag, Some(etag)),
+            ref state => panic!("{:?}", state),
+        }
+        assert_eq!((logged(&app, "hashed_part"), logged(&app, "skipped")), (2, 1));
+        assert_eq!(logged(&app, "uploaded_part"), 0);
+        assert!(app.backend.upload_ids().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn uploads_over_an_object_that_differs() {
+        let dir = scratch("app-skip-different");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let backend = uploaded(&dir).await;
+        // the same size, so only the etag tells them apart
+        std::fs::write(dir.join("part-2"), vec![3; 100]).unwrap();
+
+        let mut app = app(&dir, backend).await;
+        app.skip_identical = true;
+        app.run().await.unwrap();
+
+        assert!(matches!(app.state, State::Completed { .. }), "{:?}", app.state);
+        assert_eq!((logged(&app, "skipped"), logged(&app, "uploaded_part")), (0, 2));
+        assert_eq!(app.backend.object(BUCKET, KEY).unwrap().data[2048..], [3; 100][..]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn objects_are_ours_by_size_and_etag() {
+        let md5s = [md5::compute(b"first"), md5::compute(b"second")];
+        let parts: Vec<Part> = (1..)
+            .zip(&md5s)
+            .zip(&[5, 6])
+            .map(|((number, md5), &size)| Part {
+                size: Some(size),
+                md5: Some(base64::encode(md5.0)),
+                ..Part::new(number, String::new())
+            })
+            .collect();
+        let object = |size, etag: Option<String>| ObjectInfo {
+            size,
+            etag,
+            version_id: None,
+            metadata: Default::default(),
+            content_encoding: None,
+        };
+        let etag = backend::multipart_etag(&[md5s[0].0, md5s[1].0]);
+
+        assert!(is_ours(&object(11, Some(etag.to_owned())), &parts));
+        assert!(!is_ours(&object(12, Some(etag.to_owned())), &parts));
+        assert!(!is_ours(&object(11, Some(backend::part_etag(b"firstsecond"))), &parts));
+        assert!(!is_ours(&object(11, None), &parts));
+        assert!(!is_ours(&object(11, Some(etag)), &parts[..1]));
+    }
+
+    #[tokio::test]
+    async fn resumes_after_a_lost_completion_to_a_directory() {
+        let dir = scratch("app-lost-completion");
+        std::fs::write(dir.join("part-1"), vec![1; 2048]).unwrap();
+        std::fs::write(dir.join("part-2"), vec![2; 100]).unwrap();
+        let store = dir.join("store");
+        std::fs::create_dir(&store).unwrap();
+        let open = || async {
+            let pattern = dir.join("part-*");
+            let log = Wal::open(&dir.join("log")).await.unwrap();
+            let bucket = store.to_str().unwrap();
+            App::new(FsBackend::new(), bucket, KEY, 3, Box::new(log), pattern.to_str().unwrap(), 1 << 20)
+                .await
+                .unwrap()
+        };
+
+        // handed off just before completing
+        let mut app = open().await;
+        app.handoff = true;
+        app.run().await.unwrap();
+        let (upload_id, parts) = match app.state {
+            State::Completing { ref upload_id, ref parts, .. } => (upload_id.to_owned(), parts.to_owned()),
+            ref state => panic!("{:?}", state),
+        };
+        drop(app);
+
+        // completed, with the response lost
+        let completed = parts
+            .iter()
+            .map(|part| CompletedPart { part_number: part.number, etag: part.etag.to_owned() })
+            .collect();
+        let bucket = store.to_str().unwrap();
+        let completion = FsBackend::new().complete_upload(bucket, KEY, &upload_id, completed).await.unwrap();
+
+        let mut app = open().await;
+        app.run().await.unwrap();
+        match app.state {
+            State::Completed { completion: ref found, .. } => assert_eq!(found.etag, completion.etag),
+            ref state => panic!("{:?}", state),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,466 @@
+//! Uploading a directory tree as a tar archive made on the fly.
+//!
+//! The archive is deterministic: entries are sorted by name and their
+//! headers carry normalised metadata, with no owners or times. Each part
+//! records where in the archive it starts and the entries it has some of,
+//! so a resumed upload makes any part again without reading the ones
+//! before it.
+
+use crate::backend::MAX_PART_NUMBER;
+use crate::error::Error;
+use crate::state::{Part, Segment};
+use crate::upload::Piece;
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+static BLOCK: u64 = 512;
+
+/// The longest name or link target a header holds. Longer ones go in a GNU
+/// long name entry before it.
+static NAME_LEN: usize = 100;
+
+/// A directory to upload as an archive, cut into parts of `part_size` bytes.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub dir: PathBuf,
+    pub part_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+    /// The two zero blocks ending the archive.
+    End,
+}
+
+/// A member of the archive, with what its header is made from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The name in the archive, relative to the directory, ending in `/`
+    /// for directories.
+    pub name: String,
+    /// The file its data is read from.
+    pub path: String,
+    pub kind: Kind,
+    pub size: u64,
+    pub mode: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// Where a part lies in the archive: `len` bytes from `offset` into the
+/// member of its first entry, the archive's `first`, counting from 0.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveSlice {
+    pub first: usize,
+    pub offset: u64,
+    pub len: u64,
+    /// The entries the part has some of, in order.
+    pub entries: Vec<Entry>,
+}
+
+impl Archive {
+    /// The archive of the directory, cut into parts.
+    pub fn parts(&self) -> Result<Vec<Part>, Error> {
+        if self.part_size == 0 {
+            return Err("archive parts need a size of at least one byte".into());
+        }
+
+        let mut entries = vec![];
+        walk(&self.dir, "", &mut entries)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries.push(Entry {
+            name: String::new(),
+            path: self.dir.to_string_lossy().into_owned(),
+            kind: Kind::End,
+            size: 0,
+            mode: 0,
+            link: None,
+        });
+        let lens: Vec<u64> = entries.iter().map(Entry::len).collect();
+        let total: u64 = lens.iter().sum();
+
+        let mut parts = vec![];
+        // the entry the next part starts in, and where it starts in the archive
+        let (mut first, mut first_start) = (0, 0);
+        let mut start = 0;
+        while start < total {
+            let len = cmp::min(self.part_size, total - start);
+            while first_start + lens[first] <= start {
+                first_start += lens[first];
+                first += 1;
+            }
+            let (mut last, mut last_end) = (first, first_start + lens[first]);
+            while last_end < start + len {
+                last += 1;
+                last_end += lens[last];
+            }
+
+            let slice = ArchiveSlice {
+                first,
+                offset: start - first_start,
+                len,
+                entries: entries[first..=last].to_vec(),
+            };
+            parts.push(Part::archived(parts.len() as i64 + 1, slice));
+            start += len;
+        }
+
+        if parts.len() as i64 > MAX_PART_NUMBER {
+            return Err(format!(
+                "archive of {:?} is {} parts, more than S3's {}, so needs larger parts",
+                self.dir,
+                parts.len(),
+                MAX_PART_NUMBER
+            )
+            .into());
+        }
+        Ok(parts)
+    }
+}
+
+impl ArchiveSlice {
+    /// The bytes of the part, from headers made again and the files.
+    pub fn pieces(&self) -> Vec<Piece> {
+        let mut pieces = vec![];
+        let (mut skip, mut left) = (self.offset, self.len);
+        for piece in self.entries.iter().flat_map(Entry::pieces) {
+            let len = piece.len();
+            if skip >= len {
+                skip -= len;
+                continue;
+            }
+            let take = cmp::min(len - skip, left);
+            pieces.push(match piece {
+                Piece::Bytes(bytes) => Piece::Bytes(bytes[skip as usize..(skip + take) as usize].to_vec()),
+                Piece::File(segment) => Piece::File(Segment {
+                    offset: segment.offset + skip,
+                    len: take,
+                    ..segment
+                }),
+            });
+            skip = 0;
+            left -= take;
+            if left == 0 {
+                break;
+            }
+        }
+        pieces
+    }
+}
+
+impl Entry {
+    /// The entry's member of the archive: its headers, data and padding.
+    pub fn pieces(&self) -> Vec<Piece> {
+        if self.kind == Kind::End {
+            return vec![Piece::Bytes(vec![0; 2 * BLOCK as usize])];
+        }
+
+        let mut headers = vec![];
+        if self.name.len() > NAME_LEN {
+            headers.extend_from_slice(&long_name(b'L', &self.name));
+        }
+        let link = self.link.as_deref().unwrap_or("");
+        if link.len() > NAME_LEN {
+            headers.extend_from_slice(&long_name(b'K', link));
+        }
+        let (kind, size) = match self.kind {
+            Kind::File => (b'0', self.size),
+            Kind::Directory => (b'5', 0),
+            _ => (b'2', 0),
+        };
+        headers.extend_from_slice(&header(&self.name, kind, size, self.mode, link));
+
+        let mut pieces = vec![Piece::Bytes(headers)];
+        if size > 0 {
+            pieces.push(Piece::File(Segment {
+                path: self.path.to_owned(),
+                offset: 0,
+                len: size,
+            }));
+            pieces.push(Piece::Bytes(vec![0; padding(size) as usize]));
+        }
+        pieces
+    }
+
+    fn len(&self) -> u64 {
+        self.pieces().iter().map(Piece::len).sum()
+    }
+}
+
+/// Add the entries under a directory, named with `prefix` before their
+/// names in it.
+fn walk(dir: &Path, prefix: &str, entries: &mut Vec<Entry>) -> Result<(), Error> {
+    let listing = std::fs::read_dir(dir).map_err(|err| format!("error listing {:?}: {}", dir, err))?;
+    for dir_entry in listing {
+        let path = dir_entry?.path();
+        let metadata =
+            std::fs::symlink_metadata(&path).map_err(|err| format!("error reading metadata of {:?}: {}", path, err))?;
+        let file_name = path.file_name().and_then(|name| name.to_str()).ok_or("error handling non utf8 path")?;
+        let name = format!("{}{}", prefix, file_name);
+        let source = path.to_str().ok_or("error handling non utf8 path")?.to_owned();
+
+        let file_type = metadata.file_type();
+        let entry = if file_type.is_dir() {
+            walk(&path, &format!("{}/", name), entries)?;
+            Entry {
+                name: format!("{}/", name),
+                path: source,
+                kind: Kind::Directory,
+                size: 0,
+                mode: 0o755,
+                link: None,
+            }
+        } else if file_type.is_file() {
+            let executable = metadata.permissions().mode() & 0o111 != 0;
+            Entry {
+                name,
+                path: source,
+                kind: Kind::File,
+                size: metadata.len(),
+                mode: if executable { 0o755 } else { 0o644 },
+                link: None,
+            }
+        } else if file_type.is_symlink() {
+            let target = std::fs::read_link(&path).map_err(|err| format!("error reading link {:?}: {}", path, err))?;
+            Entry {
+                name,
+                path: source,
+                kind: Kind::Symlink,
+                size: 0,
+                mode: 0o777,
+                link: Some(target.to_str().ok_or("error handling non utf8 path")?.to_owned()),
+            }
+        } else {
+            return Err(format!("can't archive {:?}, only files, directories and symlinks", path).into());
+        };
+        entries.push(entry);
+    }
+    Ok(())
+}
+
+fn padding(size: u64) -> u64 {
+    (BLOCK - size % BLOCK) % BLOCK
+}
+
+/// A GNU header holding a name too long for the next header, and the name.
+fn long_name(kind: u8, name: &str) -> Vec<u8> {
+    let size = name.len() as u64 + 1;
+    let mut bytes = header("././@LongLink", kind, size, 0o644, "").to_vec();
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.resize(bytes.len() + 1 + padding(size) as usize, 0);
+    bytes
+}
+
+/// A GNU tar header, owned by root and dated at the epoch.
+fn header(name: &str, kind: u8, size: u64, mode: u32, link: &str) -> [u8; 512] {
+    let mut header = [0; 512];
+    let name = &name.as_bytes()[..cmp::min(name.len(), NAME_LEN)];
+    let link = &link.as_bytes()[..cmp::min(link.len(), NAME_LEN)];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], mode as u64);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    if size < 0o77777777777 {
+        octal(&mut header[124..136], size);
+    } else {
+        // too large for octal, so base 256, marked by the top bit
+        header[124] = 0x80;
+        header[128..136].copy_from_slice(&size.to_be_bytes());
+    }
+    octal(&mut header[136..148], 0);
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link);
+    header[257..265].copy_from_slice(b"ustar  \0");
+
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    header
+}
+
+/// Write a number in octal, zero padded to fill the field before a NUL.
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(digits.as_bytes());
+    field[width] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+    use std::fs;
+
+    fn joined(pieces: &[Piece]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for piece in pieces {
+            match piece {
+                Piece::Bytes(data) => bytes.extend_from_slice(data),
+                Piece::File(segment) => {
+                    let data = fs::read(&segment.path).unwrap();
+                    bytes.extend_from_slice(&data[segment.offset as usize..][..segment.len as usize]);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// The archive of a directory, joined back together from its parts.
+    fn archived(dir: &Path, part_size: u64) -> (Vec<Part>, Vec<u8>) {
+        let parts = Archive {
+            dir: dir.to_owned(),
+            part_size,
+        }
+        .parts()
+        .unwrap();
+        let mut archive = vec![];
+        for part in &parts {
+            let piece = joined(&part.archive.as_ref().unwrap().pieces());
+            assert_eq!(piece.len() as u64, part.archive.as_ref().unwrap().len);
+            archive.extend_from_slice(&piece);
+        }
+        (parts, archive)
+    }
+
+    /// A tree with nested directories, long names and link targets, an
+    /// executable and files empty, small and a few blocks long.
+    fn tree(dir: &Path) {
+        let long = "d".repeat(60);
+        fs::create_dir_all(dir.join("a/b").join(&long).join(&long)).unwrap();
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        fs::write(dir.join("a/small"), b"hello").unwrap();
+        fs::write(dir.join("a/b/none"), b"").unwrap();
+        let data: Vec<u8> = (0..1500u32).map(|i| (i * 7) as u8).collect();
+        fs::write(dir.join("a/b").join(&long).join(&long).join("blocks"), &data).unwrap();
+        fs::write(dir.join("run.sh"), b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(dir.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("small", dir.join("a/near")).unwrap();
+        std::os::unix::fs::symlink(format!("b/{}/{}/blocks", long, long), dir.join("a/far")).unwrap();
+    }
+
+    /// Everything under a directory: names, with the contents of files,
+    /// targets of links and whether files are executable.
+    fn listing(dir: &Path, prefix: &str, found: &mut Vec<(String, String)>) {
+        let mut names: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        names.sort();
+        for path in names {
+            let name = format!("{}{}", prefix, path.file_name().unwrap().to_str().unwrap());
+            let metadata = fs::symlink_metadata(&path).unwrap();
+            if metadata.file_type().is_symlink() {
+                found.push((name, format!("-> {:?}", fs::read_link(&path).unwrap())));
+            } else if metadata.is_dir() {
+                found.push((format!("{}/", name), String::new()));
+                listing(&path, &format!("{}/", name), found);
+            } else {
+                let executable = metadata.permissions().mode() & 0o111 != 0;
+                found.push((name, format!("{} {:?}", executable, fs::read(&path).unwrap())));
+            }
+        }
+    }
+
+    #[test]
+    fn headers_parse_as_tar() {
+        let file = header("dir/file", b'0', 1234, 0o644, "");
+        let parsed = tar::Header::from_byte_slice(&file);
+        assert_eq!(parsed.path().unwrap(), Path::new("dir/file"));
+        assert_eq!(parsed.entry_size().unwrap(), 1234);
+        assert_eq!(parsed.mode().unwrap(), 0o644);
+        assert_eq!(parsed.entry_type(), tar::EntryType::Regular);
+        let unsummed: u32 = file[..148].iter().chain(&file[156..]).map(|&b| b as u32).sum();
+        assert_eq!(parsed.cksum().unwrap(), unsummed + 8 * b' ' as u32);
+
+        let link = header("link", b'2', 0, 0o777, "target");
+        let parsed = tar::Header::from_byte_slice(&link);
+        assert_eq!(parsed.entry_type(), tar::EntryType::Symlink);
+        assert_eq!(parsed.link_name().unwrap().unwrap(), Path::new("target"));
+    }
+
+    #[test]
+    fn headers_hold_large_sizes_in_base_256() {
+        let largest_octal = 0o77777777777 - 1;
+        let header_octal = header("big", b'0', largest_octal, 0o644, "");
+        assert_eq!(header_octal[124] & 0x80, 0);
+        assert_eq!(tar::Header::from_byte_slice(&header_octal).entry_size().unwrap(), largest_octal);
+
+        for &size in &[0o77777777777, 1 << 40, u64::MAX >> 1] {
+            let big = header("big", b'0', size, 0o644, "");
+            assert_eq!(big[124], 0x80);
+            assert_eq!(tar::Header::from_byte_slice(&big).entry_size().unwrap(), size);
+        }
+    }
+
+    #[test]
+    fn long_names_go_in_gnu_entries() {
+        let name = format!("{}/file", "n".repeat(120));
+        let bytes = long_name(b'L', &name);
+        assert_eq!(bytes.len(), 2 * BLOCK as usize);
+        let parsed = tar::Header::from_byte_slice(&bytes[..512]);
+        assert_eq!(parsed.entry_type(), tar::EntryType::GNULongName);
+        assert_eq!(parsed.entry_size().unwrap(), name.len() as u64 + 1);
+        assert_eq!(&bytes[512..512 + name.len()], name.as_bytes());
+        assert!(bytes[512 + name.len()..].iter().all(|&b| b == 0));
+
+        let entry = Entry {
+            name: name.clone(),
+            path: String::new(),
+            kind: Kind::Symlink,
+            size: 0,
+            mode: 0o777,
+            link: Some("t".repeat(101)),
+        };
+        let headers = joined(&entry.pieces());
+        assert_eq!(headers.len(), 5 * BLOCK as usize);
+        assert_eq!(tar::Header::from_byte_slice(&headers[1024..1536]).entry_type(), tar::EntryType::GNULongLink);
+        // the names in the header itself are cut short
+        assert_eq!(&headers[2048..2148], &name.as_bytes()[..100]);
+    }
+
+    #[test]
+    fn slices_starting_mid_header_match_the_archive() {
+        let dir = scratch("archive-slices");
+        tree(&dir);
+        let (_, whole) = archived(&dir, u64::MAX);
+        assert_eq!(whole.len() as u64 % BLOCK, 0);
+
+        // parts of odd sizes start part way through headers, and joined
+        // back together make the same archive
+        for &part_size in &[7, 99, 511, 513, 1000] {
+            let (parts, archive) = archived(&dir, part_size);
+            assert_eq!(archive, whole, "parts of {} bytes", part_size);
+            assert_eq!(parts.len() as u64, (whole.len() as u64).div_ceil(part_size));
+            assert!(parts.iter().any(|part| part.archive.as_ref().unwrap().offset % BLOCK != 0));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn archives_unpack_to_the_source_tree() {
+        let dir = scratch("archive-unpack");
+        let (source, unpacked) = (dir.join("source"), dir.join("unpacked"));
+        fs::create_dir_all(&source).unwrap();
+        tree(&source);
+        let (_, archive) = archived(&source, 700);
+
+        let mut names = vec![];
+        for entry in tar::Archive::new(&archive[..]).entries().unwrap() {
+            names.push(entry.unwrap().path().unwrap().to_str().unwrap().to_owned());
+        }
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(names, sorted);
+
+        tar::Archive::new(&archive[..]).unpack(&unpacked).unwrap();
+        let (mut expected, mut found) = (vec![], vec![]);
+        listing(&source, "", &mut expected);
+        listing(&unpacked, "", &mut found);
+        assert_eq!(found, expected);
+        assert!(expected.iter().any(|(name, _)| name.len() > NAME_LEN));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
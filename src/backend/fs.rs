@@ -0,0 +1,622 @@
+use super::{
+    multipart_etag, Backend, Body, CompletedPart, Completion, ListedPart, ObjectInfo, S3Error, MAX_PART_NUMBER,
+};
+use crate::result::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Uploads in progress are staged under this directory, next to the object.
+static STAGING_DIR: &str = ".s3mu-uploads";
+
+/// Files can't hold an etag, so the etags of completed objects are kept
+/// under this directory, next to them.
+static ETAG_DIR: &str = ".s3mu-etags";
+
+/// The etag of a completed object, with the size and modification time of
+/// its file, so a file replaced since isn't given the etag.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Stamp {
+    etag: String,
+    size: u64,
+    modified: (u64, u32),
+}
+
+impl Stamp {
+    fn new(etag: &str, metadata: &std::fs::Metadata) -> Option<Self> {
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Stamp {
+            etag: etag.to_owned(),
+            size: metadata.len(),
+            modified: (modified.as_secs(), modified.subsec_nanos()),
+        })
+    }
+}
+
+/// A local or network filesystem. The bucket is a directory and the key a
+/// path within it. Parts are staged in a hidden directory per upload and
+/// concatenated into place when the upload completes, so the object appears
+/// atomically.
+pub struct FsBackend;
+
+impl FsBackend {
+    pub fn new() -> Self {
+        FsBackend
+    }
+
+    fn object_path(bucket: &str, key: &str) -> PathBuf {
+        Path::new(bucket).join(key)
+    }
+
+    fn staging_path(bucket: &str, key: &str, upload_id: &str) -> Result<PathBuf> {
+        if upload_id.is_empty() || upload_id.contains(['/', '.']) {
+            return Err(S3Error::coded("NoSuchUpload", format!("invalid upload id {:?}", upload_id)).into());
+        }
+        Ok(FsBackend::staging_root(bucket, key).join(upload_id))
+    }
+
+    fn staging_root(bucket: &str, key: &str) -> PathBuf {
+        let object = FsBackend::object_path(bucket, key);
+        let dir = object.parent().unwrap_or_else(|| Path::new("."));
+        dir.join(STAGING_DIR)
+    }
+
+    fn part_path(staging: &Path, part_number: i64) -> PathBuf {
+        staging.join(format!("part-{:05}", part_number))
+    }
+
+    /// Where a staged part's etag is kept, so listing parts needn't read them.
+    fn part_etag_path(part: &Path) -> PathBuf {
+        part.with_extension("etag")
+    }
+
+    fn etag_path(bucket: &str, key: &str) -> PathBuf {
+        let object = FsBackend::object_path(bucket, key);
+        let dir = object.parent().unwrap_or_else(|| Path::new("."));
+        dir.join(ETAG_DIR).join(object.file_name().unwrap_or_default())
+    }
+
+    /// Record the etag of the object about to be moved from `file` into
+    /// place at the key. Moving it keeps the size and modification time.
+    async fn write_etag(bucket: &str, key: &str, file: &Path, etag: &str) -> Result<()> {
+        let metadata = fs::metadata(file)
+            .await
+            .map_err(|err| format!("error reading metadata of {:?}: {}", file, err))?;
+        let stamp = Stamp::new(etag, &metadata).ok_or_else(|| format!("{:?} has no modification time", file))?;
+
+        let path = FsBackend::etag_path(bucket, key);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .await
+                .map_err(|err| format!("error creating etag directory {:?}: {}", dir, err))?;
+        }
+        FsBackend::write_atomic(&path, &serde_json::to_vec(&stamp)?).await
+    }
+
+    /// The etag recorded for the object at the key, if it is still the file
+    /// it was recorded for.
+    async fn read_etag(bucket: &str, key: &str, metadata: &std::fs::Metadata) -> Option<String> {
+        let data = fs::read(FsBackend::etag_path(bucket, key)).await.ok()?;
+        let stamp: Stamp = serde_json::from_slice(&data).ok()?;
+        match Stamp::new(&stamp.etag, metadata) {
+            Some(current) if current == stamp => Some(stamp.etag),
+            _ => None,
+        }
+    }
+
+    /// Find an upload's staging directory, checking it belongs to this key.
+    async fn upload(bucket: &str, key: &str, upload_id: &str) -> Result<PathBuf> {
+        let staging = FsBackend::staging_path(bucket, key, upload_id)?;
+        let owner = fs::read_to_string(staging.join("key")).await.ok();
+        if owner.as_deref() != Some(key) {
+            return Err(S3Error::coded("NoSuchUpload", format!("no such upload {}", upload_id)).into());
+        }
+        Ok(staging)
+    }
+
+    /// Remove an upload's staging directory, and the staging root if no
+    /// other uploads are using it.
+    async fn remove_staging(staging: &Path) -> Result<()> {
+        fs::remove_dir_all(staging)
+            .await
+            .map_err(|err| format!("error removing staging directory {:?}: {}", staging, err))?;
+        if let Some(root) = staging.parent() {
+            let _ = fs::remove_dir(root).await;
+        }
+        Ok(())
+    }
+
+    /// Assemble an upload's parts and move the object into place, if
+    /// `if_absent` only when there is no object at the key. That is done by
+    /// linking rather than renaming, which fails if the object exists. The
+    /// etag is recorded first, so it is there as soon as the object is.
+    async fn complete(
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+        if_absent: bool,
+    ) -> Result<Completion> {
+        let staging = FsBackend::upload(bucket, key, upload_id).await?;
+        if parts.is_empty() {
+            return Err(S3Error::coded("MalformedXML", "no parts to complete".to_owned()).into());
+        }
+
+        let assembled = staging.join("object.tmp");
+        let mut object = fs::File::create(&assembled)
+            .await
+            .map_err(|err| format!("error creating {:?}: {}", assembled, err))?;
+        let mut digests = vec![];
+        let mut size = 0;
+        let mut previous = 0;
+
+        for part in parts.iter() {
+            if part.part_number <= previous {
+                return Err(S3Error::coded(
+                    "InvalidPartOrder",
+                    format!("part {} is out of order", part.part_number),
+                )
+                .into());
+            }
+            previous = part.part_number;
+
+            let not_found = || {
+                S3Error::coded(
+                    "InvalidPart",
+                    format!("part {} with etag {} not found", part.part_number, part.etag),
+                )
+            };
+            let mut f = fs::File::open(FsBackend::part_path(&staging, part.part_number))
+                .await
+                .map_err(|_| not_found())?;
+            let (digest, len) = copy_digest(&mut f, Some(&mut object))
+                .await
+                .map_err(|err| format!("error copying part {} to {:?}: {}", part.part_number, assembled, err))?;
+            if format!("\"{:x}\"", digest) != part.etag {
+                return Err(not_found().into());
+            }
+
+            digests.push(digest.0);
+            size += len;
+        }
+        object
+            .sync_all()
+            .await
+            .map_err(|err| format!("error syncing {:?}: {}", assembled, err))?;
+
+        let path = FsBackend::object_path(bucket, key);
+        let etag = multipart_etag(&digests);
+        let exists = || S3Error::coded("PreconditionFailed", format!("{:?} already exists", path));
+        if if_absent {
+            // checked before replacing the etag of the object there
+            if fs::symlink_metadata(&path).await.is_ok() {
+                let _ = fs::remove_file(&assembled).await;
+                return Err(exists().into());
+            }
+            FsBackend::write_etag(bucket, key, &assembled, &etag).await?;
+            let linked = fs::hard_link(&assembled, &path).await;
+            let _ = fs::remove_file(&assembled).await;
+            match linked {
+                // written since the check, and left without an etag
+                Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => return Err(exists().into()),
+                result => result.map_err(|err| format!("error moving object into place at {:?}: {}", path, err))?,
+            }
+        } else {
+            FsBackend::write_etag(bucket, key, &assembled, &etag).await?;
+            fs::rename(&assembled, &path)
+                .await
+                .map_err(|err| format!("error moving object into place at {:?}: {}", path, err))?;
+        }
+        FsBackend::remove_staging(&staging).await?;
+
+        Ok(Completion {
+            etag: Some(etag),
+            version_id: None,
+            location: Some(format!("file://{}", path.display())),
+            size: Some(size),
+        })
+    }
+
+    async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+        let temp = temp_path(path);
+        fs::write(&temp, data)
+            .await
+            .map_err(|err| format!("error writing {:?}: {}", temp, err))?;
+        fs::rename(&temp, path)
+            .await
+            .map_err(|err| format!("error renaming {:?}: {}", temp, err))?;
+        Ok(())
+    }
+}
+
+impl Default for FsBackend {
+    fn default() -> Self {
+        FsBackend::new()
+    }
+}
+
+#[async_trait]
+impl Backend for FsBackend {
+    async fn create_upload(&self, bucket: &str, key: &str) -> Result<String> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let upload_id = format!("{:x}-{:x}", nanos, std::process::id());
+
+        let staging = FsBackend::staging_path(bucket, key, &upload_id)?;
+        fs::create_dir_all(&staging)
+            .await
+            .map_err(|err| format!("error creating staging directory {:?}: {}", staging, err))?;
+        FsBackend::write_atomic(&staging.join("key"), key.as_bytes()).await?;
+
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Body,
+    ) -> Result<String> {
+        if part_number < 1 || part_number > MAX_PART_NUMBER {
+            return Err(S3Error::coded(
+                "InvalidArgument",
+                format!("part number {} must be between 1 and {}", part_number, MAX_PART_NUMBER),
+            )
+            .into());
+        }
+
+        let staging = FsBackend::upload(bucket, key, upload_id).await?;
+        let path = FsBackend::part_path(&staging, part_number);
+        let temp = temp_path(&path);
+
+        let mut file = fs::File::create(&temp)
+            .await
+            .map_err(|err| format!("error creating part file {:?}: {}", temp, err))?;
+        let mut digest = md5::Context::new();
+        let mut len = 0;
+        let mut stream = body.stream;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            digest.consume(&chunk);
+            len += chunk.len() as u64;
+            file.write_all(&chunk)
+                .await
+                .map_err(|err| format!("error writing part file {:?}: {}", temp, err))?;
+        }
+        file.sync_all()
+            .await
+            .map_err(|err| format!("error syncing part file {:?}: {}", temp, err))?;
+
+        let md5 = digest.compute();
+        if len != body.len {
+            let _ = fs::remove_file(&temp).await;
+            return Err(S3Error::coded(
+                "IncompleteBody",
+                format!("expected {} bytes but received {}", body.len, len),
+            )
+            .into());
+        }
+        if base64::encode(md5.0) != body.md5 {
+            let _ = fs::remove_file(&temp).await;
+            return Err(S3Error::coded("BadDigest", "content md5 does not match".to_owned()).into());
+        }
+
+        // the etag of the part replaced goes first, so any etag found is
+        // the staged part's
+        let etag = format!("\"{:x}\"", md5);
+        let etag_path = FsBackend::part_etag_path(&path);
+        match fs::remove_file(&etag_path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(format!("error removing {:?}: {}", etag_path, err).into())
+            }
+            _ => {}
+        }
+        fs::rename(&temp, &path)
+            .await
+            .map_err(|err| format!("error renaming part file {:?}: {}", temp, err))?;
+        FsBackend::write_atomic(&etag_path, etag.as_bytes()).await?;
+
+        Ok(etag)
+    }
+
+    async fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        FsBackend::complete(bucket, key, upload_id, parts, false).await
+    }
+
+    async fn complete_upload_if_absent(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        FsBackend::complete(bucket, key, upload_id, parts, true).await
+    }
+
+    async fn abort_upload(&self, bucket: &str, key: &str, upload_id: &str) -> Result<()> {
+        let staging = FsBackend::upload(bucket, key, upload_id).await?;
+        FsBackend::remove_staging(&staging).await?;
+        Ok(())
+    }
+
+    async fn list_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<Vec<ListedPart>> {
+        let staging = FsBackend::upload(bucket, key, upload_id).await?;
+        let mut entries = fs::read_dir(&staging)
+            .await
+            .map_err(|err| format!("error listing {:?}: {}", staging, err))?;
+
+        let mut listed = vec![];
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|err| format!("error listing {:?}: {}", staging, err))?
+        {
+            let name = entry.file_name();
+            let part_number = match name
+                .to_str()
+                .and_then(|name| name.strip_prefix("part-"))
+                .and_then(|number| number.parse().ok())
+            {
+                Some(part_number) => part_number,
+                None => continue,
+            };
+
+            let path = entry.path();
+            let recorded = fs::read_to_string(FsBackend::part_etag_path(&path)).await.ok();
+            let (etag, size) = match recorded {
+                Some(etag) => {
+                    let metadata = fs::metadata(&path)
+                        .await
+                        .map_err(|err| format!("error reading part {}: {}", part_number, err))?;
+                    (etag, metadata.len())
+                }
+                // staged before its etag was written
+                None => {
+                    let mut f = fs::File::open(&path)
+                        .await
+                        .map_err(|err| format!("error reading part {}: {}", part_number, err))?;
+                    let (digest, size) = copy_digest(&mut f, None)
+                        .await
+                        .map_err(|err| format!("error reading part {}: {}", part_number, err))?;
+                    (format!("\"{:x}\"", digest), size)
+                }
+            };
+            listed.push(ListedPart {
+                part_number,
+                etag,
+                size,
+            });
+        }
+
+        listed.sort_by_key(|part| part.part_number);
+        Ok(listed)
+    }
+
+    async fn list_uploads(&self, bucket: &str, key: &str) -> Result<Vec<String>> {
+        let root = FsBackend::staging_root(bucket, key);
+        let mut entries = match fs::read_dir(&root).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(format!("error listing {:?}: {}", root, err).into()),
+        };
+
+        let mut ids = vec![];
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|err| format!("error listing {:?}: {}", root, err))?
+        {
+            let owner = fs::read_to_string(entry.path().join("key")).await.ok();
+            if owner.as_deref() != Some(key) {
+                continue;
+            }
+            if let Some(upload_id) = entry.file_name().to_str() {
+                ids.push(upload_id.to_owned());
+            }
+        }
+
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Objects completed from parts have the etag recorded then. Files
+    /// written or replaced otherwise have none.
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<ObjectInfo>> {
+        let path = FsBackend::object_path(bucket, key);
+        match fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(ObjectInfo {
+                size: metadata.len(),
+                etag: FsBackend::read_etag(bucket, key, &metadata).await,
+                version_id: None,
+                metadata: HashMap::new(),
+                content_encoding: None,
+            })),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("error reading metadata of {:?}: {}", path, err).into()),
+        }
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        let _ = fs::remove_file(FsBackend::etag_path(bucket, key)).await;
+        FsBackend::write_atomic(&FsBackend::object_path(bucket, key), &data).await
+    }
+
+    /// Written aside and linked into place, which fails if the object exists.
+    async fn put_object_if_absent(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = FsBackend::object_path(bucket, key);
+        let temp = temp_path(&path);
+        fs::write(&temp, data)
+            .await
+            .map_err(|err| format!("error writing {:?}: {}", temp, err))?;
+        let linked = fs::hard_link(&temp, &path).await;
+        let _ = fs::remove_file(&temp).await;
+        match linked {
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                Err(S3Error::coded("PreconditionFailed", format!("{:?} already exists", path)).into())
+            }
+            result => result.map_err(|err| format!("error linking {:?} into place: {}", path, err).into()),
+        }
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = FsBackend::object_path(bucket, key);
+        match fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("error reading {:?}: {}", path, err).into()),
+        }
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<()> {
+        let path = FsBackend::object_path(bucket, key);
+        let _ = fs::remove_file(FsBackend::etag_path(bucket, key)).await;
+        match fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(format!("error deleting {:?}: {}", path, err).into()),
+        }
+    }
+}
+
+/// A temporary name for writing `path` before renaming it into place: the
+/// whole name with a suffix unique to this process and write, so neither
+/// other writers nor files sharing the stem are clobbered.
+fn temp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(format!(
+        ".s3mu-tmp-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    path.with_file_name(name)
+}
+
+/// Read a file to the end a chunk at a time, copying it to `out` if given,
+/// and return its md5 and length.
+async fn copy_digest(f: &mut fs::File, mut out: Option<&mut fs::File>) -> std::io::Result<(md5::Digest, u64)> {
+    let mut digest = md5::Context::new();
+    let mut len = 0;
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let count = f.read(&mut buf).await?;
+        if count == 0 {
+            return Ok((digest.compute(), len));
+        }
+        digest.consume(&buf[..count]);
+        len += count as u64;
+        if let Some(ref mut out) = out {
+            out.write_all(&buf[..count]).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+    use rusoto_core::ByteStream;
+
+    static KEY: &str = "key";
+
+    fn body(data: &[u8]) -> Body {
+        Body {
+            stream: ByteStream::from(data.to_vec()),
+            len: data.len() as u64,
+            md5: base64::encode(md5::compute(data).0),
+        }
+    }
+
+    async fn upload(backend: &FsBackend, bucket: &str, upload_id: &str, parts: &[&[u8]]) -> Vec<CompletedPart> {
+        let mut completed = vec![];
+        for (part_number, data) in (1..).zip(parts) {
+            let etag = backend.upload_part(bucket, KEY, upload_id, part_number, body(data)).await.unwrap();
+            completed.push(CompletedPart { part_number, etag });
+        }
+        completed
+    }
+
+    #[tokio::test]
+    async fn lists_staged_parts_with_their_recorded_etags() {
+        let dir = scratch("fs-parts");
+        let bucket = dir.to_str().unwrap();
+        let backend = FsBackend::new();
+        let upload_id = backend.create_upload(bucket, KEY).await.unwrap();
+        let parts = upload(&backend, bucket, &upload_id, &[b"first", b"second"]).await;
+        assert_eq!(parts[0].etag, format!("\"{:x}\"", md5::compute(b"first")));
+
+        // the recorded etag is listed, without reading the part
+        let staging = FsBackend::staging_path(bucket, KEY, &upload_id).unwrap();
+        let recorded = FsBackend::part_etag_path(&FsBackend::part_path(&staging, 1));
+        std::fs::write(&recorded, "\"recorded\"").unwrap();
+        let listed = backend.list_parts(bucket, KEY, &upload_id).await.unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!((listed[0].etag.as_str(), listed[0].size), ("\"recorded\"", 5));
+
+        // and a part without one is read
+        std::fs::remove_file(&recorded).unwrap();
+        let listed = backend.list_parts(bucket, KEY, &upload_id).await.unwrap();
+        assert_eq!(listed[0].etag, parts[0].etag);
+        assert_eq!(listed[1].etag, parts[1].etag);
+    }
+
+    #[tokio::test]
+    async fn reports_the_etag_of_completed_objects() {
+        let dir = scratch("fs-etag");
+        let bucket = dir.to_str().unwrap();
+        let backend = FsBackend::new();
+        let upload_id = backend.create_upload(bucket, KEY).await.unwrap();
+        let parts = upload(&backend, bucket, &upload_id, &[b"abcd", b"ef"]).await;
+        let completion = backend.complete_upload(bucket, KEY, &upload_id, parts).await.unwrap();
+
+        let digests = [md5::compute(b"abcd").0, md5::compute(b"ef").0];
+        assert_eq!(completion.etag, Some(multipart_etag(&digests)));
+        let info = backend.head_object(bucket, KEY).await.unwrap().unwrap();
+        assert_eq!((info.size, info.etag), (6, completion.etag));
+
+        // a file written over it isn't given the etag
+        backend.put_object(bucket, KEY, b"other".to_vec()).await.unwrap();
+        let info = backend.head_object(bucket, KEY).await.unwrap().unwrap();
+        assert_eq!((info.size, info.etag), (5, None));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_etag_of_an_existing_object_when_not_replacing_it() {
+        let dir = scratch("fs-if-absent");
+        let bucket = dir.to_str().unwrap();
+        let backend = FsBackend::new();
+        let upload_id = backend.create_upload(bucket, KEY).await.unwrap();
+        let parts = upload(&backend, bucket, &upload_id, &[b"abcd"]).await;
+        let completion = backend.complete_upload(bucket, KEY, &upload_id, parts).await.unwrap();
+
+        let upload_id = backend.create_upload(bucket, KEY).await.unwrap();
+        let parts = upload(&backend, bucket, &upload_id, &[b"other"]).await;
+        let err = backend.complete_upload_if_absent(bucket, KEY, &upload_id, parts).await.unwrap_err();
+        assert_eq!(super::super::error_code(&err).as_deref(), Some("PreconditionFailed"));
+        let info = backend.head_object(bucket, KEY).await.unwrap().unwrap();
+        assert_eq!(info.etag, completion.etag);
+    }
+}
@@ -0,0 +1,579 @@
+use super::{
+    multipart_etag, part_etag, Backend, Body, CompletedPart, Completion, ListedPart, ObjectInfo, ObjectSettings,
+    PartInfo, S3Error, MAX_PART_NUMBER, MIN_PART_SIZE,
+};
+use crate::result::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use rusoto_core::ByteStream;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone)]
+struct StoredPart {
+    data: Vec<u8>,
+    etag: String,
+}
+
+#[derive(Debug, Clone)]
+struct Upload {
+    bucket: String,
+    key: String,
+    parts: BTreeMap<i64, StoredPart>,
+    settings: ObjectSettings,
+}
+
+/// An object created by completing an upload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub data: Vec<u8>,
+    pub etag: String,
+    pub version_id: String,
+    /// The sizes of the parts it was completed from, in order.
+    pub part_sizes: Vec<u64>,
+    pub metadata: HashMap<String, String>,
+    pub content_encoding: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    next_id: u64,
+    uploads: HashMap<String, Upload>,
+    /// Shared, so reading an object doesn't copy its data.
+    objects: HashMap<(String, String), Arc<Object>>,
+}
+
+/// An object store held in memory, enforcing S3's multipart upload rules.
+/// Used for tests.
+#[derive(Debug)]
+pub struct MemoryBackend {
+    pub min_part_size: u64,
+    store: Mutex<Store>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        MemoryBackend::new()
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend {
+            min_part_size: MIN_PART_SIZE,
+            store: Mutex::new(Store::default()),
+        }
+    }
+
+    pub fn object(&self, bucket: &str, key: &str) -> Option<Arc<Object>> {
+        self.store
+            .lock()
+            .unwrap()
+            .objects
+            .get(&(bucket.to_owned(), key.to_owned()))
+            .cloned()
+    }
+
+    /// The ids of uploads that have been started but not completed or aborted.
+    pub fn upload_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.store.lock().unwrap().uploads.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    fn put(&self, bucket: &str, key: &str, data: Vec<u8>, if_absent: bool) -> Result<()> {
+        let mut store = self.store.lock().unwrap();
+        let key = (bucket.to_owned(), key.to_owned());
+        if if_absent && store.objects.contains_key(&key) {
+            return Err(S3Error::coded("PreconditionFailed", format!("{} already exists", key.1)).into());
+        }
+        store.next_id += 1;
+        let object = Object {
+            etag: part_etag(&data),
+            part_sizes: vec![data.len() as u64],
+            data,
+            version_id: format!("memory-version-{}", store.next_id),
+            metadata: HashMap::new(),
+            content_encoding: None,
+        };
+        store.objects.insert(key, Arc::new(object));
+        Ok(())
+    }
+
+    fn no_such_upload(upload_id: &str) -> S3Error {
+        S3Error::coded("NoSuchUpload", format!("no such upload {}", upload_id))
+    }
+
+    /// Complete an upload, if `if_absent` only when there is no object at
+    /// the key.
+    fn complete(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+        if_absent: bool,
+    ) -> Result<Completion> {
+        let mut store = self.store.lock().unwrap();
+        let upload = store
+            .uploads
+            .get(upload_id)
+            .filter(|upload| upload.bucket == bucket && upload.key == key)
+            .ok_or_else(|| MemoryBackend::no_such_upload(upload_id))?;
+
+        if if_absent && store.objects.contains_key(&(bucket.to_owned(), key.to_owned())) {
+            return Err(S3Error::coded("PreconditionFailed", format!("{} already exists", key)).into());
+        }
+
+        if parts.is_empty() {
+            return Err(S3Error::coded("MalformedXML", "no parts to complete".to_owned()).into());
+        }
+
+        let mut data = vec![];
+        let mut digests = vec![];
+        let mut part_sizes = vec![];
+        let mut previous = 0;
+
+        for (i, part) in parts.iter().enumerate() {
+            if part.part_number <= previous {
+                return Err(S3Error::coded(
+                    "InvalidPartOrder",
+                    format!("part {} is out of order", part.part_number),
+                )
+                .into());
+            }
+            previous = part.part_number;
+
+            let stored = upload
+                .parts
+                .get(&part.part_number)
+                .filter(|stored| stored.etag == part.etag)
+                .ok_or_else(|| {
+                    S3Error::coded(
+                        "InvalidPart",
+                        format!("part {} with etag {} not found", part.part_number, part.etag),
+                    )
+                })?;
+
+            let last = i == parts.len() - 1;
+            if !last && (stored.data.len() as u64) < self.min_part_size {
+                return Err(S3Error::coded(
+                    "EntityTooSmall",
+                    format!(
+                        "part {} is {} bytes, smaller than the minimum {}",
+                        part.part_number,
+                        stored.data.len(),
+                        self.min_part_size
+                    ),
+                )
+                .into());
+            }
+
+            digests.push(md5::compute(&stored.data).0);
+            part_sizes.push(stored.data.len() as u64);
+            data.extend_from_slice(&stored.data);
+        }
+
+        let etag = multipart_etag(&digests);
+        let settings = upload.settings.to_owned();
+        store.uploads.remove(upload_id);
+        store.next_id += 1;
+        let version_id = format!("memory-version-{}", store.next_id);
+        let size = data.len() as u64;
+        store.objects.insert(
+            (bucket.to_owned(), key.to_owned()),
+            Arc::new(Object {
+                data,
+                etag: etag.to_owned(),
+                version_id: version_id.to_owned(),
+                part_sizes,
+                metadata: settings.metadata,
+                content_encoding: settings.content_encoding,
+            }),
+        );
+
+        Ok(Completion {
+            etag: Some(etag),
+            version_id: Some(version_id),
+            location: Some(format!("memory://{}/{}", bucket, key)),
+            size: Some(size),
+        })
+    }
+}
+
+#[async_trait]
+impl Backend for MemoryBackend {
+    async fn create_upload(&self, bucket: &str, key: &str) -> Result<String> {
+        self.create_upload_with(bucket, key, ObjectSettings::default()).await
+    }
+
+    async fn create_upload_with(&self, bucket: &str, key: &str, settings: ObjectSettings) -> Result<String> {
+        let mut store = self.store.lock().unwrap();
+        store.next_id += 1;
+        let upload_id = format!("memory-upload-{}", store.next_id);
+        store.uploads.insert(
+            upload_id.to_owned(),
+            Upload {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                parts: BTreeMap::new(),
+                settings,
+            },
+        );
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Body,
+    ) -> Result<String> {
+        if part_number < 1 || part_number > MAX_PART_NUMBER {
+            return Err(S3Error::coded(
+                "InvalidArgument",
+                format!("part number {} must be between 1 and {}", part_number, MAX_PART_NUMBER),
+            )
+            .into());
+        }
+
+        let mut data = vec![];
+        let mut stream = body.stream;
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+
+        if data.len() as u64 != body.len {
+            return Err(S3Error::coded(
+                "IncompleteBody",
+                format!("expected {} bytes but received {}", body.len, data.len()),
+            )
+            .into());
+        }
+        if base64::encode(md5::compute(&data).0) != body.md5 {
+            return Err(S3Error::coded("BadDigest", "content md5 does not match".to_owned()).into());
+        }
+
+        let mut store = self.store.lock().unwrap();
+        let upload = store
+            .uploads
+            .get_mut(upload_id)
+            .filter(|upload| upload.bucket == bucket && upload.key == key)
+            .ok_or_else(|| MemoryBackend::no_such_upload(upload_id))?;
+
+        let etag = part_etag(&data);
+        upload.parts.insert(
+            part_number,
+            StoredPart {
+                data,
+                etag: etag.to_owned(),
+            },
+        );
+
+        Ok(etag)
+    }
+
+    async fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        self.complete(bucket, key, upload_id, parts, false)
+    }
+
+    async fn complete_upload_if_absent(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        self.complete(bucket, key, upload_id, parts, true)
+    }
+
+    async fn abort_upload(&self, bucket: &str, key: &str, upload_id: &str) -> Result<()> {
+        let mut store = self.store.lock().unwrap();
+        let exists = store
+            .uploads
+            .get(upload_id)
+            .map(|upload| upload.bucket == bucket && upload.key == key)
+            .unwrap_or(false);
+        if !exists {
+            return Err(MemoryBackend::no_such_upload(upload_id).into());
+        }
+        store.uploads.remove(upload_id);
+        Ok(())
+    }
+
+    async fn list_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<Vec<ListedPart>> {
+        let store = self.store.lock().unwrap();
+        let upload = store
+            .uploads
+            .get(upload_id)
+            .filter(|upload| upload.bucket == bucket && upload.key == key)
+            .ok_or_else(|| MemoryBackend::no_such_upload(upload_id))?;
+
+        Ok(upload
+            .parts
+            .iter()
+            .map(|(part_number, part)| ListedPart {
+                part_number: *part_number,
+                etag: part.etag.to_owned(),
+                size: part.data.len() as u64,
+            })
+            .collect())
+    }
+
+    async fn list_uploads(&self, bucket: &str, key: &str) -> Result<Vec<String>> {
+        let store = self.store.lock().unwrap();
+        let mut ids: Vec<String> = store
+            .uploads
+            .iter()
+            .filter(|(_, upload)| upload.bucket == bucket && upload.key == key)
+            .map(|(upload_id, _)| upload_id.to_owned())
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<ObjectInfo>> {
+        Ok(self.object(bucket, key).map(|object| ObjectInfo {
+            size: object.data.len() as u64,
+            etag: Some(object.etag.to_owned()),
+            version_id: Some(object.version_id.to_owned()),
+            metadata: object.metadata.to_owned(),
+            content_encoding: object.content_encoding.to_owned(),
+        }))
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        self.put(bucket, key, data, false)
+    }
+
+    async fn put_object_if_absent(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        self.put(bucket, key, data, true)
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.object(bucket, key).map(|object| object.data.to_owned()))
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<()> {
+        self.store
+            .lock()
+            .unwrap()
+            .objects
+            .remove(&(bucket.to_owned(), key.to_owned()));
+        Ok(())
+    }
+
+    async fn get_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        part_number: i64,
+    ) -> Result<Option<ByteStream>> {
+        let object = match self.object(bucket, key) {
+            Some(object) if version_id.is_none_or(|id| id == object.version_id) => object,
+            _ => return Ok(None),
+        };
+
+        let index = part_number as usize;
+        if part_number < 1 || index > object.part_sizes.len() {
+            return Err(S3Error::coded(
+                "InvalidPartNumber",
+                format!("{} has {} parts, not part {}", key, object.part_sizes.len(), part_number),
+            )
+            .into());
+        }
+        let start = object.part_sizes[..index - 1].iter().sum::<u64>() as usize;
+        let end = start + object.part_sizes[index - 1] as usize;
+
+        Ok(Some(ByteStream::from(object.data[start..end].to_vec())))
+    }
+
+    async fn head_part(&self, bucket: &str, key: &str, part_number: i64) -> Result<Option<PartInfo>> {
+        let object = match self.object(bucket, key) {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+
+        if part_number < 1 {
+            return Ok(None);
+        }
+        Ok(object.part_sizes.get(part_number as usize - 1).map(|size| PartInfo {
+            size: *size,
+            parts_count: Some(object.part_sizes.len() as i64),
+        }))
+    }
+
+    async fn get_range(&self, bucket: &str, key: &str, offset: u64, len: u64) -> Result<Option<ByteStream>> {
+        let object = match self.object(bucket, key) {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+
+        let size = object.data.len() as u64;
+        if len == 0 || offset >= size {
+            return Err(S3Error::coded(
+                "InvalidRange",
+                format!("{} is {} bytes, can't read {} from {}", key, size, len, offset),
+            )
+            .into());
+        }
+        let end = cmp::min(offset + len, size);
+
+        Ok(Some(ByteStream::from(object.data[offset as usize..end as usize].to_vec())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static BUCKET: &str = "bucket";
+    static KEY: &str = "key";
+
+    fn body(data: &[u8]) -> Body {
+        Body {
+            stream: ByteStream::from(data.to_vec()),
+            len: data.len() as u64,
+            md5: base64::encode(md5::compute(data).0),
+        }
+    }
+
+    fn small_parts() -> MemoryBackend {
+        let mut backend = MemoryBackend::new();
+        backend.min_part_size = 4;
+        backend
+    }
+
+    fn code<T: std::fmt::Debug>(result: Result<T>) -> String {
+        super::super::error_code(&result.unwrap_err()).unwrap_or_default()
+    }
+
+    async fn upload(backend: &MemoryBackend, upload_id: &str, parts: &[&[u8]]) -> Vec<CompletedPart> {
+        let mut completed = vec![];
+        for (part_number, data) in (1..).zip(parts) {
+            let etag = backend.upload_part(BUCKET, KEY, upload_id, part_number, body(data)).await.unwrap();
+            assert_eq!(etag, format!("\"{:x}\"", md5::compute(data)));
+            completed.push(CompletedPart { part_number, etag });
+        }
+        completed
+    }
+
+    #[tokio::test]
+    async fn completes_uploads_into_objects() {
+        let backend = small_parts();
+        let upload_id = backend.create_upload(BUCKET, KEY).await.unwrap();
+        let parts = upload(&backend, &upload_id, &[b"first", b"second", b"3"]).await;
+
+        let listed = backend.list_parts(BUCKET, KEY, &upload_id).await.unwrap();
+        let sizes: Vec<(i64, u64)> = listed.iter().map(|part| (part.part_number, part.size)).collect();
+        assert_eq!(sizes, vec![(1, 5), (2, 6), (3, 1)]);
+        assert_eq!(listed[1].etag, parts[1].etag);
+
+        let completion = backend.complete_upload(BUCKET, KEY, &upload_id, parts).await.unwrap();
+        let object = backend.object(BUCKET, KEY).unwrap();
+        assert_eq!(object.data, b"firstsecond3");
+        assert_eq!(completion.etag.as_ref(), Some(&object.etag));
+        assert!(backend.upload_ids().is_empty());
+    }
+
+    #[tokio::test]
+    async fn gives_objects_the_composite_etag() {
+        let backend = small_parts();
+        let upload_id = backend.create_upload(BUCKET, KEY).await.unwrap();
+        let parts = upload(&backend, &upload_id, &[b"abcd", b"ef"]).await;
+        let completion = backend.complete_upload(BUCKET, KEY, &upload_id, parts).await.unwrap();
+
+        let digests: Vec<u8> = [&b"abcd"[..], b"ef"].iter().flat_map(|data| md5::compute(data).0.to_vec()).collect();
+        let expected = format!("\"{:x}-2\"", md5::compute(&digests));
+        assert_eq!(completion.etag, Some(expected));
+    }
+
+    #[tokio::test]
+    async fn lists_uploads_in_progress() {
+        let backend = small_parts();
+        let first = backend.create_upload(BUCKET, KEY).await.unwrap();
+        let second = backend.create_upload(BUCKET, KEY).await.unwrap();
+        backend.create_upload(BUCKET, "other").await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(backend.list_uploads(BUCKET, KEY).await.unwrap(), vec![first.to_owned(), second]);
+        assert_eq!(backend.upload_ids().len(), 3);
+        assert!(backend.list_parts(BUCKET, KEY, &first).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn aborted_uploads_are_gone() {
+        let backend = small_parts();
+        let upload_id = backend.create_upload(BUCKET, KEY).await.unwrap();
+        let parts = upload(&backend, &upload_id, &[b"data"]).await;
+        backend.abort_upload(BUCKET, KEY, &upload_id).await.unwrap();
+
+        assert_eq!(code(backend.list_parts(BUCKET, KEY, &upload_id).await), "NoSuchUpload");
+        assert_eq!(code(backend.upload_part(BUCKET, KEY, &upload_id, 2, body(b"more")).await), "NoSuchUpload");
+        assert_eq!(code(backend.complete_upload(BUCKET, KEY, &upload_id, parts).await), "NoSuchUpload");
+        assert_eq!(code(backend.abort_upload(BUCKET, KEY, &upload_id).await), "NoSuchUpload");
+        assert!(backend.object(BUCKET, KEY).is_none());
+    }
+
+    #[tokio::test]
+    async fn uploads_belong_to_their_key() {
+        let backend = small_parts();
+        let upload_id = backend.create_upload(BUCKET, KEY).await.unwrap();
+        assert_eq!(code(backend.list_parts(BUCKET, "other", &upload_id).await), "NoSuchUpload");
+        assert_eq!(code(backend.abort_upload("other", KEY, &upload_id).await), "NoSuchUpload");
+    }
+
+    #[tokio::test]
+    async fn checks_part_bodies() {
+        let backend = small_parts();
+        let upload_id = backend.create_upload(BUCKET, KEY).await.unwrap();
+
+        let mut short = body(b"data");
+        short.len = 5;
+        assert_eq!(code(backend.upload_part(BUCKET, KEY, &upload_id, 1, short).await), "IncompleteBody");
+        let mut corrupt = body(b"data");
+        corrupt.md5 = base64::encode(md5::compute(b"other").0);
+        assert_eq!(code(backend.upload_part(BUCKET, KEY, &upload_id, 1, corrupt).await), "BadDigest");
+        assert_eq!(code(backend.upload_part(BUCKET, KEY, &upload_id, 0, body(b"data")).await), "InvalidArgument");
+        let too_many = backend.upload_part(BUCKET, KEY, &upload_id, MAX_PART_NUMBER + 1, body(b"data")).await;
+        assert_eq!(code(too_many), "InvalidArgument");
+    }
+
+    #[tokio::test]
+    async fn enforces_completion_rules() {
+        let backend = small_parts();
+        let upload_id = backend.create_upload(BUCKET, KEY).await.unwrap();
+        let parts = upload(&backend, &upload_id, &[b"abc", b"defg"]).await;
+
+        // every part but the last must reach the minimum size
+        assert_eq!(code(backend.complete_upload(BUCKET, KEY, &upload_id, parts.clone()).await), "EntityTooSmall");
+
+        let mut reversed = parts.clone();
+        reversed.reverse();
+        assert_eq!(code(backend.complete_upload(BUCKET, KEY, &upload_id, reversed).await), "InvalidPartOrder");
+
+        let mut stale = parts[1..].to_vec();
+        stale[0].etag = format!("\"{:x}\"", md5::compute(b"old"));
+        assert_eq!(code(backend.complete_upload(BUCKET, KEY, &upload_id, stale).await), "InvalidPart");
+
+        assert_eq!(code(backend.complete_upload(BUCKET, KEY, &upload_id, vec![]).await), "MalformedXML");
+
+        // the last part alone may be small, and parts may be left out
+        backend.complete_upload(BUCKET, KEY, &upload_id, parts[1..].to_vec()).await.unwrap();
+        assert_eq!(backend.object(BUCKET, KEY).unwrap().data, b"defg");
+    }
+}
@@ -0,0 +1,347 @@
+use crate::error::Error;
+use crate::result::Result;
+use async_trait::async_trait;
+use rusoto_core::ByteStream;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+pub mod fs;
+pub mod memory;
+pub mod presigned;
+pub mod s3;
+
+pub use self::fs::FsBackend;
+pub use memory::MemoryBackend;
+pub use presigned::PresignedBackend;
+pub use s3::S3Backend;
+
+/// The smallest part S3 accepts, other than the last part of an upload.
+pub static MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The largest part number S3 accepts.
+pub static MAX_PART_NUMBER: i64 = 10000;
+
+/// An error from an object store request, keeping the S3 error code when
+/// there is one.
+#[derive(Debug)]
+pub struct S3Error {
+    pub code: Option<String>,
+    pub msg: String,
+}
+
+impl S3Error {
+    pub fn coded(code: &str, msg: String) -> Self {
+        S3Error {
+            code: Some(code.to_owned()),
+            msg,
+        }
+    }
+}
+
+impl std::error::Error for S3Error {}
+
+impl fmt::Display for S3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+/// The S3 error code behind an error, if it came from an object store request.
+pub fn error_code(err: &Error) -> Option<String> {
+    err.downcast_ref::<S3Error>()
+        .and_then(|err| err.code.to_owned())
+}
+
+/// The etag S3 gives a part: the quoted hex md5 of its bytes.
+pub fn part_etag(data: &[u8]) -> String {
+    format!("\"{:x}\"", md5::compute(data))
+}
+
+/// The etag S3 gives an object assembled from parts: the md5 of the
+/// concatenated binary part md5s, followed by the number of parts.
+pub fn multipart_etag(md5s: &[[u8; 16]]) -> String {
+    let digests: Vec<u8> = md5s.iter().flat_map(|md5| md5.iter().cloned()).collect();
+    format!("\"{:x}-{}\"", md5::compute(&digests), md5s.len())
+}
+
+/// A part body ready to send, with its length and base64 md5.
+pub struct Body {
+    pub stream: ByteStream,
+    pub len: u64,
+    pub md5: String,
+}
+
+/// A part to include when completing an upload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletedPart {
+    pub part_number: i64,
+    pub etag: String,
+}
+
+/// A part the store holds for an upload in progress.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListedPart {
+    pub part_number: i64,
+    pub etag: String,
+    pub size: u64,
+}
+
+/// What the store reports about the object created by completing an upload,
+/// along with its size when known.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Completion {
+    pub etag: Option<String>,
+    pub version_id: Option<String>,
+    pub location: Option<String>,
+    pub size: Option<u64>,
+}
+
+/// What the store reports about an object that exists.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectInfo {
+    pub size: u64,
+    pub etag: Option<String>,
+    pub version_id: Option<String>,
+    /// User metadata, without the `x-amz-meta-` prefix.
+    pub metadata: HashMap<String, String>,
+    pub content_encoding: Option<String>,
+}
+
+/// What an upload gives the object it creates, besides its data.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectSettings {
+    /// User metadata, without the `x-amz-meta-` prefix.
+    pub metadata: HashMap<String, String>,
+    pub content_encoding: Option<String>,
+}
+
+/// What the store reports about one part of an object.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PartInfo {
+    pub size: u64,
+    /// How many parts the object has, if it was completed from parts.
+    pub parts_count: Option<i64>,
+}
+
+/// The multipart upload operations of an object store.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    async fn create_upload(&self, bucket: &str, key: &str) -> Result<String>;
+
+    /// Create an upload whose object will have these settings. Stores that
+    /// don't keep metadata or encodings can't do this.
+    async fn create_upload_with(&self, _bucket: &str, key: &str, _settings: ObjectSettings) -> Result<String> {
+        Err(S3Error::coded(
+            "NotImplemented",
+            format!("can't give {} metadata, the store doesn't keep it", key),
+        )
+        .into())
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Body,
+    ) -> Result<String>;
+
+    async fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion>;
+
+    /// Complete an upload only if there is no object at the key, failing
+    /// with `PreconditionFailed` if there is. Stores that can't do this in
+    /// one request check first, leaving a moment for another writer.
+    async fn complete_upload_if_absent(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        if self.head_object(bucket, key).await?.is_some() {
+            return Err(S3Error::coded("PreconditionFailed", format!("{} already exists", key)).into());
+        }
+        self.complete_upload(bucket, key, upload_id, parts).await
+    }
+
+    async fn abort_upload(&self, bucket: &str, key: &str, upload_id: &str) -> Result<()>;
+
+    async fn list_parts(&self, bucket: &str, key: &str, upload_id: &str)
+        -> Result<Vec<ListedPart>>;
+
+    /// The ids of the uploads in progress for a key.
+    async fn list_uploads(&self, bucket: &str, key: &str) -> Result<Vec<String>>;
+
+    /// The object at a key, or `None` if there isn't one.
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<ObjectInfo>>;
+
+    /// Write a small object in a single request.
+    async fn put_object(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()>;
+
+    /// Write a small object only if there is no object at the key, failing
+    /// with `PreconditionFailed` if there is. Stores that can't make a write
+    /// conditional can't do this.
+    async fn put_object_if_absent(&self, _bucket: &str, key: &str, _data: Vec<u8>) -> Result<()> {
+        Err(S3Error::coded(
+            "NotImplemented",
+            format!("can't write {} only if absent, the store can't make writes conditional", key),
+        )
+        .into())
+    }
+
+    /// Read a whole object, or `None` if there isn't one.
+    async fn get_object(&self, bucket: &str, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Delete an object, if it exists.
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<()>;
+
+    /// Stream one part of an object completed from parts, of the given
+    /// version if there is one, or `None` if there is no such object. Parts
+    /// are counted from 1 in order, whatever numbers they were uploaded with.
+    /// Stores that don't keep part boundaries can't do this.
+    async fn get_part(
+        &self,
+        _bucket: &str,
+        key: &str,
+        _version_id: Option<&str>,
+        part_number: i64,
+    ) -> Result<Option<ByteStream>> {
+        Err(S3Error::coded(
+            "NotImplemented",
+            format!("can't read part {} of {}, the store doesn't keep parts", part_number, key),
+        )
+        .into())
+    }
+
+    /// One part of an object, counted as for `get_part`, or `None` if there
+    /// is no object or it has no such part. Stores that don't keep part
+    /// boundaries can't do this.
+    async fn head_part(&self, _bucket: &str, key: &str, part_number: i64) -> Result<Option<PartInfo>> {
+        Err(S3Error::coded(
+            "NotImplemented",
+            format!("can't read part {} of {}, the store doesn't keep parts", part_number, key),
+        )
+        .into())
+    }
+
+    /// Stream `len` bytes of an object from `offset`, or `None` if there is
+    /// no such object. `len` must be at least one.
+    async fn get_range(&self, _bucket: &str, key: &str, offset: u64, len: u64) -> Result<Option<ByteStream>> {
+        Err(S3Error::coded(
+            "NotImplemented",
+            format!("can't read {} bytes of {} from {}, the store doesn't do ranged reads", len, key, offset),
+        )
+        .into())
+    }
+}
+
+/// A shared backend, e.g. one used by a lease's heartbeat as well as the
+/// upload.
+#[async_trait]
+impl<B: Backend + ?Sized> Backend for Arc<B> {
+    async fn create_upload(&self, bucket: &str, key: &str) -> Result<String> {
+        (**self).create_upload(bucket, key).await
+    }
+
+    async fn create_upload_with(&self, bucket: &str, key: &str, settings: ObjectSettings) -> Result<String> {
+        (**self).create_upload_with(bucket, key, settings).await
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Body,
+    ) -> Result<String> {
+        (**self)
+            .upload_part(bucket, key, upload_id, part_number, body)
+            .await
+    }
+
+    async fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        (**self).complete_upload(bucket, key, upload_id, parts).await
+    }
+
+    async fn complete_upload_if_absent(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        (**self)
+            .complete_upload_if_absent(bucket, key, upload_id, parts)
+            .await
+    }
+
+    async fn abort_upload(&self, bucket: &str, key: &str, upload_id: &str) -> Result<()> {
+        (**self).abort_upload(bucket, key, upload_id).await
+    }
+
+    async fn list_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<Vec<ListedPart>> {
+        (**self).list_parts(bucket, key, upload_id).await
+    }
+
+    async fn list_uploads(&self, bucket: &str, key: &str) -> Result<Vec<String>> {
+        (**self).list_uploads(bucket, key).await
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<ObjectInfo>> {
+        (**self).head_object(bucket, key).await
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        (**self).put_object(bucket, key, data).await
+    }
+
+    async fn put_object_if_absent(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        (**self).put_object_if_absent(bucket, key, data).await
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get_object(bucket, key).await
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<()> {
+        (**self).delete_object(bucket, key).await
+    }
+
+    async fn get_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        part_number: i64,
+    ) -> Result<Option<ByteStream>> {
+        (**self).get_part(bucket, key, version_id, part_number).await
+    }
+
+    async fn head_part(&self, bucket: &str, key: &str, part_number: i64) -> Result<Option<PartInfo>> {
+        (**self).head_part(bucket, key, part_number).await
+    }
+
+    async fn get_range(&self, bucket: &str, key: &str, offset: u64, len: u64) -> Result<Option<ByteStream>> {
+        (**self).get_range(bucket, key, offset, len).await
+    }
+}
@@ -0,0 +1,318 @@
+use super::{Backend, Body, CompletedPart, Completion, ListedPart, ObjectInfo, S3Error};
+use crate::result::Result;
+use crate::state::Part;
+use async_trait::async_trait;
+use chrono::{SecondsFormat, Utc};
+use hyper::client::HttpConnector;
+use hyper::{Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use rusoto_core::credential::AwsCredentials;
+use rusoto_core::Region;
+use rusoto_s3::util::{PreSignedRequest, PreSignedRequestOption};
+use rusoto_s3::UploadPartRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// The longest presigned URLs last.
+pub static MAX_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A part of a plan, with the URL to PUT it to.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PlannedPart {
+    pub number: i64,
+    pub path: String,
+    pub url: String,
+}
+
+/// An upload created by a host holding credentials, with a presigned
+/// UploadPart URL for each part, so a host without them can upload the parts.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Plan {
+    pub bucket: String,
+    pub key: String,
+    pub upload_id: String,
+    /// When the URLs stop working.
+    pub expires: String,
+    pub parts: Vec<PlannedPart>,
+}
+
+impl Plan {
+    pub fn new(
+        region: &Region,
+        credentials: &AwsCredentials,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: &[Part],
+        expires_in: Duration,
+    ) -> Self {
+        let option = PreSignedRequestOption { expires_in };
+        let expires = Utc::now() + chrono::Duration::from_std(expires_in).unwrap_or_else(|_| chrono::Duration::zero());
+
+        let parts = parts
+            .iter()
+            .map(|part| {
+                let request = UploadPartRequest {
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                    upload_id: upload_id.to_owned(),
+                    part_number: part.number,
+                    ..Default::default()
+                };
+                PlannedPart {
+                    number: part.number,
+                    path: part.path.to_owned(),
+                    url: request.get_presigned_url(region, credentials, &option),
+                }
+            })
+            .collect();
+
+        Plan {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            upload_id: upload_id.to_owned(),
+            expires: expires.to_rfc3339_opts(SecondsFormat::Secs, true),
+            parts,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).map_err(|err| format!("error reading plan {:?}: {}", path, err))?;
+        Ok(serde_json::from_slice(&data).map_err(|err| format!("error reading plan {:?}: {}", path, err))?)
+    }
+
+    /// The plan's parts, to configure an upload with.
+    pub fn parts(&self) -> Vec<Part> {
+        self.parts
+            .iter()
+            .map(|part| Part::new(part.number, part.path.to_owned()))
+            .collect()
+    }
+}
+
+/// Uploads the parts of a plan with plain HTTP PUTs to their presigned URLs.
+/// Nothing else can be done without credentials, so the other operations
+/// fail.
+pub struct PresignedBackend {
+    upload_id: String,
+    urls: HashMap<i64, String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl PresignedBackend {
+    pub fn new(plan: &Plan) -> Self {
+        PresignedBackend {
+            upload_id: plan.upload_id.to_owned(),
+            urls: plan
+                .parts
+                .iter()
+                .map(|part| (part.number, part.url.to_owned()))
+                .collect(),
+            client: Client::builder().build(HttpsConnector::new()),
+        }
+    }
+
+    fn unsupported<T>(operation: &str) -> Result<T> {
+        Err(format!("{} needs credentials, which presigned uploads don't have", operation).into())
+    }
+}
+
+#[async_trait]
+impl Backend for PresignedBackend {
+    async fn create_upload(&self, _bucket: &str, _key: &str) -> Result<String> {
+        PresignedBackend::unsupported("creating an upload")
+    }
+
+    async fn upload_part(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Body,
+    ) -> Result<String> {
+        let url = match self.urls.get(&part_number) {
+            Some(url) if upload_id == self.upload_id => url,
+            _ => {
+                return Err(S3Error::coded(
+                    "NoSuchUpload",
+                    format!("no presigned url for part {} of upload {}", part_number, upload_id),
+                )
+                .into())
+            }
+        };
+
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(url.as_str())
+            .header("Content-Length", body.len)
+            .header("Content-MD5", body.md5)
+            .body(hyper::Body::wrap_stream(body.stream))?;
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|err| S3Error::coded("HttpDispatch", format!("upload part error: {}", err)))?;
+
+        let status = response.status();
+        if status.is_success() {
+            let etag = response
+                .headers()
+                .get("ETag")
+                .and_then(|etag| etag.to_str().ok())
+                .ok_or("upload part response has no etag")?;
+            return Ok(etag.to_owned());
+        }
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap_or_default();
+        let body = String::from_utf8_lossy(&body);
+        let code = body
+            .split("<Code>")
+            .nth(1)
+            .and_then(|rest| rest.split("</Code>").next())
+            .map(|code| code.to_owned())
+            .unwrap_or_else(|| format!("Http{}", status.as_u16()));
+        let message = body
+            .split("<Message>")
+            .nth(1)
+            .and_then(|rest| rest.split("</Message>").next())
+            .unwrap_or_else(|| status.as_str());
+
+        Err(S3Error::coded(&code, format!("upload part error: {}: {}", code, message)).into())
+    }
+
+    async fn complete_upload(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        _upload_id: &str,
+        _parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        PresignedBackend::unsupported("completing an upload")
+    }
+
+    async fn abort_upload(&self, _bucket: &str, _key: &str, _upload_id: &str) -> Result<()> {
+        PresignedBackend::unsupported("aborting an upload")
+    }
+
+    async fn list_parts(
+        &self,
+        _bucket: &str,
+        _key: &str,
+        _upload_id: &str,
+    ) -> Result<Vec<ListedPart>> {
+        PresignedBackend::unsupported("listing parts")
+    }
+
+    async fn list_uploads(&self, _bucket: &str, _key: &str) -> Result<Vec<String>> {
+        PresignedBackend::unsupported("listing uploads")
+    }
+
+    async fn head_object(&self, _bucket: &str, _key: &str) -> Result<Option<ObjectInfo>> {
+        PresignedBackend::unsupported("reading object metadata")
+    }
+
+    async fn put_object(&self, _bucket: &str, _key: &str, _data: Vec<u8>) -> Result<()> {
+        PresignedBackend::unsupported("writing an object")
+    }
+
+    async fn get_object(&self, _bucket: &str, _key: &str) -> Result<Option<Vec<u8>>> {
+        PresignedBackend::unsupported("reading an object")
+    }
+
+    async fn delete_object(&self, _bucket: &str, _key: &str) -> Result<()> {
+        PresignedBackend::unsupported("deleting an object")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use rusoto_core::ByteStream;
+    use std::convert::Infallible;
+
+    /// Serve one canned response to every request, returning the address.
+    fn serve(status: u16, etag: Option<&'static str>, body: &'static str) -> String {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<hyper::Body>| async move {
+                let mut response = Response::builder().status(status);
+                if request.headers().get("Content-MD5").is_none() {
+                    response = response.status(400);
+                }
+                if let Some(etag) = etag {
+                    response = response.header("ETag", etag);
+                }
+                response.body(hyper::Body::from(body))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/bucket/key?partNumber=1", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    fn backend(url: String) -> PresignedBackend {
+        PresignedBackend::new(&Plan {
+            bucket: "bucket".to_owned(),
+            key: "key".to_owned(),
+            upload_id: "upload".to_owned(),
+            expires: "2000-01-01T00:00:00Z".to_owned(),
+            parts: vec![PlannedPart {
+                number: 1,
+                path: "part-1".to_owned(),
+                url,
+            }],
+        })
+    }
+
+    async fn upload(backend: &PresignedBackend, upload_id: &str, part_number: i64) -> Result<String> {
+        let body = Body {
+            stream: ByteStream::from(b"data".to_vec()),
+            len: 4,
+            md5: base64::encode(md5::compute(b"data").0),
+        };
+        backend.upload_part("bucket", "key", upload_id, part_number, body).await
+    }
+
+    fn code(result: Result<String>) -> (String, String) {
+        let err = result.unwrap_err();
+        (super::super::error_code(&err).unwrap_or_default(), err.to_string())
+    }
+
+    #[tokio::test]
+    async fn returns_the_etag_of_an_uploaded_part() {
+        let backend = backend(serve(200, Some("\"etag\""), ""));
+        assert_eq!(upload(&backend, "upload", 1).await.unwrap(), "\"etag\"");
+    }
+
+    #[tokio::test]
+    async fn reads_the_code_and_message_of_s3_errors() {
+        let xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>AccessDenied</Code>\
+                   <Message>Request has expired</Message><RequestId>1</RequestId></Error>";
+        let backend = backend(serve(403, None, xml));
+        let (code, msg) = code(upload(&backend, "upload", 1).await);
+        assert_eq!(code, "AccessDenied");
+        assert!(msg.ends_with("AccessDenied: Request has expired"), "{}", msg);
+    }
+
+    #[tokio::test]
+    async fn codes_errors_without_a_body_by_status() {
+        let backend = backend(serve(503, None, ""));
+        let (code, msg) = code(upload(&backend, "upload", 1).await);
+        assert_eq!(code, "Http503");
+        assert!(msg.ends_with("Http503: 503"), "{}", msg);
+    }
+
+    #[tokio::test]
+    async fn has_no_url_for_other_parts_or_uploads() {
+        let backend = backend(serve(200, Some("\"etag\""), ""));
+        assert_eq!(code(upload(&backend, "upload", 2).await).0, "NoSuchUpload");
+        assert_eq!(code(upload(&backend, "other", 1).await).0, "NoSuchUpload");
+        assert!(backend.complete_upload("bucket", "key", "upload", vec![]).await.is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use super::{Backend, Body, CompletedPart, Completion, ListedPart, ObjectInfo, ObjectSettings, PartInfo, S3Error};
+use crate::result::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use rusoto_core::{ByteStream, RusotoError};
+use rusoto_s3::{
+    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+    CreateMultipartUploadRequest, DeleteObjectRequest, GetObjectError, GetObjectRequest, HeadObjectError,
+    HeadObjectRequest, ListMultipartUploadsRequest, ListPartsRequest, PutObjectRequest, S3Client,
+    UploadPartRequest, S3,
+};
+
+impl S3Error {
+    pub fn new<E: std::error::Error + 'static>(context: &str, err: RusotoError<E>) -> Self {
+        let code = match err {
+            RusotoError::Service(ref err) => {
+                let name = format!("{:?}", err);
+                Some(name.split('(').next().unwrap_or("Service").to_owned())
+            }
+            RusotoError::Unknown(ref response) => {
+                let body = response.body_as_str();
+                let code = body
+                    .split("<Code>")
+                    .nth(1)
+                    .and_then(|rest| rest.split("</Code>").next())
+                    .map(|code| code.to_owned());
+                code.or_else(|| Some(format!("Http{}", response.status.as_u16())))
+            }
+            RusotoError::HttpDispatch(_) => Some("HttpDispatch".to_owned()),
+            RusotoError::Credentials(_) => Some("Credentials".to_owned()),
+            RusotoError::Validation(_) => Some("Validation".to_owned()),
+            RusotoError::ParseError(_) => Some("ParseError".to_owned()),
+            RusotoError::Blocking => Some("Blocking".to_owned()),
+        };
+
+        S3Error {
+            code,
+            msg: format!("{}: {}", context, err),
+        }
+    }
+}
+
+/// Amazon S3, or any store speaking its API.
+///
+/// The client can't make completing an upload conditional, so
+/// `complete_upload_if_absent` is the default check then complete, and only
+/// narrows the window for another writer to be replaced.
+pub struct S3Backend {
+    pub s3client: S3Client,
+}
+
+impl S3Backend {
+    pub fn new(s3client: S3Client) -> Self {
+        S3Backend { s3client }
+    }
+}
+
+#[async_trait]
+impl Backend for S3Backend {
+    async fn create_upload(&self, bucket: &str, key: &str) -> Result<String> {
+        self.create_upload_with(bucket, key, ObjectSettings::default()).await
+    }
+
+    async fn create_upload_with(&self, bucket: &str, key: &str, settings: ObjectSettings) -> Result<String> {
+        let multipart_upload = self
+            .s3client
+            .create_multipart_upload(CreateMultipartUploadRequest {
+                acl: None,
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                metadata: Some(settings.metadata).filter(|metadata| !metadata.is_empty()),
+                content_encoding: settings.content_encoding,
+                ..Default::default()
+            })
+            .await
+            .map_err(|err| S3Error::new("error creating multipart upload request", err))?;
+
+        let upload_id = multipart_upload
+            .upload_id
+            .ok_or("no upload id returned by create multipart upload request")?;
+
+        Ok(upload_id)
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Body,
+    ) -> Result<String> {
+        let upload = self
+            .s3client
+            .upload_part(UploadPartRequest {
+                body: Some(body.stream),
+                bucket: bucket.to_string(),
+                content_md5: Some(body.md5),
+                content_length: Some(body.len as i64),
+                key: key.to_string(),
+                part_number,
+                upload_id: upload_id.to_string(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|err| S3Error::new("error uploading part", err))?;
+
+        log::debug!("uploaded part {} with etag {:?}", part_number, upload.e_tag);
+
+        Ok(upload.e_tag.ok_or("missing etag in uploaded part")?)
+    }
+
+    async fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        log::info!("completing upload");
+        let completed_multipart_upload = CompletedMultipartUpload {
+            parts: Some(
+                parts
+                    .into_iter()
+                    .map(|part| rusoto_s3::CompletedPart {
+                        e_tag: Some(part.etag),
+                        part_number: Some(part.part_number),
+                    })
+                    .collect(),
+            ),
+        };
+
+        let output = self
+            .s3client
+            .complete_multipart_upload(CompleteMultipartUploadRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                multipart_upload: Some(completed_multipart_upload),
+                upload_id: upload_id.to_owned(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|err| S3Error::new("error completing multipart upload", err))?;
+
+        Ok(Completion {
+            etag: output.e_tag,
+            version_id: output.version_id,
+            location: output.location,
+            size: None,
+        })
+    }
+
+    async fn abort_upload(&self, bucket: &str, key: &str, upload_id: &str) -> Result<()> {
+        self.s3client
+            .abort_multipart_upload(AbortMultipartUploadRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                upload_id: upload_id.to_owned(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|err| S3Error::new("error aborting upload", err))?;
+
+        Ok(())
+    }
+
+    async fn list_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<Vec<ListedPart>> {
+        let mut listed = vec![];
+        let mut marker = None;
+
+        loop {
+            let output = self
+                .s3client
+                .list_parts(ListPartsRequest {
+                    bucket: bucket.to_owned(),
+                    key: key.to_owned(),
+                    upload_id: upload_id.to_owned(),
+                    part_number_marker: marker,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|err| S3Error::new("error listing parts", err))?;
+
+            for part in output.parts.unwrap_or_default() {
+                listed.push(ListedPart {
+                    part_number: part.part_number.ok_or("missing part number in listed part")?,
+                    etag: part.e_tag.ok_or("missing etag in listed part")?,
+                    size: part.size.unwrap_or(0) as u64,
+                });
+            }
+
+            if output.is_truncated != Some(true) {
+                break;
+            }
+            marker = output.next_part_number_marker;
+        }
+
+        Ok(listed)
+    }
+
+    async fn list_uploads(&self, bucket: &str, key: &str) -> Result<Vec<String>> {
+        let mut ids = vec![];
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+
+        loop {
+            let output = self
+                .s3client
+                .list_multipart_uploads(ListMultipartUploadsRequest {
+                    bucket: bucket.to_owned(),
+                    prefix: Some(key.to_owned()),
+                    key_marker,
+                    upload_id_marker,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|err| S3Error::new("error listing multipart uploads", err))?;
+
+            for upload in output.uploads.unwrap_or_default() {
+                // the prefix also matches longer keys
+                if upload.key.as_deref() == Some(key) {
+                    ids.push(upload.upload_id.ok_or("missing upload id in listed upload")?);
+                }
+            }
+
+            if output.is_truncated != Some(true) {
+                break;
+            }
+            key_marker = output.next_key_marker;
+            upload_id_marker = output.next_upload_id_marker;
+        }
+
+        Ok(ids)
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<ObjectInfo>> {
+        let output = match self
+            .s3client
+            .head_object(HeadObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(output) => output,
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => return Ok(None),
+            // a HEAD response has no body to carry an error code
+            Err(RusotoError::Unknown(ref response)) if response.status.as_u16() == 404 => {
+                return Ok(None)
+            }
+            Err(err) => return Err(S3Error::new("error reading object metadata", err).into()),
+        };
+
+        Ok(Some(ObjectInfo {
+            size: output.content_length.unwrap_or(0) as u64,
+            etag: output.e_tag,
+            version_id: output.version_id,
+            metadata: output.metadata.unwrap_or_default(),
+            content_encoding: output.content_encoding,
+        }))
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        let md5 = base64::encode(md5::compute(&data).0);
+        self.s3client
+            .put_object(PutObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                content_length: Some(data.len() as i64),
+                content_md5: Some(md5),
+                body: Some(data.into()),
+                ..Default::default()
+            })
+            .await
+            .map_err(|err| S3Error::new("error putting object", err))?;
+
+        Ok(())
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let output = match self
+            .s3client
+            .get_object(GetObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(output) => output,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(err) => return Err(S3Error::new("error getting object", err).into()),
+        };
+
+        let mut data = vec![];
+        if let Some(mut body) = output.body {
+            while let Some(chunk) = body.next().await {
+                data.extend_from_slice(&chunk?);
+            }
+        }
+
+        Ok(Some(data))
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<()> {
+        self.s3client
+            .delete_object(DeleteObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                ..Default::default()
+            })
+            .await
+            .map_err(|err| S3Error::new("error deleting object", err))?;
+
+        Ok(())
+    }
+
+    async fn get_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        part_number: i64,
+    ) -> Result<Option<ByteStream>> {
+        let output = match self
+            .s3client
+            .get_object(GetObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                version_id: version_id.map(|id| id.to_owned()),
+                part_number: Some(part_number),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(output) => output,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(err) => return Err(S3Error::new("error getting part", err).into()),
+        };
+
+        Ok(Some(output.body.unwrap_or_else(|| ByteStream::from(vec![]))))
+    }
+
+    async fn head_part(&self, bucket: &str, key: &str, part_number: i64) -> Result<Option<PartInfo>> {
+        let output = match self
+            .s3client
+            .head_object(HeadObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                part_number: Some(part_number),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(output) => output,
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => return Ok(None),
+            // no such key, or no such part
+            Err(RusotoError::Unknown(ref response)) if [404, 416].contains(&response.status.as_u16()) => {
+                return Ok(None)
+            }
+            Err(err) => return Err(S3Error::new("error reading part metadata", err).into()),
+        };
+
+        Ok(Some(PartInfo {
+            size: output.content_length.unwrap_or(0) as u64,
+            parts_count: output.parts_count,
+        }))
+    }
+
+    async fn get_range(&self, bucket: &str, key: &str, offset: u64, len: u64) -> Result<Option<ByteStream>> {
+        let output = match self
+            .s3client
+            .get_object(GetObjectRequest {
+                bucket: bucket.to_owned(),
+                key: key.to_owned(),
+                range: Some(format!("bytes={}-{}", offset, offset + len - 1)),
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(output) => output,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+            Err(err) => return Err(S3Error::new("error getting range", err).into()),
+        };
+
+        Ok(Some(output.body.unwrap_or_else(|| ByteStream::from(vec![]))))
+    }
+}
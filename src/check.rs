@@ -0,0 +1,203 @@
+//! Comparing local part files with the object at a key, without uploading
+//! anything.
+
+use crate::backend::{Backend, ObjectInfo};
+use crate::result::Result;
+use crate::state::Part;
+use crate::upload::{self, Order};
+use std::path::Path;
+
+/// A part whose size differs between the local files and the object, or
+/// which only one of them has.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartDifference {
+    /// The local part's number, or for a part only the object has, its
+    /// position in the object.
+    pub number: i64,
+    pub local: Option<u64>,
+    pub remote: Option<u64>,
+}
+
+/// How the local parts compare with the object at a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    /// The etag uploading the local parts would give the object.
+    pub etag: Option<String>,
+    pub size: u64,
+    /// The object at the key, if there is one.
+    pub object: Option<ObjectInfo>,
+    /// How many parts the object has, if the store says.
+    pub parts_count: Option<i64>,
+    pub differences: Vec<PartDifference>,
+}
+
+impl Comparison {
+    /// Whether the object has the size and etag the local parts would give
+    /// it, and parts of the same sizes.
+    pub fn identical(&self) -> bool {
+        match self.object {
+            Some(ref object) => {
+                object.size == self.size
+                    && object.etag.is_some()
+                    && object.etag == self.etag
+                    && self.differences.is_empty()
+            }
+            None => false,
+        }
+    }
+}
+
+/// Hash the part files matching a pattern, numbered and ordered by
+/// `upload::get_parts` and packed into parts of a size if given, as an
+/// upload would.
+pub async fn hash_parts(pattern: &str, order: &Order, pack: Option<u64>) -> Result<Vec<Part>> {
+    let mut parts = upload::get_parts(pattern, order)?;
+    if let Some(size) = pack {
+        parts = upload::pack(&parts, size)?;
+    }
+    for part in parts.iter_mut() {
+        let digest = match upload::pieces(part) {
+            None => upload::digest_file(Path::new(&part.path)).await,
+            Some(pieces) => upload::digest_pieces(&pieces).await,
+        };
+        let (size, md5) = digest
+            .map_err(|err| format!("error hashing {}: {}", part.path, err))?;
+        part.size = Some(size);
+        part.md5 = Some(md5);
+    }
+    Ok(parts)
+}
+
+/// Compare hashed parts with the object at a key: its size and etag, and
+/// the size of each of its parts, including any beyond the local ones.
+pub async fn compare<B: Backend>(backend: &B, bucket: &str, key: &str, parts: &[Part]) -> Result<Comparison> {
+    let object = backend.head_object(bucket, key).await?;
+
+    let mut differences = vec![];
+    let mut remote_count = None;
+    if object.is_some() {
+        // the object's parts are counted in order, whatever numbers they
+        // were uploaded with
+        for (position, part) in (1..).zip(parts) {
+            let remote = backend.head_part(bucket, key, position).await?;
+            if let Some(ref info) = remote {
+                remote_count = remote_count.or(info.parts_count);
+            }
+            let remote = remote.map(|info| info.size);
+            if remote != part.size {
+                differences.push(PartDifference {
+                    number: part.number,
+                    local: part.size,
+                    remote,
+                });
+            }
+        }
+
+        for position in parts.len() as i64 + 1..=remote_count.unwrap_or(0) {
+            let remote = backend.head_part(bucket, key, position).await?;
+            differences.push(PartDifference {
+                number: position,
+                local: None,
+                remote: remote.map(|info| info.size),
+            });
+        }
+    }
+
+    Ok(Comparison {
+        etag: upload::composite_etag(parts),
+        size: parts.iter().filter_map(|part| part.size).sum(),
+        object,
+        parts_count: remote_count,
+        differences,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Body, CompletedPart, MemoryBackend};
+    use rusoto_core::ByteStream;
+    use crate::testing::scratch;
+
+    static BUCKET: &str = "bucket";
+    static KEY: &str = "key";
+
+    /// Write each of `parts` to a file in `dir` and hash them as `check`
+    /// would.
+    async fn local(dir: &Path, parts: &[&[u8]]) -> Vec<Part> {
+        for (number, data) in (1..).zip(parts) {
+            std::fs::write(dir.join(format!("part-{}", number)), data).unwrap();
+        }
+        hash_parts(dir.join("part-*").to_str().unwrap(), &Order::default(), None).await.unwrap()
+    }
+
+    /// A store holding an object uploaded from `parts`.
+    async fn uploaded(parts: &[&[u8]]) -> MemoryBackend {
+        let mut backend = MemoryBackend::new();
+        backend.min_part_size = 1;
+        let upload_id = backend.create_upload(BUCKET, KEY).await.unwrap();
+        let mut completed = vec![];
+        for (part_number, data) in (1..).zip(parts) {
+            let body = Body {
+                stream: ByteStream::from(data.to_vec()),
+                len: data.len() as u64,
+                md5: base64::encode(md5::compute(data).0),
+            };
+            let etag = backend.upload_part(BUCKET, KEY, &upload_id, part_number, body).await.unwrap();
+            completed.push(CompletedPart { part_number, etag });
+        }
+        backend.complete_upload(BUCKET, KEY, &upload_id, completed).await.unwrap();
+        backend
+    }
+
+    #[tokio::test]
+    async fn matches_an_object_uploaded_from_the_same_parts() {
+        let dir = scratch("check-identical");
+        let parts = local(&dir, &[b"first", b"second"]).await;
+        let backend = uploaded(&[b"first", b"second"]).await;
+
+        let comparison = compare(&backend, BUCKET, KEY, &parts).await.unwrap();
+        assert!(comparison.identical(), "{:?}", comparison);
+        assert_eq!(comparison.etag, comparison.object.unwrap().etag);
+        assert_eq!((comparison.size, comparison.parts_count), (11, Some(2)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_parts_that_differ_or_only_the_object_has() {
+        let dir = scratch("check-different");
+        let parts = local(&dir, &[b"first", b"second"]).await;
+        let backend = uploaded(&[b"first", b"second!", b"3"]).await;
+
+        let comparison = compare(&backend, BUCKET, KEY, &parts).await.unwrap();
+        assert!(!comparison.identical());
+        let difference = |number, local, remote| PartDifference { number, local, remote };
+        assert_eq!(comparison.differences, vec![difference(2, Some(6), Some(7)), difference(3, None, Some(1))]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn differs_from_the_same_data_in_other_parts() {
+        let dir = scratch("check-repartitioned");
+        let parts = local(&dir, &[b"first", b"second"]).await;
+        let backend = uploaded(&[b"firstsecond"]).await;
+
+        let comparison = compare(&backend, BUCKET, KEY, &parts).await.unwrap();
+        assert!(!comparison.identical());
+        assert_ne!(comparison.etag, comparison.object.unwrap().etag);
+        assert_eq!(comparison.differences[0], PartDifference { number: 1, local: Some(5), remote: Some(11) });
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn differs_from_a_missing_object() {
+        let dir = scratch("check-missing");
+        let parts = local(&dir, &[b"first", b"second"]).await;
+
+        let comparison = compare(&MemoryBackend::new(), BUCKET, KEY, &parts).await.unwrap();
+        assert!(!comparison.identical());
+        assert!(comparison.object.is_none() && comparison.differences.is_empty());
+        assert_eq!(comparison.etag, upload::composite_etag(&parts));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+//! Compressing parts as they are uploaded.
+//!
+//! Each part is compressed on its own, into a gzip member or a zstd frame,
+//! and both formats read a run of these as one stream, so the object
+//! decompresses to the parts' data in order. Compression output isn't
+//! guaranteed to be the same from one run to the next, so the size and md5
+//! of each part as uploaded are recorded rather than expected.
+
+use crate::error::Error;
+use crate::result::Result;
+use crate::state::{Part, Segment};
+use crate::upload::{self, copy_pieces, spool, Hashing, PartBody, PartData, Piece};
+use flate2::write::GzEncoder;
+use std::fmt;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+/// The user metadata entry recording the codec of a compressed object.
+pub static METADATA_KEY: &str = "s3mu-compression";
+
+static DEFAULT_GZIP_LEVEL: u32 = 6;
+static DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// How parts are compressed, and at what level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Gzip(u32),
+    Zstd(i32),
+}
+
+impl Codec {
+    /// The `Content-Encoding` of an object compressed with the codec.
+    pub fn content_encoding(&self) -> &'static str {
+        match self {
+            Codec::Gzip(_) => "gzip",
+            Codec::Zstd(_) => "zstd",
+        }
+    }
+
+    /// Compress a part ready for upload, into memory if its data is no
+    /// larger than `buffer_threshold`, or else into an unlinked spool file
+    /// in the temporary directory, which `TMPDIR` sets.
+    pub async fn compress_part(self, part: &Part, buffer_threshold: u64) -> Result<PartData> {
+        let pieces = match upload::pieces(part) {
+            Some(pieces) => pieces,
+            None => {
+                let len = tokio::fs::metadata(&part.path)
+                    .await
+                    .map_err(|err| format!("error reading part file metadata: {}", err))?
+                    .len();
+                vec![Piece::File(Segment {
+                    path: part.path.to_owned(),
+                    offset: 0,
+                    len,
+                })]
+            }
+        };
+        let name = part.path.to_owned();
+
+        let data = tokio::task::spawn_blocking(move || self.compress_pieces(&pieces, buffer_threshold))
+            .await
+            .map_err(|err| format!("error compressing part {}: {}", name, err))?
+            .map_err(|err| format!("error compressing part {}: {}", name, err))?;
+        log::debug!("compressed part {} to {} bytes as {}", name, data.len, data.md5);
+        Ok(data)
+    }
+
+    fn compress_pieces(self, pieces: &[Piece], buffer_threshold: u64) -> io::Result<PartData> {
+        let len: u64 = pieces.iter().map(Piece::len).sum();
+        if len <= buffer_threshold {
+            let output = self.compress(pieces, Hashing::new(vec![]))?;
+            let md5 = base64::encode(output.digest.compute().0);
+            return Ok(PartData {
+                len: output.len,
+                md5,
+                body: PartBody::Memory(output.inner),
+            });
+        }
+
+        let mut output = self.compress(pieces, Hashing::new(spool()?))?;
+        output.inner.seek(SeekFrom::Start(0))?;
+        Ok(PartData {
+            len: output.len,
+            md5: base64::encode(output.digest.compute().0),
+            body: PartBody::Spool(output.inner),
+        })
+    }
+
+    fn compress<W: Write>(self, pieces: &[Piece], writer: W) -> io::Result<W> {
+        match self {
+            Codec::Gzip(level) => {
+                let mut encoder = GzEncoder::new(writer, flate2::Compression::new(level));
+                copy_pieces(pieces, &mut encoder)?;
+                encoder.finish()
+            }
+            Codec::Zstd(level) => {
+                let mut encoder = zstd::stream::write::Encoder::new(writer, level)?;
+                encoder.include_checksum(true)?;
+                copy_pieces(pieces, &mut encoder)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    /// Parse codecs like `zstd`, `zstd:19` or `gzip:9`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, level) = match s.trim().split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s.trim(), None),
+        };
+        let level = |default: i64, min: i64, max: i64| -> std::result::Result<i64, Error> {
+            let level = match level {
+                Some(level) => level
+                    .parse()
+                    .map_err(|err| format!("invalid level in {:?}: {}", s, err))?,
+                None => default,
+            };
+            if level < min || level > max {
+                return Err(format!("{} level must be from {} to {}", name, min, max).into());
+            }
+            Ok(level)
+        };
+
+        match name {
+            "gzip" => Ok(Codec::Gzip(level(DEFAULT_GZIP_LEVEL as i64, 0, 9)? as u32)),
+            "zstd" => Ok(Codec::Zstd(level(DEFAULT_ZSTD_LEVEL as i64, 1, 22)? as i32)),
+            name => Err(format!("unknown codec {:?}, expected gzip or zstd", name).into()),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::Gzip(level) => write!(f, "gzip:{}", level),
+            Codec::Zstd(level) => write!(f, "zstd:{}", level),
+        }
+    }
+}
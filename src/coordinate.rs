@@ -0,0 +1,520 @@
+//! Uploads whose parts are spread across hosts, coordinated through a
+//! directory they all share, e.g. over NFS.
+//!
+//! The coordinator owns the log. Once the upload has started it writes
+//! `job.json` describing it and an assignment per part not yet uploaded,
+//! `part-00001.json`. A worker claims an assignment whose file it holds by
+//! creating `part-00001.claim`, uploads the part and reports the result in
+//! `part-00001.done` or `part-00001.failed`. The coordinator logs each result
+//! as it arrives, hands failed parts out again, and completes the upload once
+//! every part has been reported, writing `finished` so the workers exit.
+//!
+//! A worker rewrites its claim while uploading, at a third of the claim
+//! timeout given in `job.json`. The coordinator releases a claim it hasn't
+//! seen rewritten for the whole timeout, by its own clock, so a part held
+//! by a worker that died is handed out again. A worker finding its claim
+//! gone or taken gives up the part without reporting it.
+
+use crate::app::CancelToken;
+use crate::backend::{self, Backend};
+use crate::result::Result;
+use crate::state::Part;
+use crate::throttle::Throttle;
+use crate::upload::{self, Transfer};
+use futures::future::{self, Either};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::time::delay_for;
+
+/// How a job is coordinated: the shared directory, the manifest listing the
+/// part files in order, how often to look for reports, and how long a claim
+/// can go without being rewritten before it is released. `store` is passed
+/// on to the workers as `Job::store`.
+#[derive(Debug, Clone)]
+pub struct Coordination {
+    pub dir: PathBuf,
+    pub store: String,
+    pub manifest: PathBuf,
+    pub poll: Duration,
+    pub claim_timeout: Duration,
+}
+
+/// The upload the workers are contributing parts to.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Job {
+    /// "s3" or "file", telling the workers which store to use.
+    pub store: String,
+    pub bucket: String,
+    pub key: String,
+    pub upload_id: String,
+    /// Seconds a claim can go without being rewritten before it is
+    /// released.
+    pub claim_timeout: u64,
+}
+
+/// A part for a worker to upload, with its path on the host holding it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Assignment {
+    pub upload_id: String,
+    pub index: usize,
+    pub part_number: i64,
+    pub path: String,
+}
+
+/// The result of a worker's attempts at a part.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Report {
+    Done {
+        upload_id: String,
+        worker: String,
+        etag: String,
+        size: u64,
+        md5: String,
+    },
+    Failed {
+        upload_id: String,
+        worker: String,
+        msg: String,
+        code: Option<String>,
+    },
+}
+
+impl Report {
+    fn upload_id(&self) -> &str {
+        match self {
+            Report::Done { upload_id, .. } | Report::Failed { upload_id, .. } => upload_id,
+        }
+    }
+}
+
+/// The part files listed in a manifest, one path per line, numbered in
+/// order unless each line is `<number>\t<path>`.
+pub fn read_manifest(path: &Path) -> Result<Vec<Part>> {
+    upload::read_part_list(path).map_err(|err| format!("error reading manifest: {}", err).into())
+}
+
+fn part_file(dir: &Path, number: i64, extension: &str) -> PathBuf {
+    dir.join(format!("part-{:05}.{}", number, extension))
+}
+
+fn finished_file(dir: &Path) -> PathBuf {
+    dir.join("finished")
+}
+
+/// Write a file whole, so readers never see part of it.
+async fn write_atomic<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.tmp", std::process::id()));
+    let data = serde_json::to_vec(value)?;
+    fs::write(&temp, data)
+        .await
+        .map_err(|err| format!("error writing {:?}: {}", temp, err))?;
+    fs::rename(&temp, path)
+        .await
+        .map_err(|err| format!("error renaming {:?}: {}", temp, err))?;
+    Ok(())
+}
+
+/// Read a json file, or `None` if there isn't one.
+async fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path).await {
+        Ok(data) => Ok(Some(
+            serde_json::from_slice(&data).map_err(|err| format!("error reading {:?}: {}", path, err))?,
+        )),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("error reading {:?}: {}", path, err).into()),
+    }
+}
+
+async fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(ref err) if err.kind() != ErrorKind::NotFound => {
+            Err(format!("error removing {:?}: {}", path, err).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Describe the upload and assign each part without an etag, replacing
+/// assignments left from another upload.
+pub async fn assign(dir: &Path, job: &Job, parts: &[Part]) -> Result<()> {
+    fs::create_dir_all(dir)
+        .await
+        .map_err(|err| format!("error creating work directory {:?}: {}", dir, err))?;
+    remove(&finished_file(dir)).await?;
+
+    if read_json::<Job>(&dir.join("job.json")).await?.as_ref() != Some(job) {
+        write_atomic(&dir.join("job.json"), job).await?;
+    }
+
+    for (index, part) in parts.iter().enumerate() {
+        if !part.etag.is_empty() {
+            continue;
+        }
+        let path = part_file(dir, part.number, "json");
+        let assignment = Assignment {
+            upload_id: job.upload_id.to_owned(),
+            index,
+            part_number: part.number,
+            path: part.path.to_owned(),
+        };
+        if read_json::<Assignment>(&path).await?.as_ref() == Some(&assignment) {
+            continue;
+        }
+        for extension in &["claim", "done", "failed"] {
+            remove(&part_file(dir, part.number, extension)).await?;
+        }
+        write_atomic(&path, &assignment).await?;
+    }
+    Ok(())
+}
+
+/// The first report for this upload of a part without an etag. A failed
+/// part's claim and report are removed, so it is handed out again.
+pub async fn collect(dir: &Path, upload_id: &str, parts: &[Part]) -> Result<Option<(usize, Report)>> {
+    for (index, part) in parts.iter().enumerate() {
+        if !part.etag.is_empty() {
+            continue;
+        }
+        for extension in &["done", "failed"] {
+            let path = part_file(dir, part.number, extension);
+            let report = match read_json::<Report>(&path).await? {
+                Some(report) if report.upload_id() == upload_id => report,
+                _ => continue,
+            };
+            if let Report::Failed { .. } = report {
+                remove(&path).await?;
+                remove(&part_file(dir, part.number, "claim")).await?;
+            }
+            return Ok(Some((index, report)));
+        }
+    }
+    Ok(None)
+}
+
+/// When the coordinator last saw each claim change, to release the claims
+/// of workers that have stopped rewriting them.
+#[derive(Default)]
+pub struct Claims {
+    seen: HashMap<i64, (SystemTime, Instant)>,
+}
+
+impl Claims {
+    /// Remove the claims on parts without an etag that haven't been
+    /// rewritten for `timeout`, so the parts are handed out again.
+    pub async fn release_stale(&mut self, dir: &Path, parts: &[Part], timeout: Duration) -> Result<()> {
+        for part in parts.iter().filter(|part| part.etag.is_empty()) {
+            let path = part_file(dir, part.number, "claim");
+            let modified = match fs::metadata(&path).await.and_then(|metadata| metadata.modified()) {
+                Ok(modified) => modified,
+                Err(_) => {
+                    self.seen.remove(&part.number);
+                    continue;
+                }
+            };
+            match self.seen.get(&part.number) {
+                Some(&(seen, since)) if seen == modified => {
+                    if since.elapsed() >= timeout {
+                        let owner = fs::read_to_string(&path).await.unwrap_or_default();
+                        log::warn!("releasing part {}, claimed by {} which stopped renewing it", part.number, owner);
+                        remove(&path).await?;
+                        self.seen.remove(&part.number);
+                    }
+                }
+                _ => {
+                    self.seen.insert(part.number, (modified, Instant::now()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tell the workers the upload is over.
+pub async fn finish(dir: &Path) -> Result<()> {
+    fs::write(finished_file(dir), b"")
+        .await
+        .map_err(|err| format!("error finishing work directory {:?}: {}", dir, err).into())
+}
+
+/// Read the job a work directory is coordinating, waiting until the
+/// coordinator has written it.
+pub async fn wait_for_job(dir: &Path, poll: Duration) -> Result<Job> {
+    loop {
+        if let Some(job) = read_json(&dir.join("job.json")).await? {
+            return Ok(job);
+        }
+        delay_for(poll).await;
+    }
+}
+
+/// Settings for a worker.
+pub struct Worker {
+    pub dir: PathBuf,
+    pub name: String,
+    pub max_attempts: u32,
+    pub buffer_threshold: u64,
+    pub throttle: Arc<Throttle>,
+    pub poll: Duration,
+}
+
+impl Worker {
+    /// A name for a worker unique across hosts, from the host name and pid.
+    pub fn default_name() -> String {
+        crate::lease::owner_id()
+    }
+
+    /// Upload the assigned parts held on this host until the coordinator
+    /// finishes, returning how many this worker uploaded.
+    pub async fn run<B: Backend>(&self, backend: &B) -> Result<usize> {
+        let mut uploaded = 0;
+
+        while fs::metadata(finished_file(&self.dir)).await.is_err() {
+            match self.claim().await? {
+                Some((job, assignment)) => {
+                    let claim = part_file(&self.dir, assignment.part_number, "claim");
+                    let upload = self.upload(backend, &job, &assignment);
+                    let renew = self.renew(&claim, Duration::from_secs(job.claim_timeout));
+                    futures::pin_mut!(upload, renew);
+                    let report = match future::select(upload, renew).await {
+                        Either::Left((report, _)) => report,
+                        Either::Right(((), _)) => {
+                            log::warn!("lost the claim on part {}, leaving it", assignment.part_number);
+                            continue;
+                        }
+                    };
+                    if let Report::Done { .. } = report {
+                        uploaded += 1;
+                    }
+                    let extension = match report {
+                        Report::Done { .. } => "done",
+                        Report::Failed { .. } => "failed",
+                    };
+                    write_atomic(&part_file(&self.dir, assignment.part_number, extension), &report).await?;
+                }
+                None => delay_for(self.poll).await,
+            }
+        }
+
+        log::info!("coordinator finished, {} parts uploaded by {}", uploaded, self.name);
+        Ok(uploaded)
+    }
+
+    /// Claim an unclaimed assignment for the current upload whose part file
+    /// is on this host.
+    async fn claim(&self) -> Result<Option<(Job, Assignment)>> {
+        let job: Job = match read_json(&self.dir.join("job.json")).await? {
+            Some(job) => job,
+            None => return Ok(None),
+        };
+
+        let mut names = vec![];
+        let mut entries = fs::read_dir(&self.dir)
+            .await
+            .map_err(|err| format!("error reading work directory {:?}: {}", self.dir, err))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with("part-") && name.ends_with(".json") {
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        for name in names {
+            let assignment: Assignment = match read_json(&self.dir.join(&name)).await? {
+                Some(assignment) => assignment,
+                None => continue,
+            };
+            if assignment.upload_id != job.upload_id || fs::metadata(&assignment.path).await.is_err() {
+                continue;
+            }
+
+            let claim = part_file(&self.dir, assignment.part_number, "claim");
+            let created = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&claim)
+                .await;
+            match created {
+                Ok(mut file) => {
+                    // flushed, as a tokio file writes in the background
+                    let written = match file.write_all(self.name.as_bytes()).await {
+                        Ok(()) => file.flush().await,
+                        err => err,
+                    };
+                    written.map_err(|err| format!("error writing claim {:?}: {}", claim, err))?;
+                    log::info!("claimed part {} of upload {}", assignment.part_number, job.upload_id);
+                    return Ok(Some((job, assignment)));
+                }
+                Err(ref err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(format!("error claiming {:?}: {}", claim, err).into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Rewrite a claim at a third of the timeout, returning once it has
+    /// been released or claimed by another worker. Never returns if the job
+    /// has no timeout.
+    async fn renew(&self, claim: &Path, timeout: Duration) {
+        if timeout == Duration::from_secs(0) {
+            return future::pending().await;
+        }
+        loop {
+            delay_for(timeout / 3).await;
+            match fs::read_to_string(claim).await {
+                Ok(ref owner) if *owner == self.name => {}
+                _ => return,
+            }
+            if let Err(err) = fs::write(claim, self.name.as_bytes()).await {
+                log::warn!("error renewing claim {:?}: {}", claim, err);
+            }
+        }
+    }
+
+    /// Try uploading a part up to `max_attempts` times, waiting a poll
+    /// interval between attempts so a part briefly unreadable, e.g. over
+    /// NFS, isn't failed at once.
+    async fn upload<B: Backend>(&self, backend: &B, job: &Job, assignment: &Assignment) -> Report {
+        let path = PathBuf::from(&assignment.path);
+        let mut digest = None;
+        let mut failure = (String::new(), None);
+
+        for attempt in 0..self.max_attempts {
+            if attempt > 0 {
+                delay_for(self.poll).await;
+            }
+            log::info!("uploading part {} attempt {}", assignment.part_number, attempt);
+            let data = match upload::read_part(&path, digest.clone(), self.buffer_threshold).await {
+                Ok(data) => data,
+                Err(err) => {
+                    failure = (format!("read part error: {}", err), None);
+                    continue;
+                }
+            };
+            let (size, md5) = (data.len, data.md5.to_owned());
+            digest = Some((size, md5.to_owned()));
+
+            let transfer = Transfer {
+                throttle: self.throttle.clone(),
+                sent: Arc::new(Default::default()),
+                cancel: CancelToken::new(),
+            };
+            match upload::upload_part(
+                backend,
+                data,
+                &transfer,
+                &job.bucket,
+                &job.key,
+                &job.upload_id,
+                assignment.part_number,
+            )
+            .await
+            {
+                Ok(etag) => {
+                    return Report::Done {
+                        upload_id: job.upload_id.to_owned(),
+                        worker: self.name.to_owned(),
+                        etag,
+                        size,
+                        md5,
+                    }
+                }
+                Err(err) => failure = (format!("upload part error: {}", err), backend::error_code(&err)),
+            }
+        }
+
+        let (msg, code) = failure;
+        log::warn!("giving up on part {}: {}", assignment.part_number, msg);
+        Report::Failed {
+            upload_id: job.upload_id.to_owned(),
+            worker: self.name.to_owned(),
+            msg,
+            code,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::testing::scratch;
+
+    fn job() -> Job {
+        Job {
+            store: "file".to_owned(),
+            bucket: "bucket".to_owned(),
+            key: "key".to_owned(),
+            upload_id: "upload".to_owned(),
+            claim_timeout: 60,
+        }
+    }
+
+    fn worker(dir: &Path, name: &str) -> Worker {
+        Worker {
+            dir: dir.to_owned(),
+            name: name.to_owned(),
+            max_attempts: 3,
+            buffer_threshold: 1 << 20,
+            throttle: Arc::new(Throttle::unlimited()),
+            poll: Duration::from_millis(50),
+        }
+    }
+
+    #[test]
+    fn jobs_need_a_claim_timeout() {
+        let json = serde_json::to_value(job()).unwrap();
+        assert_eq!(serde_json::from_value::<Job>(json.clone()).unwrap(), job());
+        let mut old = json;
+        old.as_object_mut().unwrap().remove("claim_timeout");
+        assert!(serde_json::from_value::<Job>(old).is_err());
+    }
+
+    #[tokio::test]
+    async fn claims_each_part_once() {
+        let dir = scratch("coordinate-claim");
+        std::fs::write(dir.join("data"), b"part").unwrap();
+        let part = Part {
+            number: 1,
+            path: dir.join("data").to_str().unwrap().to_owned(),
+            etag: String::new(),
+            size: None,
+            md5: None,
+            segments: vec![],
+            archive: None,
+        };
+        assign(&dir, &job(), &[part]).await.unwrap();
+
+        let (_, assignment) = worker(&dir, "first").claim().await.unwrap().unwrap();
+        assert_eq!(assignment.part_number, 1);
+        assert_eq!(std::fs::read_to_string(part_file(&dir, 1, "claim")).unwrap(), "first");
+        assert!(worker(&dir, "second").claim().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn waits_between_attempts_at_an_unreadable_part() {
+        let dir = scratch("coordinate-unreadable");
+        let worker = worker(&dir, "worker");
+        let assignment = Assignment {
+            upload_id: "upload".to_owned(),
+            index: 0,
+            part_number: 1,
+            path: dir.join("missing").to_str().unwrap().to_owned(),
+        };
+
+        let started = Instant::now();
+        let report = worker.upload(&MemoryBackend::new(), &job(), &assignment).await;
+        assert!(started.elapsed() >= worker.poll * 2, "{:?}", started.elapsed());
+        match report {
+            Report::Failed { msg, .. } => assert!(msg.starts_with("read part error"), "{}", msg),
+            report => panic!("{:?}", report),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use crate::error::Error;
+use chrono::{SecondsFormat, Utc};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+
+/// The encodings supported by `--events`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            other => Err(format!("unknown event format {:?}", other).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    ActionStarted {
+        action: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        part_number: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        attempt: Option<u32>,
+    },
+    OperationApplied {
+        operation: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        part_number: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        etag: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error_code: Option<String>,
+    },
+    Retry {
+        stage: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        part_number: Option<i64>,
+        attempt: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error_code: Option<String>,
+        error: String,
+    },
+    StateTransition {
+        from: &'static str,
+        to: &'static str,
+    },
+    Summary {
+        state: &'static str,
+        bucket: String,
+        key: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        upload_id: Option<String>,
+        parts: usize,
+        bytes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        etag: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        version_id: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl Event {
+    pub fn new(kind: EventKind) -> Self {
+        Event {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            kind,
+        }
+    }
+}
+
+/// Writes one JSON event per line to stdout, a file or a unix socket, or
+/// sends events to a channel.
+pub struct EventSink {
+    target: Target,
+}
+
+enum Target {
+    Writer(Box<dyn Write + Send>),
+    Channel(UnboundedSender<Event>),
+}
+
+impl EventSink {
+    /// Open a destination: `-` for stdout, `unix:<path>` for a listening unix
+    /// socket, or a file path to append to.
+    pub fn open(dest: &str) -> std::result::Result<Self, Error> {
+        let writer: Box<dyn Write + Send> = if dest == "-" {
+            Box::new(io::stdout())
+        } else if let Some(path) = dest.strip_prefix("unix:") {
+            Box::new(
+                UnixStream::connect(path)
+                    .map_err(|err| format!("error connecting to event socket {}: {}", path, err))?,
+            )
+        } else {
+            Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(dest)
+                    .map_err(|err| format!("error opening event file {}: {}", dest, err))?,
+            )
+        };
+
+        Ok(EventSink {
+            target: Target::Writer(writer),
+        })
+    }
+
+    /// A sink sending events to the returned receiver.
+    pub fn channel() -> (Self, UnboundedReceiver<Event>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let sink = EventSink {
+            target: Target::Channel(sender),
+        };
+        (sink, receiver)
+    }
+
+    /// Write an event. Failures are logged rather than stopping the upload,
+    /// and events for a dropped receiver are discarded.
+    pub fn emit(&mut self, event: &Event) {
+        let writer = match self.target {
+            Target::Writer(ref mut writer) => writer,
+            Target::Channel(ref sender) => {
+                let _ = sender.unbounded_send(event.clone());
+                return;
+            }
+        };
+
+        let result = serde_json::to_string(event)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(writer, "{}", line))
+            .and_then(|()| writer.flush());
+
+        if let Err(err) = result {
+            log::warn!("error writing event: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+    use std::os::unix::net::UnixListener;
+
+    fn retry() -> Event {
+        Event::new(EventKind::Retry {
+            stage: "upload_part",
+            part_number: Some(2),
+            attempt: 1,
+            error_code: None,
+            error: "timed out".to_owned(),
+        })
+    }
+
+    fn json(line: &str) -> Value {
+        let mut value: Value = serde_json::from_str(line).unwrap();
+        let timestamp = value.as_object_mut().unwrap().remove("timestamp").unwrap();
+        assert!(timestamp.as_str().unwrap().ends_with('Z'), "{}", timestamp);
+        value
+    }
+
+    #[test]
+    fn parses_formats() {
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn serializes_events_flat_and_tagged() {
+        let line = serde_json::to_string(&retry()).unwrap();
+        assert_eq!(
+            json(&line),
+            json!({"event": "retry", "stage": "upload_part", "part_number": 2, "attempt": 1, "error": "timed out"})
+        );
+
+        let event = Event::new(EventKind::StateTransition { from: "starting", to: "uploading" });
+        let line = serde_json::to_string(&event).unwrap();
+        assert_eq!(json(&line), json!({"event": "state_transition", "from": "starting", "to": "uploading"}));
+    }
+
+    #[test]
+    fn appends_a_line_per_event_to_a_file() {
+        let path = scratch("events-file").join("events.ndjson");
+        std::fs::write(&path, "").unwrap();
+        let dest = path.to_str().unwrap();
+        EventSink::open(dest).unwrap().emit(&retry());
+        EventSink::open(dest).unwrap().emit(&retry());
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = text.lines().map(json).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["event"], "retry");
+    }
+
+    #[test]
+    fn writes_to_a_unix_socket() {
+        let path = scratch("events-socket").join("events.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut sink = EventSink::open(&format!("unix:{}", path.to_str().unwrap())).unwrap();
+        sink.emit(&retry());
+        drop(sink);
+
+        let mut text = String::new();
+        io::Read::read_to_string(&mut listener.accept().unwrap().0, &mut text).unwrap();
+        assert_eq!(json(text.trim_end())["stage"], "upload_part");
+    }
+
+    #[tokio::test]
+    async fn sends_events_to_a_channel() {
+        let (mut sink, receiver) = EventSink::channel();
+        sink.emit(&retry());
+        drop(sink);
+
+        let events: Vec<Event> = receiver.collect().await;
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0].kind, EventKind::Retry { attempt: 1, .. }));
+    }
+}
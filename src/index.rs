@@ -0,0 +1,295 @@
+//! An index of where each source file lies in an uploaded object, so one
+//! file can be fetched back later with a ranged read.
+
+use crate::backend::Backend;
+use crate::result::Result;
+use crate::state::Part;
+use crate::upload;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// The user metadata entry holding an index small enough to keep there.
+pub static METADATA_KEY: &str = "s3mu-index";
+
+/// S3 allows 2KB of user metadata in all, so larger indexes go in a sidecar
+/// object instead.
+pub static MAX_METADATA_LEN: usize = 1536;
+
+/// The length of a base64 md5.
+static MD5_LEN: usize = 24;
+
+/// The source files of an object, in the order they appear in it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Index {
+    pub files: Vec<IndexEntry>,
+}
+
+/// Where a source file lies in the object, and its base64 md5.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub path: String,
+    pub offset: u64,
+    pub len: u64,
+    pub md5: String,
+}
+
+impl Index {
+    /// The key of the object holding the index of an object at `key`, when
+    /// it doesn't fit in the object's metadata.
+    pub fn sidecar_key(key: &str) -> String {
+        format!("{}.s3mu-index", key)
+    }
+
+    /// Where the files making up these parts lie in the object, without
+    /// hashing them, so their md5s are empty.
+    pub fn layout(parts: &[Part]) -> Result<Self> {
+        let mut files: Vec<IndexEntry> = vec![];
+        let mut offset = 0;
+        for part in parts {
+            if part.archive.is_some() {
+                return Err("can't index the files in an archive".into());
+            }
+            if part.segments.is_empty() {
+                let len = match part.size {
+                    Some(size) => size,
+                    None => std::fs::metadata(&part.path)
+                        .map_err(|err| format!("error reading size of {}: {}", part.path, err))?
+                        .len(),
+                };
+                files.push(IndexEntry::new(&part.path, offset, len));
+                offset += len;
+                continue;
+            }
+            for segment in &part.segments {
+                match files.last_mut() {
+                    // the rest of a file packed into the part before
+                    Some(file) if file.path == segment.path && file.len == segment.offset && segment.offset > 0 => {
+                        file.len += segment.len
+                    }
+                    _ => files.push(IndexEntry::new(&segment.path, offset, segment.len)),
+                }
+                offset += segment.len;
+            }
+        }
+        Ok(Index { files })
+    }
+
+    /// The index of the object made from these parts, hashing each file.
+    /// Parts already hashed give the md5s of whole files, and are checked
+    /// against the files they were packed from.
+    pub async fn build(parts: &[Part]) -> Result<Self> {
+        let mut index = Index::layout(parts)?;
+        // one digest per entry, and how much of the last one is hashed, to
+        // tell as layout does when a segment carries on a file
+        let mut digests: Vec<md5::Context> = Vec::with_capacity(index.files.len());
+        let mut hashed = 0;
+        let mut buffer = vec![0; 64 * 1024];
+
+        for part in parts {
+            if part.segments.is_empty() {
+                index.files[digests.len()].md5 = match part.digest() {
+                    Some((_, md5)) => md5,
+                    None => upload::digest_file(Path::new(&part.path)).await?.1,
+                };
+                digests.push(md5::Context::new());
+                hashed = 0;
+                continue;
+            }
+
+            let mut part_digest = md5::Context::new();
+            for segment in &part.segments {
+                let carries_on = match digests.len() {
+                    0 => false,
+                    len => index.files[len - 1].path == segment.path && hashed == segment.offset && segment.offset > 0,
+                };
+                if !carries_on {
+                    digests.push(md5::Context::new());
+                    hashed = 0;
+                }
+                let digest = digests.last_mut().ok_or("no file to hash")?;
+
+                let mut f = upload::open_segment(segment).await?;
+                let mut read = 0;
+                loop {
+                    let count = f.read(&mut buffer[..]).await?;
+                    if count == 0 {
+                        break;
+                    }
+                    read += count as u64;
+                    digest.consume(&buffer[..count]);
+                    part_digest.consume(&buffer[..count]);
+                }
+                if read != segment.len {
+                    return Err(format!("{} changed since it was packed", segment.path).into());
+                }
+                hashed += read;
+            }
+            if let Some(ref md5) = part.md5 {
+                if base64::encode(part_digest.compute().0) != *md5 {
+                    return Err(format!("files packed into part {} changed since it was hashed", part.number).into());
+                }
+            }
+        }
+
+        for (entry, digest) in index.files.iter_mut().zip(digests) {
+            if entry.md5.is_empty() {
+                entry.md5 = base64::encode(digest.compute().0);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Whether the index, once its files are hashed, fits in user metadata.
+    pub fn fits_metadata(&self) -> bool {
+        let unhashed = self.files.iter().filter(|file| file.md5.is_empty()).count();
+        match serde_json::to_string(self) {
+            Ok(json) => json.is_ascii() && json.len() + unhashed * MD5_LEN <= MAX_METADATA_LEN,
+            Err(_) => false,
+        }
+    }
+
+    /// The user metadata holding the index, if it fits.
+    pub fn to_metadata(&self) -> Option<HashMap<String, String>> {
+        if !self.fits_metadata() {
+            return None;
+        }
+        let json = serde_json::to_string(self).ok()?;
+        Some(std::iter::once((METADATA_KEY.to_owned(), json)).collect())
+    }
+
+    /// The entry for a source file.
+    pub fn find(&self, path: &str) -> Option<&IndexEntry> {
+        self.files.iter().find(|file| file.path == path)
+    }
+
+    /// The index of the object at a key, from its metadata or else its
+    /// sidecar, or `None` if it has neither.
+    pub async fn load<B: Backend>(backend: &B, bucket: &str, key: &str) -> Result<Option<Self>> {
+        let object = backend
+            .head_object(bucket, key)
+            .await?
+            .ok_or_else(|| format!("s3://{}/{} doesn't exist", bucket, key))?;
+        if let Some(json) = object.metadata.get(METADATA_KEY) {
+            let index = serde_json::from_str(json).map_err(|err| format!("error reading index metadata: {}", err))?;
+            return Ok(Some(index));
+        }
+
+        match backend.get_object(bucket, &Index::sidecar_key(key)).await? {
+            Some(data) => {
+                let index =
+                    serde_json::from_slice(&data).map_err(|err| format!("error reading index sidecar: {}", err))?;
+                Ok(Some(index))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl IndexEntry {
+    fn new(path: &str, offset: u64, len: u64) -> Self {
+        IndexEntry {
+            path: path.to_owned(),
+            offset,
+            len,
+            md5: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Segment;
+    use crate::testing::scratch;
+
+    fn segment(path: &str, offset: u64, len: u64) -> Segment {
+        Segment {
+            path: path.to_owned(),
+            offset,
+            len,
+        }
+    }
+
+    fn spans(index: &Index) -> Vec<(&str, u64, u64)> {
+        index.files.iter().map(|file| (file.path.as_str(), file.offset, file.len)).collect()
+    }
+
+    fn md5(data: &[u8]) -> String {
+        base64::encode(md5::compute(data).0)
+    }
+
+    #[test]
+    fn lays_out_whole_file_parts() {
+        let parts: Vec<Part> = (1..=3)
+            .map(|number| Part {
+                size: Some(number as u64 * 10),
+                ..Part::new(number, format!("part{}", number))
+            })
+            .collect();
+        let index = Index::layout(&parts).unwrap();
+        assert_eq!(spans(&index), vec![("part1", 0, 10), ("part2", 10, 20), ("part3", 30, 30)]);
+        assert!(index.files.iter().all(|file| file.md5.is_empty()));
+    }
+
+    #[test]
+    fn lays_out_a_file_continued_across_parts() {
+        let parts = vec![
+            Part::packed(1, vec![segment("a", 0, 3), segment("b", 0, 2)]),
+            Part::packed(2, vec![segment("b", 2, 5)]),
+            Part::packed(3, vec![segment("b", 7, 1), segment("c", 0, 4)]),
+        ];
+        let index = Index::layout(&parts).unwrap();
+        assert_eq!(spans(&index), vec![("a", 0, 3), ("b", 3, 8), ("c", 11, 4)]);
+        assert_eq!(index.find("b").map(|file| file.offset), Some(3));
+    }
+
+    #[test]
+    fn lays_out_a_file_packed_twice_as_two_entries() {
+        let parts = vec![
+            Part::packed(1, vec![segment("a", 0, 3)]),
+            Part::packed(2, vec![segment("a", 0, 3)]),
+        ];
+        let index = Index::layout(&parts).unwrap();
+        assert_eq!(spans(&index), vec![("a", 0, 3), ("a", 3, 3)]);
+    }
+
+    #[tokio::test]
+    async fn builds_md5s_of_files_continued_across_parts() {
+        let dir = scratch("index-build");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        let (a, b, c) = (b"abc".to_vec(), b"0123456789".to_vec(), b"xyz".to_vec());
+        std::fs::write(path("a"), &a).unwrap();
+        std::fs::write(path("b"), &b).unwrap();
+        std::fs::write(path("c"), &c).unwrap();
+
+        let mut first = Part::packed(1, vec![segment(&path("a"), 0, 3), segment(&path("b"), 0, 4)]);
+        first.md5 = Some(md5(b"abc0123"));
+        let parts = vec![
+            first,
+            Part::packed(2, vec![segment(&path("b"), 4, 6)]),
+            Part {
+                size: Some(3),
+                md5: Some("cached".to_owned()),
+                ..Part::new(3, path("c"))
+            },
+        ];
+        let index = Index::build(&parts).await.unwrap();
+        assert_eq!(
+            spans(&index),
+            vec![(path("a").as_str(), 0, 3), (path("b").as_str(), 3, 10), (path("c").as_str(), 13, 3)]
+        );
+        let md5s: Vec<&str> = index.files.iter().map(|file| file.md5.as_str()).collect();
+        assert_eq!(md5s, vec![md5(&a).as_str(), md5(&b).as_str(), "cached"]);
+
+        // a packed part whose files changed since it was hashed
+        let mut changed = Part::packed(1, vec![segment(&path("a"), 0, 3)]);
+        changed.md5 = Some(md5(b"abd"));
+        assert!(Index::build(&[changed]).await.is_err());
+        // or whose file is shorter than when it was packed
+        assert!(Index::build(&[Part::packed(1, vec![segment(&path("a"), 0, 4)])]).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,558 @@
+use crate::app::{App, CancelToken, Mirror};
+use crate::archive::Archive;
+use crate::backend::{Backend, Completion};
+use crate::compress::Codec;
+use crate::coordinate::Coordination;
+use crate::events::{Event, EventSink};
+use crate::lease::Lease;
+use crate::metrics::Metrics;
+use crate::progress::Progress;
+use crate::result::Result;
+use crate::state::{Operation, Part, State, Verification};
+use crate::throttle::{Rate, Throttle};
+use crate::upload::{Order, DEFAULT_BUFFER_THRESHOLD};
+use crate::wal::{SqliteStore, Storage, Wal, WalEntry};
+use futures::channel::mpsc::UnboundedReceiver;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Where a job keeps its log.
+enum LogLocation {
+    File(PathBuf),
+    Sqlite(PathBuf),
+    Store(Box<dyn Storage<Operation>>),
+}
+
+/// Settings for a resumable multipart upload, from the files matching a
+/// pattern to one object.
+pub struct UploadJob<B: Backend> {
+    backend: B,
+    bucket: String,
+    key: String,
+    source: String,
+    order: Order,
+    pack: Option<u64>,
+    archive: Option<Archive>,
+    compress: Option<Codec>,
+    log: Option<LogLocation>,
+    max_attempts: u32,
+    buffer_threshold: u64,
+    throttle: Arc<Throttle>,
+    events: Vec<EventSink>,
+    metrics: Option<Arc<Metrics>>,
+    mirror_every: Option<usize>,
+    resume: bool,
+    lease_ttl: Option<Duration>,
+    coordination: Option<Coordination>,
+    existing: Option<(String, Vec<Part>)>,
+    handoff: bool,
+    skip_identical: bool,
+    no_overwrite: bool,
+    verify: bool,
+    index: bool,
+}
+
+impl<B: Backend + 'static> UploadJob<B> {
+    /// An upload to `key` in `bucket` of the files in the current directory.
+    pub fn new(backend: B, bucket: &str, key: &str) -> Self {
+        UploadJob {
+            backend,
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            source: "*".to_owned(),
+            order: Order::default(),
+            pack: None,
+            archive: None,
+            compress: None,
+            log: None,
+            max_attempts: 3,
+            buffer_threshold: DEFAULT_BUFFER_THRESHOLD,
+            throttle: Arc::new(Throttle::unlimited()),
+            events: vec![],
+            metrics: None,
+            mirror_every: None,
+            resume: false,
+            lease_ttl: None,
+            coordination: None,
+            existing: None,
+            handoff: false,
+            skip_identical: false,
+            no_overwrite: false,
+            verify: false,
+            index: false,
+        }
+    }
+
+    /// A glob matching the part files, uploaded in natural name order
+    /// unless another `order` is given.
+    pub fn source(mut self, pattern: &str) -> Self {
+        self.source = pattern.to_owned();
+        self
+    }
+
+    /// How to put the part files in order.
+    pub fn order(mut self, order: Order) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Stream the files, in order, into parts of `size` bytes instead of
+    /// uploading a part per file, so files smaller than S3's minimum part
+    /// size can still make an object. Which slices of which files make each
+    /// part is logged, so a resumed upload rebuilds the same parts.
+    pub fn pack(mut self, size: u64) -> Self {
+        self.pack = Some(size);
+        self
+    }
+
+    /// Upload a directory as a tar archive cut into parts of `part_size`
+    /// bytes as it is made, instead of the files matching `source`. Entries
+    /// are sorted and their headers normalised, so the same tree always
+    /// makes the same archive. See `archive::Archive`.
+    pub fn archive<P: AsRef<Path>>(mut self, dir: P, part_size: u64) -> Self {
+        self.archive = Some(Archive {
+            dir: dir.as_ref().to_owned(),
+            part_size,
+        });
+        self
+    }
+
+    /// Compress each part on its own as it is uploaded, so the object is a
+    /// run of gzip members or zstd frames that decompresses as one stream,
+    /// with its `Content-Encoding` and codec metadata set. The size and md5
+    /// each part was sent with are logged, since compressing a part again
+    /// needn't give the same bytes. See `compress::Codec`.
+    pub fn compress(mut self, codec: Codec) -> Self {
+        self.compress = Some(codec);
+        self
+    }
+
+    /// The write ahead log file recording the upload's progress. Starting a
+    /// job with the log of an earlier one resumes it. This, `state_db` or
+    /// `storage` is required.
+    pub fn log<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.log = Some(LogLocation::File(path.as_ref().to_owned()));
+        self
+    }
+
+    /// Keep the log in a SQLite database shared with other jobs, as the job
+    /// for this bucket and key.
+    pub fn state_db<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.log = Some(LogLocation::Sqlite(path.as_ref().to_owned()));
+        self
+    }
+
+    /// Keep the log in some other store.
+    pub fn storage(mut self, storage: Box<dyn Storage<Operation>>) -> Self {
+        self.log = Some(LogLocation::Store(storage));
+        self
+    }
+
+    /// How many times each step is tried before giving up.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Parts up to this many bytes are read into memory and uploaded in a
+    /// single pass.
+    pub fn buffer_threshold(mut self, buffer_threshold: u64) -> Self {
+        self.buffer_threshold = buffer_threshold;
+        self
+    }
+
+    /// Limit the bandwidth of this job's part uploads.
+    pub fn max_bandwidth(self, rate: Rate) -> Self {
+        self.throttle(Arc::new(Throttle::new(rate)))
+    }
+
+    /// Share a throttle, e.g. one with a schedule, or one limiting several
+    /// jobs together.
+    pub fn throttle(mut self, throttle: Arc<Throttle>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Also send events to a sink, such as one opened with `EventSink::open`.
+    pub fn events(mut self, sink: EventSink) -> Self {
+        self.events.push(sink);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Copy the log to an object beside the upload, `<key>.s3mu-wal`, after
+    /// every `every` operations and whenever the upload starts or finishes.
+    pub fn mirror_log(mut self, every: usize) -> Self {
+        self.mirror_every = Some(every.max(1));
+        self
+    }
+
+    /// Hold a lease on the upload while it runs, so a process on another
+    /// host won't drive it at the same time. See `Lease`.
+    pub fn lease(mut self, ttl: Duration) -> Self {
+        self.lease_ttl = Some(ttl);
+        self
+    }
+
+    /// Have workers on the hosts holding the parts upload them, taking the
+    /// parts from a manifest instead of the source pattern. See `coordinate`.
+    pub fn coordinate(mut self, coordination: Coordination) -> Self {
+        self.coordination = Some(coordination);
+        self
+    }
+
+    /// Upload these parts to an upload created elsewhere, e.g. by `s3mu
+    /// presign`, instead of creating one from the source pattern.
+    pub fn existing_upload(mut self, upload_id: &str, parts: Vec<Part>) -> Self {
+        self.existing = Some((upload_id.to_owned(), parts));
+        self
+    }
+
+    /// Stop once every part is uploaded, or a part runs out of attempts,
+    /// leaving completing or aborting the upload to another process.
+    pub fn handoff(mut self) -> Self {
+        self.handoff = true;
+        self
+    }
+
+    /// Before starting, check whether the object at the key is already the
+    /// one the upload would create, from its size and etag, and if so finish
+    /// without uploading.
+    pub fn skip_identical(mut self) -> Self {
+        self.skip_identical = true;
+        self
+    }
+
+    /// Don't replace an object already at the key: check before starting
+    /// and complete only if there still isn't one, and if there is, abort
+    /// the upload and end with `Outcome::Refused`. Files are completed in
+    /// one step that fails if the object exists; S3 is checked just before
+    /// completing, so a writer in between can still be replaced.
+    pub fn no_overwrite(mut self) -> Self {
+        self.no_overwrite = true;
+        self
+    }
+
+    /// Once completed, read the object back part by part and check each part
+    /// against the md5 it was uploaded with, ending with
+    /// `Outcome::VerificationFailed` if one differs. The store must keep
+    /// part boundaries, as S3 does.
+    pub fn verify(mut self) -> Self {
+        self.verify = true;
+        self
+    }
+
+    /// Once completed, put an index of where each source file lies in the
+    /// object, with its md5, in the object's metadata if it is small enough,
+    /// or else in a sidecar object, `<key>.s3mu-index`. See `index::Index`.
+    pub fn index(mut self) -> Self {
+        self.index = true;
+        self
+    }
+
+    /// When the local log is empty, fill it from the mirrored log so the
+    /// upload continues where another host left off.
+    pub fn resume_from_mirror(mut self) -> Self {
+        self.resume = true;
+        self
+    }
+
+    /// Open the log and recover the upload's state, ready to run.
+    pub async fn start(self) -> Result<UploadHandle<B>> {
+        if self.skip_identical && self.coordination.is_some() {
+            return Err("skipping identical objects needs the parts here to hash them".into());
+        }
+
+        let mut log: Box<dyn Storage<Operation>> = match self.log {
+            Some(LogLocation::File(path)) => Box::new(Wal::open(&path).await?),
+            Some(LogLocation::Sqlite(path)) => {
+                Box::new(SqliteStore::open(&path, &self.bucket, &self.key)?)
+            }
+            Some(LogLocation::Store(storage)) => storage,
+            None => return Err("an upload job needs a log to record its progress".into()),
+        };
+
+        let backend = Arc::new(self.backend);
+        let cancel = CancelToken::new();
+        let lease = match self.lease_ttl {
+            Some(ttl) => {
+                Some(Lease::acquire(backend.clone(), &self.bucket, &self.key, ttl, cancel.clone()).await?)
+            }
+            None => None,
+        };
+
+        if let Some((upload_id, parts)) = self.existing {
+            if log.entries().is_empty() {
+                let mut state = State::new();
+                for op in [Operation::ConfiguredParts(parts), Operation::Started { upload_id }] {
+                    state = state.apply(op.clone())?;
+                    log.append(WalEntry::new(op), state.name()).await?;
+                }
+            }
+        }
+
+        if self.resume {
+            if log.entries().is_empty() {
+                UploadJob::fetch_mirror(&backend, &self.bucket, &self.key, log.as_mut()).await?;
+            } else {
+                log::info!("local log has entries, resuming from it instead of the mirror");
+            }
+        }
+
+        let mut app = App::new(
+            backend,
+            &self.bucket,
+            &self.key,
+            self.max_attempts,
+            log,
+            &self.source,
+            self.buffer_threshold,
+        )
+        .await?;
+        app.order = self.order;
+        app.pack = self.pack;
+        app.archive = self.archive;
+        app.compress = self.compress;
+        app.throttle = self.throttle;
+        app.events = self.events;
+        app.metrics = self.metrics;
+        app.mirror = self.mirror_every.map(|every| Mirror::new(&app.key, every));
+        app.cancel = cancel;
+        app.coordination = self.coordination;
+        app.handoff = self.handoff;
+        app.skip_identical = self.skip_identical;
+        app.no_overwrite = self.no_overwrite;
+        app.verify = self.verify;
+        app.index = self.index;
+
+        Ok(UploadHandle { app, lease })
+    }
+
+    /// Copy the mirrored log into an empty local log.
+    async fn fetch_mirror(
+        backend: &B,
+        bucket: &str,
+        key: &str,
+        log: &mut dyn Storage<Operation>,
+    ) -> Result<()> {
+        let sidecar = Mirror::sidecar_key(key);
+        let data = backend
+            .get_object(bucket, &sidecar)
+            .await?
+            .ok_or_else(|| format!("no mirrored log found at {}", sidecar))?;
+        let text = String::from_utf8(data).map_err(|err| format!("mirrored log is not utf8: {}", err))?;
+
+        let mut state = State::new();
+        for (number, line) in (1..).zip(text.lines()) {
+            let entry: WalEntry<Operation> = serde_json::from_str(line)
+                .map_err(|err| format!("error reading line {} of mirrored log: {}", number, err))?;
+            state = state.apply(entry.action.to_owned())?;
+            log.append(entry, state.name()).await?;
+        }
+
+        log::info!("resuming from mirrored log {} in the {} state", sidecar, state.name());
+        Ok(())
+    }
+}
+
+/// How a run of an upload job ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Completed(Completion),
+    Aborted,
+    /// Stopped, and any upload aborted, because an object was already at the
+    /// key and the job mustn't overwrite it.
+    Refused,
+    /// Completed, but reading the object back found a part that differs.
+    VerificationFailed(String),
+    /// Stopped by the cancel token. Starting the job again resumes it.
+    Cancelled,
+    /// Stopped after running out of attempts, in this state.
+    Stopped(&'static str),
+}
+
+/// An upload job ready to run.
+pub struct UploadHandle<B: Backend> {
+    app: App<Arc<B>>,
+    lease: Option<Lease<B>>,
+}
+
+impl<B: Backend + 'static> UploadHandle<B> {
+    /// A token that stops the run cleanly, between requests.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.app.cancel.clone()
+    }
+
+    /// Receive the job's events. Subscribe before running to see them all.
+    pub fn subscribe(&mut self) -> UnboundedReceiver<Event> {
+        let (sink, receiver) = EventSink::channel();
+        self.app.events.push(sink);
+        receiver
+    }
+
+    pub fn progress(&self) -> Arc<Progress> {
+        self.app.progress.clone()
+    }
+
+    /// The state recovered from the log, or reached by running.
+    pub fn state(&self) -> &State {
+        &self.app.state
+    }
+
+    /// Run the upload until it completes, aborts, is cancelled or runs out
+    /// of attempts.
+    pub async fn run(&mut self) -> Result<Outcome> {
+        let result = self.app.run().await;
+
+        if let Some(lease) = self.lease.take() {
+            if let Err(err) = lease.release().await {
+                log::warn!("error releasing lease: {}", err);
+            }
+        }
+        result?;
+
+        Ok(match self.app.state {
+            State::Completed {
+                verification: Some(Verification::Failed(ref reason)),
+                ..
+            } => Outcome::VerificationFailed(reason.to_owned()),
+            State::Completed {
+                verification: None, ..
+            } if self.app.verify => {
+                if self.app.cancel.is_cancelled() {
+                    Outcome::Cancelled
+                } else {
+                    Outcome::Stopped("verifying")
+                }
+            }
+            State::Completed { indexed: false, .. } if self.app.index => {
+                if self.app.cancel.is_cancelled() {
+                    Outcome::Cancelled
+                } else {
+                    Outcome::Stopped("indexing")
+                }
+            }
+            State::Completed { ref completion, .. } => Outcome::Completed(completion.to_owned()),
+            State::Aborted => Outcome::Aborted,
+            State::Refused => Outcome::Refused,
+            _ if self.app.cancel.is_cancelled() => Outcome::Cancelled,
+            ref state => Outcome::Stopped(state.name()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use crate::testing::scratch;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::time::delay_for;
+
+    /// When the lease on an upload to "key" expires, in milliseconds since
+    /// the unix epoch.
+    async fn lease_expires(backend: &MemoryBackend) -> u64 {
+        let data = backend.get_object("bucket", &Lease::<MemoryBackend>::lease_key("key")).await.unwrap().unwrap();
+        let record: serde_json::Value = serde_json::from_slice(&data).unwrap();
+        record["expires"].as_u64().unwrap()
+    }
+
+    /// An upload of the `part-*` files in `dir`, logged to `log` there.
+    fn upload(backend: &Arc<MemoryBackend>, dir: &Path, log: &str) -> UploadJob<Arc<MemoryBackend>> {
+        UploadJob::new(backend.clone(), "bucket", "key")
+            .source(dir.join("part-*").to_str().unwrap())
+            .log(dir.join(log))
+    }
+
+    fn small_parts() -> Arc<MemoryBackend> {
+        let mut backend = MemoryBackend::new();
+        backend.min_part_size = 4;
+        Arc::new(backend)
+    }
+
+    #[tokio::test]
+    async fn mirrors_the_log_beside_the_object() {
+        let dir = scratch("job-mirror");
+        std::fs::write(dir.join("part-1"), b"first").unwrap();
+        std::fs::write(dir.join("part-2"), b"second").unwrap();
+        let backend = small_parts();
+
+        // mirrored at the end too, so not every 100 operations only
+        let mut handle = upload(&backend, &dir, "log").mirror_log(100).start().await.unwrap();
+        assert!(matches!(handle.run().await.unwrap(), Outcome::Completed(_)));
+
+        let mirrored = backend.get_object("bucket", &Mirror::sidecar_key("key")).await.unwrap().unwrap();
+        assert_eq!(mirrored, std::fs::read(dir.join("log")).unwrap());
+    }
+
+    #[tokio::test]
+    async fn resumes_from_the_mirrored_log() {
+        let dir = scratch("job-resume");
+        std::fs::write(dir.join("part-1"), b"first").unwrap();
+        std::fs::write(dir.join("part-2"), b"second").unwrap();
+        let backend = small_parts();
+
+        // another host's upload, stopped before completing
+        let mut handle = upload(&backend, &dir, "log").mirror_log(1).handoff().start().await.unwrap();
+        assert_eq!(handle.run().await.unwrap(), Outcome::Stopped("completing"));
+        let upload_id = handle.state().upload_id().unwrap().to_owned();
+        drop(handle);
+
+        let mut handle = upload(&backend, &dir, "resumed").resume_from_mirror().start().await.unwrap();
+        assert_eq!(handle.state().name(), "completing");
+        assert_eq!(handle.state().upload_id(), Some(upload_id.as_str()));
+        assert!(matches!(handle.run().await.unwrap(), Outcome::Completed(_)));
+        assert_eq!(backend.get_object("bucket", "key").await.unwrap().unwrap(), b"firstsecond");
+    }
+
+    #[tokio::test]
+    async fn resumes_from_a_local_log_over_the_mirror() {
+        let dir = scratch("job-resume-local");
+        std::fs::write(dir.join("part-1"), b"first").unwrap();
+        let backend = small_parts();
+        assert!(upload(&backend, &dir, "log").resume_from_mirror().start().await.is_err(), "no mirror");
+
+        let mut handle = upload(&backend, &dir, "log").handoff().start().await.unwrap();
+        handle.run().await.unwrap();
+        drop(handle);
+        backend.put_object("bucket", &Mirror::sidecar_key("key"), b"not a log".to_vec()).await.unwrap();
+
+        let handle = upload(&backend, &dir, "log").resume_from_mirror().start().await.unwrap();
+        assert_eq!(handle.state().name(), "completing");
+    }
+
+    #[tokio::test]
+    async fn dropping_a_started_upload_lets_its_lease_expire() {
+        let dir = scratch("job-lease");
+        std::fs::write(dir.join("part-1"), b"part").unwrap();
+        let backend = Arc::new(MemoryBackend::new());
+        // renewals are read back after a couple of seconds, so the lease
+        // must outlast that
+        let ttl = Duration::from_secs(3);
+        let handle = UploadJob::new(backend.clone(), "bucket", "key")
+            .source(dir.join("part-*").to_str().unwrap())
+            .log(dir.join("log"))
+            .lease(ttl)
+            .start()
+            .await
+            .unwrap();
+
+        let claimed = lease_expires(&backend).await;
+        delay_for(ttl / 2).await;
+        assert!(lease_expires(&backend).await > claimed, "renewed while held");
+
+        drop(handle);
+        let dropped = lease_expires(&backend).await;
+        // past when the next renewal would have been written
+        delay_for(ttl).await;
+        assert_eq!(lease_expires(&backend).await, dropped);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        assert!(dropped <= now);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
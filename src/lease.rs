@@ -0,0 +1,294 @@
+use crate::app::CancelToken;
+use crate::backend::{self, Backend};
+use crate::result::Result;
+use futures::future::{self, AbortHandle, Aborted};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tokio::time::delay_for;
+
+/// How long to wait after writing a claim before reading it back.
+static SETTLE: Duration = Duration::from_secs(2);
+
+/// How often the heartbeat checks whether it has been stopped.
+static TICK: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    owner: String,
+    /// Milliseconds since the unix epoch.
+    expires: u64,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A name for this process, unique across hosts.
+pub(crate) fn owner_id() -> String {
+    let host = std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|host| host.trim().to_owned())
+        .filter(|host| !host.is_empty())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "unknown".to_owned());
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    format!("{}:{}:{:x}", host, std::process::id(), nanos)
+}
+
+/// A claim on an upload stored as an object beside it, `<key>.s3mu-lease`,
+/// naming the process driving the upload and when its claim expires. The
+/// claim is renewed while the upload runs, and another process won't start
+/// until it expires.
+///
+/// Where there is no lease, it is claimed with a write that fails if one has
+/// appeared, on stores that can make writes conditional. Otherwise, and to
+/// take over an expired lease, a claim is written and read back after a
+/// pause: of two processes claiming at once, the one whose write landed last
+/// keeps the lease and the other gives up. Each renewal is read back the
+/// same way, and losing the lease interrupts the upload straight away.
+/// Expiry is judged by each host's clock, so clocks must roughly agree.
+///
+/// Dropping a lease without releasing it stops the renewals, leaving the
+/// claim to expire.
+pub struct Lease<B: Backend> {
+    backend: Arc<B>,
+    bucket: String,
+    key: String,
+    owner: String,
+    stop: Arc<AtomicBool>,
+    heartbeat: Option<JoinHandle<std::result::Result<(), Aborted>>>,
+    /// Stops the heartbeat at once, where `stop` waits for it to finish a
+    /// renewal.
+    abort: AbortHandle,
+}
+
+impl<B: Backend + 'static> Lease<B> {
+    /// The key of the object holding the lease of an upload to `key`.
+    pub fn lease_key(key: &str) -> String {
+        format!("{}.s3mu-lease", key)
+    }
+
+    /// Claim the upload to `key` for `ttl`, and keep renewing the claim
+    /// until released. If another process takes the lease over, or it can't
+    /// be renewed before it expires, `cancel` interrupts the upload.
+    pub async fn acquire(
+        backend: Arc<B>,
+        bucket: &str,
+        key: &str,
+        ttl: Duration,
+        cancel: CancelToken,
+    ) -> Result<Self> {
+        let key = Lease::<B>::lease_key(key);
+        let owner = owner_id();
+
+        let current = read(backend.as_ref(), bucket, &key).await?;
+        if let Some(ref current) = current {
+            let now = now_millis();
+            if current.expires > now {
+                return Err(format!(
+                    "upload is leased to {} for another {}s",
+                    current.owner,
+                    (current.expires - now).div_ceil(1000)
+                )
+                .into());
+            }
+            log::info!("taking over expired lease from {}", current.owner);
+        }
+
+        let claimed = match current {
+            None => write_if_absent(backend.as_ref(), bucket, &key, &owner, ttl).await?,
+            Some(_) => None,
+        };
+        let expires = match claimed {
+            Some(expires) => expires,
+            None => {
+                let expires = write(backend.as_ref(), bucket, &key, &owner, ttl).await?;
+                match settled_owner(backend.as_ref(), bucket, &key).await? {
+                    Some(ref current) if *current == owner => {}
+                    Some(current) => {
+                        return Err(format!("lost the lease to {}, which claimed it at the same time", current).into())
+                    }
+                    None => return Err(format!("lease {} was deleted while claiming it", key).into()),
+                }
+                expires
+            }
+        };
+        log::info!("acquired lease {} as {}", key, owner);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (heartbeat, abort) = future::abortable(heartbeat(
+            backend.clone(),
+            bucket.to_owned(),
+            key.to_owned(),
+            owner.to_owned(),
+            ttl,
+            expires,
+            stop.clone(),
+            cancel,
+        ));
+
+        Ok(Lease {
+            backend,
+            bucket: bucket.to_owned(),
+            key,
+            owner,
+            stop,
+            heartbeat: Some(tokio::spawn(heartbeat)),
+            abort,
+        })
+    }
+
+    /// Stop renewing the lease and delete it, if it is still ours.
+    pub async fn release(mut self) -> Result<()> {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.await;
+        }
+
+        match read(self.backend.as_ref(), &self.bucket, &self.key).await? {
+            Some(ref current) if current.owner == self.owner => {
+                self.backend.delete_object(&self.bucket, &self.key).await
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<B: Backend> Drop for Lease<B> {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+async fn read<B: Backend>(backend: &B, bucket: &str, key: &str) -> Result<Option<Record>> {
+    match backend.get_object(bucket, key).await? {
+        Some(data) => Ok(Some(
+            serde_json::from_slice(&data).map_err(|err| format!("error reading lease {}: {}", key, err))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Write a claim expiring `ttl` from now, returning when it expires.
+async fn write<B: Backend>(backend: &B, bucket: &str, key: &str, owner: &str, ttl: Duration) -> Result<u64> {
+    let record = Record {
+        owner: owner.to_owned(),
+        expires: now_millis() + ttl.as_millis() as u64,
+    };
+    backend
+        .put_object(bucket, key, serde_json::to_vec(&record)?)
+        .await?;
+    Ok(record.expires)
+}
+
+/// Write a claim only if there is no lease, returning when it expires, or
+/// `None` if the store can't make the write conditional.
+async fn write_if_absent<B: Backend>(
+    backend: &B,
+    bucket: &str,
+    key: &str,
+    owner: &str,
+    ttl: Duration,
+) -> Result<Option<u64>> {
+    let record = Record {
+        owner: owner.to_owned(),
+        expires: now_millis() + ttl.as_millis() as u64,
+    };
+    match backend
+        .put_object_if_absent(bucket, key, serde_json::to_vec(&record)?)
+        .await
+    {
+        Ok(()) => Ok(Some(record.expires)),
+        Err(ref err) if backend::error_code(err).as_deref() == Some("NotImplemented") => Ok(None),
+        Err(ref err) if backend::error_code(err).as_deref() == Some("PreconditionFailed") => {
+            let current = read(backend, bucket, key).await?.map(|current| current.owner);
+            Err(format!(
+                "lost the lease to {}, which claimed it at the same time",
+                current.as_deref().unwrap_or("another process")
+            )
+            .into())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The owner of the lease once a write has had time to settle.
+async fn settled_owner<B: Backend>(backend: &B, bucket: &str, key: &str) -> Result<Option<String>> {
+    delay_for(SETTLE).await;
+    Ok(read(backend, bucket, key).await?.map(|current| current.owner))
+}
+
+/// Renew the lease every third of its ttl until stopped.
+#[allow(clippy::too_many_arguments)]
+async fn heartbeat<B: Backend>(
+    backend: Arc<B>,
+    bucket: String,
+    key: String,
+    owner: String,
+    ttl: Duration,
+    mut expires: u64,
+    stop: Arc<AtomicBool>,
+    cancel: CancelToken,
+) {
+    let mut renewed = Instant::now();
+
+    while !stop.load(Ordering::SeqCst) {
+        delay_for(TICK).await;
+        if renewed.elapsed() < ttl / 3 {
+            continue;
+        }
+        renewed = Instant::now();
+
+        let ours = match read(backend.as_ref(), &bucket, &key).await {
+            Ok(Some(current)) if current.owner != owner => {
+                log::error!("lease {} was taken over by {}, stopping", key, current.owner);
+                cancel.interrupt();
+                return;
+            }
+            Ok(_) => true,
+            Err(err) => {
+                log::warn!("error reading lease {}: {}", key, err);
+                false
+            }
+        };
+        // a process taking the lease over between the read and the write
+        // would have its claim replaced, so check whose write landed last
+        let written = if ours {
+            match write(backend.as_ref(), &bucket, &key, &owner, ttl).await {
+                Ok(renewed_until) => Some(renewed_until),
+                Err(err) => {
+                    log::warn!("error renewing lease {}: {}", key, err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(renewed_until) = written {
+            match settled_owner(backend.as_ref(), &bucket, &key).await {
+                Ok(Some(ref current)) if *current != owner => {
+                    log::error!("lease {} was taken over by {} while renewing it, stopping", key, current);
+                    cancel.interrupt();
+                    return;
+                }
+                Ok(_) => expires = renewed_until,
+                Err(err) => log::warn!("error reading lease {}: {}", key, err),
+            }
+        }
+
+        if now_millis() >= expires {
+            log::error!("lease {} expired before it could be renewed, stopping", key);
+            cancel.interrupt();
+            return;
+        }
+    }
+}
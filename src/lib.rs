@@ -0,0 +1,68 @@
+//! Resumable S3 multipart uploads, where each part is a local file.
+//!
+//! Progress is recorded in a write ahead log, so an upload interrupted by a
+//! crash or cancellation resumes where it stopped when started again with
+//! the same log.
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use rusoto_core::Region;
+//! use rusoto_s3::S3Client;
+//! use s3mu::backend::S3Backend;
+//! use s3mu::{Outcome, UploadJob};
+//!
+//! # async fn example() -> s3mu::result::Result<()> {
+//! let backend = S3Backend::new(S3Client::new(Region::default()));
+//! let mut handle = UploadJob::new(backend, "bucket", "backups/db.tar")
+//!     .source("/var/backups/db.tar.part-*")
+//!     .log("/var/backups/db.tar.s3mu-log")
+//!     .max_attempts(5)
+//!     .start()
+//!     .await?;
+//!
+//! // stop cleanly on shutdown; running the job again resumes it
+//! let cancel = handle.cancel_token();
+//! tokio::spawn(async move {
+//!     tokio::signal::ctrl_c().await.ok();
+//!     cancel.cancel();
+//! });
+//!
+//! let mut events = handle.subscribe();
+//! tokio::spawn(async move {
+//!     while let Some(event) = events.next().await {
+//!         println!("{:?}", event.kind);
+//!     }
+//! });
+//!
+//! match handle.run().await? {
+//!     Outcome::Completed(_) => println!("uploaded"),
+//!     outcome => println!("stopped: {:?}", outcome),
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod actions;
+pub mod app;
+pub mod archive;
+pub mod backend;
+pub mod check;
+pub mod compress;
+pub mod coordinate;
+pub mod error;
+pub mod events;
+pub mod index;
+pub mod job;
+pub mod lease;
+pub mod metrics;
+pub mod progress;
+pub mod result;
+pub mod state;
+#[cfg(test)]
+mod testing;
+pub mod throttle;
+pub mod upload;
+pub mod wal;
+
+pub use app::CancelToken;
+pub use job::{Outcome, UploadHandle, UploadJob};
@@ -1,82 +1,1100 @@
-use clap::Clap;
+use clap::{AppSettings, Clap};
+use futures::StreamExt;
 
+use rusoto_core::credential::{DefaultCredentialsProvider, ProvideAwsCredentials};
 use rusoto_core::Region;
 use rusoto_s3::S3Client;
 
-use std::path::PathBuf;
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub mod actions;
-pub mod app;
-pub mod error;
-pub mod result;
-pub mod state;
-pub mod upload;
-pub mod wal;
+use s3mu::app::Mirror;
+use s3mu::archive::Archive;
+use s3mu::backend::presigned::{Plan, MAX_EXPIRY};
+use s3mu::backend::{Backend, Completion, FsBackend, PresignedBackend, S3Backend};
+use s3mu::check;
+use s3mu::compress::Codec;
+use s3mu::coordinate::{self, Coordination, Worker};
+use s3mu::error::Error;
+use s3mu::events::{self, EventSink};
+use s3mu::index::Index;
+use s3mu::metrics::Metrics;
+use s3mu::result::Result;
+use s3mu::state::State;
+use s3mu::upload::{self, Order};
+use s3mu::wal::sqlite;
+use s3mu::throttle::{Rate, Throttle};
+use s3mu::{Outcome, UploadJob};
 
-use error::Error;
-use result::Result;
+/// Exit status when --no-overwrite finds an object already at the key.
+static EXIT_REFUSED: i32 = 3;
 
-use app::App;
+/// Exit status when check finds the object differs from the local parts.
+static EXIT_DIFFERENT: i32 = 4;
 
+/// Exit status when an upload gave up and aborted.
+static EXIT_ABORTED: i32 = 5;
+
+/// Exit status when an upload stopped unfinished, and running it again with
+/// the same log resumes it.
+static EXIT_STOPPED: i32 = 6;
+
+/// An upload that stopped rather than overwrite an object.
+#[derive(Debug)]
+struct Refused(String);
+
+impl std::error::Error for Refused {}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} already exists, not overwriting it", self.0)
+    }
+}
+
+/// An object that doesn't match the local parts it was checked against.
+#[derive(Debug)]
+struct Different(String);
+
+impl std::error::Error for Different {}
+
+impl fmt::Display for Different {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An upload that ended without an object, with the status to exit with.
+#[derive(Debug)]
+struct Unfinished(String, i32);
+
+impl std::error::Error for Unfinished {}
+
+impl fmt::Display for Unfinished {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Without a subcommand, uploads files matching a pattern as the parts of
+/// one object, as upload does.
 #[derive(Clap)]
-struct Opts {
+#[clap(setting = AppSettings::ArgsNegateSubcommands)]
+struct Args {
+    #[clap(flatten)]
+    upload: Opts,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+// parsed once, so the size of the upload options doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Clap)]
+enum Command {
+    /// Upload files matching a pattern as the parts of one object
+    Upload(Opts),
+    /// Continue an upload from the log mirrored beside it by --mirror-log,
+    /// e.g. on another host
+    Resume(Opts),
+    /// Start an upload whose parts are on other hosts, and complete it once
+    /// workers sharing the work directory have uploaded them all
+    Coordinate(CoordinateOpts),
+    /// Upload the parts a coordinator assigns that are on this host
+    Work(WorkOpts),
+    /// Upload a directory tree as a tar archive made as it is uploaded, with
+    /// sorted entries and normalised metadata so the same tree always makes
+    /// the same object
+    Archive(ArchiveOpts),
+    /// Create an upload and write a plan with a presigned url for each part,
+    /// for a host without credentials to push
+    Presign(PresignOpts),
+    /// Upload the parts of a presigned plan, without credentials
+    Push(PushOpts),
+    /// Complete an upload from the log of a push
+    Finish(FinishOpts),
+    /// Compare files matching a pattern with the object at a key, as if
+    /// they were its parts, without uploading. Exits with status 4 if they
+    /// differ
+    Check(CheckOpts),
+    /// Fetch one source file back out of an object uploaded with --index,
+    /// with a ranged read, checking its md5
+    Extract(ExtractOpts),
+    /// List the jobs in a state database
+    Jobs(JobsOpts),
+}
+
+#[derive(Clap)]
+struct ExtractOpts {
+    #[clap(short, long)]
+    bucket: Option<String>,
+
+    #[clap(short, long)]
+    key: Option<String>,
+
+    /// Object as a url instead of --bucket and --key: s3://bucket/key
+    #[clap(short, long, conflicts_with_all = &["bucket", "key"])]
+    dest: Option<String>,
+
+    /// The source file, by its path in the index
+    #[clap(short, long)]
+    file: String,
+
+    /// Index file to look the source file up in, instead of the object's
+    /// metadata or its <key>.s3mu-index sidecar
+    #[clap(long)]
+    index: Option<PathBuf>,
+
+    /// Where to write the file, instead of stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    #[clap(short, long)]
+    region: Option<String>,
+
+    #[clap(short, long)]
+    endpoint: Option<String>,
+}
+
+#[derive(Clap)]
+struct CheckOpts {
     #[clap(short, long)]
-    bucket: String,
+    bucket: Option<String>,
 
     #[clap(short, long)]
-    key: String,
+    key: Option<String>,
+
+    /// Object as a url instead of --bucket and --key: s3://bucket/key
+    #[clap(short, long, conflicts_with_all = &["bucket", "key"])]
+    dest: Option<String>,
 
+    /// Pattern matching the part files, ordered as upload orders them
     #[clap(short, long, default_value = "*")]
     pattern: String,
 
+    /// How to order the part files, as for upload
+    #[clap(long, default_value = "natural")]
+    order: Order,
+
+    /// Pack the files into parts of this many bytes, as for upload
+    #[clap(long)]
+    pack: Option<u64>,
+
     #[clap(short, long)]
     region: Option<String>,
 
     #[clap(short, long)]
     endpoint: Option<String>,
+}
+
+#[derive(Clap)]
+struct JobsOpts {
+    #[clap(long)]
+    state_db: PathBuf,
+
+    /// Include completed jobs
+    #[clap(long)]
+    all: bool,
+}
+
+#[derive(Clap)]
+struct CoordinateOpts {
+    #[clap(flatten)]
+    upload: Opts,
+
+    /// Directory shared with the workers, one per upload
+    #[clap(long)]
+    work_dir: PathBuf,
+
+    /// File listing the part files, one path per line in part order, as
+    /// seen by the hosts holding them
+    #[clap(long)]
+    manifest: PathBuf,
+
+    /// Seconds between checks for reports from workers
+    #[clap(long, default_value = "5")]
+    poll_interval: u64,
+
+    /// Seconds a worker can go without renewing its claim on a part before
+    /// the part is handed out again
+    #[clap(long, default_value = "60")]
+    claim_timeout: u64,
+}
+
+#[derive(Clap)]
+struct ArchiveOpts {
+    #[clap(flatten)]
+    upload: Opts,
+
+    /// Directory to archive, instead of the files matching --pattern
+    #[clap(long)]
+    dir: PathBuf,
+
+    /// Bytes of the archive in each part but the last
+    #[clap(long, default_value = "16777216")]
+    part_size: u64,
+}
+
+/// Where an upload's parts come from.
+enum Source {
+    /// The files matching the pattern, uploaded from here.
+    Files,
+    /// The files in a coordinator's manifest, uploaded by workers.
+    Workers(Coordination),
+    /// An archive of a directory, made as it is uploaded.
+    Archive(Archive),
+}
+
+#[derive(Clap)]
+struct WorkOpts {
+    /// Directory shared with the coordinator
+    #[clap(long)]
+    work_dir: PathBuf,
+
+    /// Name reported with each part, by default the host name and pid
+    #[clap(long)]
+    name: Option<String>,
 
+    #[clap(short, long)]
+    region: Option<String>,
+
+    #[clap(short, long)]
+    endpoint: Option<String>,
+
+    #[clap(short, long, default_value = "3")]
+    tries: u32,
+
+    /// Parts up to this many bytes are read into memory and uploaded in a single pass
+    #[clap(long, default_value = "67108864")]
+    buffer_threshold: u64,
+
+    /// Limit on this worker's upload bandwidth, e.g. 50MB/s
+    #[clap(long, default_value = "unlimited")]
+    max_bandwidth: Rate,
+
+    /// Seconds between checks for assignments
+    #[clap(long, default_value = "5")]
+    poll_interval: u64,
+}
+
+#[derive(Clap)]
+struct PresignOpts {
+    #[clap(short, long)]
+    bucket: Option<String>,
+
+    #[clap(short, long)]
+    key: Option<String>,
+
+    /// Destination as a url instead of --bucket and --key: s3://bucket/key
+    #[clap(short, long, conflicts_with_all = &["bucket", "key"])]
+    dest: Option<String>,
+
+    /// Pattern matching the part files, if they are on this host
+    #[clap(short, long, default_value = "*")]
+    pattern: String,
+
+    /// How to order the part files, as for upload
+    #[clap(long, default_value = "natural")]
+    order: Order,
+
+    /// File listing the part files instead, one path per line in part order,
+    /// as seen by the host pushing them
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+
+    #[clap(short, long)]
+    region: Option<String>,
+
+    #[clap(short, long)]
+    endpoint: Option<String>,
+
+    /// Seconds until the urls expire, at most a week
+    #[clap(long, default_value = "604800")]
+    expires: u64,
+
+    /// Where to write the plan, instead of stdout
+    #[clap(long)]
+    plan: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+struct PushOpts {
+    #[clap(long)]
+    plan: PathBuf,
+
+    /// The push's log file, recording the etags for s3mu finish
     #[clap(short, long)]
     log: PathBuf,
 
     #[clap(short, long, default_value = "3")]
     tries: u32,
+
+    /// Parts up to this many bytes are read into memory and uploaded in a single pass
+    #[clap(long, default_value = "67108864")]
+    buffer_threshold: u64,
+
+    /// Limit on upload bandwidth across all parts, e.g. 50MB/s
+    #[clap(long, default_value = "unlimited")]
+    max_bandwidth: Rate,
+
+    /// Don't report progress on stderr
+    #[clap(long)]
+    no_progress: bool,
+
+    /// Seconds between progress lines when stderr is not a terminal
+    #[clap(long, default_value = "30")]
+    progress_interval: u64,
+}
+
+#[derive(Clap)]
+struct FinishOpts {
+    #[clap(long)]
+    plan: PathBuf,
+
+    /// The log of the push, copied from the host that ran it
+    #[clap(short, long)]
+    log: PathBuf,
+
+    #[clap(short, long)]
+    region: Option<String>,
+
+    #[clap(short, long)]
+    endpoint: Option<String>,
+
+    #[clap(short, long, default_value = "3")]
+    tries: u32,
+}
+
+#[derive(Clap)]
+struct Opts {
+    #[clap(short, long)]
+    bucket: Option<String>,
+
+    #[clap(short, long)]
+    key: Option<String>,
+
+    /// Destination as a url instead of --bucket and --key: s3://bucket/key or file:///path
+    #[clap(short, long, conflicts_with_all = &["bucket", "key"])]
+    dest: Option<String>,
+
+    #[clap(short, long, default_value = "*")]
+    pattern: String,
+
+    /// How to order the part files: natural, comparing numbers in names by
+    /// value; mtime, oldest first; regex:<regex>, by the part number it
+    /// captures from each file name; or list:<file>, as listed one path per
+    /// line, instead of the pattern
+    #[clap(long, default_value = "natural")]
+    order: Order,
+
+    /// Stream the files, in order, into parts of this many bytes, the last
+    /// one smaller, instead of uploading each file as a part. For files
+    /// below S3's 5MiB minimum part size
+    #[clap(long)]
+    pack: Option<u64>,
+
+    #[clap(short, long)]
+    region: Option<String>,
+
+    #[clap(short, long)]
+    endpoint: Option<String>,
+
+    /// The upload's log file. Required unless --state-db is given; resume
+    /// defaults it to <key>.s3mu-wal in the current directory
+    #[clap(short, long)]
+    log: Option<PathBuf>,
+
+    /// Keep the log in a SQLite database shared by many jobs instead of a file
+    #[clap(long, conflicts_with = "log")]
+    state_db: Option<PathBuf>,
+
+    /// Attempts at each request before giving up. An upload that gives up
+    /// exits with status 5 once aborted, or 6 if left to resume
+    #[clap(short, long, default_value = "3")]
+    tries: u32,
+
+    /// Parts up to this many bytes are read into memory and uploaded in a single pass
+    #[clap(long, default_value = "67108864")]
+    buffer_threshold: u64,
+
+    /// Limit on upload bandwidth across all parts, e.g. 50MB/s
+    #[clap(long, default_value = "unlimited")]
+    max_bandwidth: Rate,
+
+    /// File of time of day bandwidth limits, re-read while the upload runs
+    #[clap(long)]
+    bandwidth_schedule: Option<PathBuf>,
+
+    /// Don't report progress on stderr
+    #[clap(long)]
+    no_progress: bool,
+
+    /// Seconds between progress lines when stderr is not a terminal
+    #[clap(long, default_value = "30")]
+    progress_interval: u64,
+
+    /// Emit a structured event stream, one event per line
+    #[clap(long, possible_values = &["json"])]
+    events: Option<events::Format>,
+
+    /// Where to write events: - for stdout, unix:<path> for a unix socket, or a file
+    #[clap(long, default_value = "-")]
+    events_to: String,
+
+    /// Prometheus textfile to keep updated with upload metrics, e.g. for node_exporter
+    #[clap(long)]
+    metrics_file: Option<PathBuf>,
+
+    /// Seconds between metrics textfile updates
+    #[clap(long, default_value = "15")]
+    metrics_interval: u64,
+
+    /// Mirror the log to <key>.s3mu-wal beside the object, for resume
+    #[clap(long)]
+    mirror_log: bool,
+
+    /// Operations between copies of the mirrored log
+    #[clap(long, default_value = "16")]
+    mirror_every: usize,
+
+    /// Hold a lease on the upload beside the object, <key>.s3mu-lease, so
+    /// another host can't drive it at the same time
+    #[clap(long)]
+    lease: bool,
+
+    /// Seconds a lease lasts unless renewed
+    #[clap(long, default_value = "300")]
+    lease_ttl: u64,
+
+    /// Don't upload if the object at the key already has the size and etag
+    /// the upload would give it
+    #[clap(long)]
+    skip_identical: bool,
+
+    /// Abort instead of replacing an object already at the key, checking
+    /// before starting and before completing. Best effort on S3, where an
+    /// object written between the check and completing is replaced. Exits
+    /// with status 3
+    #[clap(long)]
+    no_overwrite: bool,
+
+    /// Once completed, read the object back: full fetches each part and
+    /// checks its md5. Not supported for file destinations
+    #[clap(long, possible_values = &["full"])]
+    verify: Option<Verify>,
+
+    /// Once completed, record where each file lies in the object, and its
+    /// md5, in the object's metadata if small enough, or else in a
+    /// <key>.s3mu-index sidecar, for s3mu extract
+    #[clap(long)]
+    index: bool,
+
+    /// Compress each part as it is uploaded, making the object one gzip or
+    /// zstd stream, e.g. zstd or zstd:19. Part sizes are before compression,
+    /// so parts but the last must still compress to at least 5MiB, and very
+    /// compressible inputs need larger parts, e.g. with --pack or
+    /// --part-size. A part that compresses too small stops the upload
+    #[clap(long)]
+    compress: Option<Codec>,
+
+    /// List the parts that would be uploaded, their numbers and what they are
+    /// made of, without reading them or uploading anything
+    #[clap(long)]
+    dry_run: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
-    let opts: Opts = Opts::parse();
+    let args: Args = Args::parse();
+
+    let result = match args.command {
+        None => upload(&args.upload, false, None, None).await,
+        Some(Command::Upload(ref opts)) => upload(opts, false, None, None).await,
+        Some(Command::Resume(ref opts)) => upload(opts, true, None, None).await,
+        Some(Command::Coordinate(ref opts)) => upload(&opts.upload, false, Some(opts), None).await,
+        Some(Command::Archive(ref opts)) => upload(&opts.upload, false, None, Some(opts)).await,
+        Some(Command::Work(ref opts)) => work(opts).await,
+        Some(Command::Presign(ref opts)) => presign(opts).await,
+        Some(Command::Push(ref opts)) => push(opts).await,
+        Some(Command::Finish(ref opts)) => finish(opts).await,
+        Some(Command::Check(ref opts)) => check(opts).await,
+        Some(Command::Extract(ref opts)) => extract(opts).await,
+        Some(Command::Jobs(ref opts)) => jobs(opts),
+    };
+
+    if let Err(ref err) = result {
+        if err.is::<Refused>() {
+            eprintln!("{}", err);
+            std::process::exit(EXIT_REFUSED);
+        }
+        if err.is::<Different>() {
+            eprintln!("{}", err);
+            std::process::exit(EXIT_DIFFERENT);
+        }
+        if let Some(Unfinished(_, status)) = err.downcast_ref::<Unfinished>() {
+            eprintln!("{}", err);
+            std::process::exit(*status);
+        }
+    }
+    result
+}
+
+fn jobs(opts: &JobsOpts) -> Result<()> {
+    for job in sqlite::jobs(&opts.state_db, opts.all)? {
+        println!("{}\t{}\t{}\t{}\t{}", job.id, job.state, job.updated, job.bucket, job.key);
+    }
+    Ok(())
+}
+
+async fn check(opts: &CheckOpts) -> Result<()> {
+    let (store, bucket, key) = destination(&opts.bucket, &opts.key, &opts.dest)?;
+    if let Store::File = store {
+        return Err("check needs an S3 object, files don't keep their parts".into());
+    }
+
+    let parts = check::hash_parts(&opts.pattern, &opts.order, opts.pack).await?;
+    if parts.is_empty() {
+        return Err(format!("no files match {}", opts.pattern).into());
+    }
+
+    let region = region(&opts.region, &opts.endpoint).map_err(|err| format!("get region error: {}", err))?;
+    let backend = S3Backend::new(S3Client::new(region));
+    let comparison = check::compare(&backend, &bucket, &key, &parts).await?;
+
+    let object = match comparison.object {
+        Some(ref object) => object,
+        None => return Err(Box::new(Different(format!("s3://{}/{} doesn't exist", bucket, key)))),
+    };
+
+    let etag = |etag: &Option<String>| etag.to_owned().unwrap_or_else(|| "-".to_owned());
+    println!("local\t{}\t{}\t{}", parts.len(), comparison.size, etag(&comparison.etag));
+    let count = comparison.parts_count.map(|count| count.to_string()).unwrap_or_else(|| "-".to_owned());
+    println!("remote\t{}\t{}\t{}", count, object.size, etag(&object.etag));
+    let size = |size: Option<u64>| size.map(|size| size.to_string()).unwrap_or_else(|| "missing".to_owned());
+    for difference in comparison.differences.iter() {
+        println!(
+            "part {}\tlocal {}\tremote {}",
+            difference.number,
+            size(difference.local),
+            size(difference.remote)
+        );
+    }
+
+    if comparison.identical() {
+        println!("s3://{}/{} matches the local parts", bucket, key);
+        Ok(())
+    } else {
+        Err(Box::new(Different(format!("s3://{}/{} differs from the local parts", bucket, key))))
+    }
+}
+
+async fn extract(opts: &ExtractOpts) -> Result<()> {
+    let (store, bucket, key) = destination(&opts.bucket, &opts.key, &opts.dest)?;
+    if let Store::File = store {
+        return Err("extract needs an S3 object".into());
+    }
+
+    let region = region(&opts.region, &opts.endpoint).map_err(|err| format!("get region error: {}", err))?;
+    let backend = S3Backend::new(S3Client::new(region));
+
+    let index = match opts.index {
+        Some(ref path) => {
+            let data = std::fs::read(path).map_err(|err| format!("error reading index {:?}: {}", path, err))?;
+            serde_json::from_slice(&data).map_err(|err| format!("error reading index {:?}: {}", path, err))?
+        }
+        None => Index::load(&backend, &bucket, &key)
+            .await?
+            .ok_or_else(|| format!("s3://{}/{} has no index", bucket, key))?,
+    };
+    let entry = index
+        .find(&opts.file)
+        .ok_or_else(|| format!("{} isn't in the index of s3://{}/{}", opts.file, bucket, key))?;
+
+    let mut out: Box<dyn Write> = match opts.output {
+        Some(ref path) => Box::new(
+            std::fs::File::create(path).map_err(|err| format!("error creating {:?}: {}", path, err))?,
+        ),
+        None => Box::new(std::io::stdout()),
+    };
+
+    let mut digest = md5::Context::new();
+    let mut len = 0;
+    if entry.len > 0 {
+        let mut stream = backend
+            .get_range(&bucket, &key, entry.offset, entry.len)
+            .await?
+            .ok_or_else(|| format!("s3://{}/{} doesn't exist", bucket, key))?;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            digest.consume(&chunk);
+            out.write_all(&chunk)?;
+            len += chunk.len() as u64;
+        }
+    }
+    out.flush()?;
+
+    let md5 = base64::encode(digest.compute().0);
+    if len != entry.len || md5 != entry.md5 {
+        return Err(format!(
+            "{} read back as {} bytes with md5 {}, but was indexed as {} bytes with md5 {}",
+            opts.file, len, md5, entry.len, entry.md5
+        )
+        .into());
+    }
+    Ok(())
+}
+
+async fn work(opts: &WorkOpts) -> Result<()> {
+    let poll = Duration::from_secs(opts.poll_interval);
+    let job = coordinate::wait_for_job(&opts.work_dir, poll).await?;
+
+    let worker = Worker {
+        dir: opts.work_dir.to_owned(),
+        name: opts.name.to_owned().unwrap_or_else(Worker::default_name),
+        max_attempts: opts.tries,
+        buffer_threshold: opts.buffer_threshold,
+        throttle: Arc::new(Throttle::new(opts.max_bandwidth)),
+        poll,
+    };
+
+    let uploaded = if job.store == "file" {
+        worker.run(&FsBackend::new()).await?
+    } else {
+        let region = region(&opts.region, &opts.endpoint).map_err(|err| format!("get region error: {}", err))?;
+        worker.run(&S3Backend::new(S3Client::new(region))).await?
+    };
+
+    println!("uploaded {} parts", uploaded);
+    Ok(())
+}
+
+async fn presign(opts: &PresignOpts) -> Result<()> {
+    let (store, bucket, key) = destination(&opts.bucket, &opts.key, &opts.dest)?;
+    if let Store::File = store {
+        return Err("presigned urls need an S3 destination".into());
+    }
+
+    let expires_in = Duration::from_secs(opts.expires);
+    if expires_in > MAX_EXPIRY {
+        return Err(format!("presigned urls expire after at most {} seconds", MAX_EXPIRY.as_secs()).into());
+    }
+
+    let parts = match opts.manifest {
+        Some(ref manifest) => coordinate::read_manifest(manifest)?,
+        None => upload::get_parts(&opts.pattern, &opts.order)?,
+    };
+    if parts.is_empty() {
+        return Err("no parts to upload".into());
+    }
+
+    let region = region(&opts.region, &opts.endpoint).map_err(|err| format!("get region error: {}", err))?;
+    let credentials = DefaultCredentialsProvider::new()?.credentials().await?;
+    let backend = S3Backend::new(S3Client::new(region.clone()));
+
+    let upload_id = backend.create_upload(&bucket, &key).await?;
+    let plan = Plan::new(&region, &credentials, &bucket, &key, &upload_id, &parts, expires_in);
+    let json = serde_json::to_string_pretty(&plan)?;
+
+    match opts.plan {
+        Some(ref path) => std::fs::write(path, json).map_err(|err| format!("error writing plan {:?}: {}", path, err))?,
+        None => println!("{}", json),
+    }
+    log::info!("created upload {} of {} parts, expiring {}", upload_id, parts.len(), plan.expires);
+    Ok(())
+}
+
+async fn push(opts: &PushOpts) -> Result<()> {
+    let plan = Plan::load(&opts.plan)?;
+
+    let mut handle = UploadJob::new(PresignedBackend::new(&plan), &plan.bucket, &plan.key)
+        .log(&opts.log)
+        .existing_upload(&plan.upload_id, plan.parts())
+        .handoff()
+        .max_attempts(opts.tries)
+        .buffer_threshold(opts.buffer_threshold)
+        .max_bandwidth(opts.max_bandwidth)
+        .start()
+        .await?;
+
+    let reporter = if opts.no_progress {
+        None
+    } else {
+        let interval = Duration::from_secs(opts.progress_interval);
+        Some(tokio::spawn(handle.progress().report(interval)))
+    };
+
+    let result = handle.run().await;
+    if let Some(reporter) = reporter {
+        let _ = reporter.await;
+    }
+
+    match result? {
+        Outcome::Stopped("completing") => {
+            println!("all parts uploaded, complete the upload with s3mu finish and this log");
+            Ok(())
+        }
+        Outcome::Stopped(state) => {
+            Err(format!("push gave up in the {} state, push again with a new log to retry", state).into())
+        }
+        outcome => Err(format!("push stopped: {:?}", outcome).into()),
+    }
+}
+
+async fn finish(opts: &FinishOpts) -> Result<()> {
+    let plan = Plan::load(&opts.plan)?;
+    let region = region(&opts.region, &opts.endpoint).map_err(|err| format!("get region error: {}", err))?;
+
+    let mut handle = UploadJob::new(S3Backend::new(S3Client::new(region)), &plan.bucket, &plan.key)
+        .log(&opts.log)
+        .max_attempts(opts.tries)
+        .start()
+        .await?;
+
+    match handle.state() {
+        State::Completing { ref upload_id, .. } if *upload_id == plan.upload_id => {}
+        State::Completing { ref upload_id, .. } => {
+            return Err(format!("the log is of upload {}, not the plan's {}", upload_id, plan.upload_id).into())
+        }
+        State::Completed { .. } => {}
+        state => {
+            return Err(format!("the push hasn't uploaded every part, its log is in the {} state", state.name()).into())
+        }
+    }
+
+    match handle.run().await? {
+        Outcome::Completed(ref completion) => {
+            print_completion(&mut std::io::stdout(), &plan.bucket, &plan.key, completion)
+        }
+        outcome => Err(format!("finish stopped: {:?}", outcome).into()),
+    }
+}
+
+async fn upload(
+    opts: &Opts,
+    resume: bool,
+    coordinate: Option<&CoordinateOpts>,
+    archive: Option<&ArchiveOpts>,
+) -> Result<()> {
+    let (store, bucket, key) = opts.destination()?;
+
+    if resume && opts.dry_run {
+        return Err("a dry run has no mirrored log to resume from".into());
+    }
+
+    let source = match (coordinate, archive) {
+        (Some(coordinate), _) => Source::Workers(Coordination {
+            dir: coordinate.work_dir.to_owned(),
+            store: match store {
+                Store::S3 => "s3",
+                Store::File => "file",
+            }
+            .to_owned(),
+            manifest: coordinate.manifest.to_owned(),
+            poll: Duration::from_secs(coordinate.poll_interval),
+            claim_timeout: Duration::from_secs(coordinate.claim_timeout),
+        }),
+        (None, Some(archive)) => Source::Archive(Archive {
+            dir: archive.dir.to_owned(),
+            part_size: archive.part_size,
+        }),
+        (None, None) => Source::Files,
+    };
+
+    match (&source, opts.pack, opts.index) {
+        (Source::Workers(_), Some(_), _) => return Err("workers upload whole files, so can't pack them".into()),
+        (Source::Archive(_), Some(_), _) => return Err("archives are cut into parts by --part-size, not --pack".into()),
+        (Source::Archive(_), _, true) => return Err("can't index the files in an archive".into()),
+        _ => {}
+    }
+    match (&source, opts.compress, opts.index) {
+        (Source::Workers(_), Some(_), _) => return Err("workers upload whole files, so can't compress them".into()),
+        (_, Some(_), true) => return Err("can't index the files in a compressed object".into()),
+        _ => {}
+    }
+
+    if opts.dry_run {
+        // show the parts that would be uploaded, without reading them: the
+        // files in order, the slice of each file in packed parts, or where
+        // archive parts start
+        let parts = match source {
+            Source::Workers(ref coordination) => coordinate::read_manifest(&coordination.manifest)?,
+            Source::Archive(ref archive) => archive.parts()?,
+            Source::Files => {
+                let parts = upload::get_parts(&opts.pattern, &opts.order)?;
+                match opts.pack {
+                    Some(size) => upload::pack(&parts, size)?,
+                    None => parts,
+                }
+            }
+        };
+        let mut out = opts.output();
+        for part in parts {
+            if let Some(slice) = part.archive {
+                let name = slice.entries.first().map(|entry| entry.name.as_str()).unwrap_or("");
+                writeln!(out, "part {}\t{}\t{}\t{}", part.number, slice.first, name, slice.offset)?;
+            } else if part.segments.is_empty() {
+                writeln!(out, "part {}\t{}", part.number, part.path)?;
+            }
+            for segment in part.segments {
+                writeln!(out, "part {}\t{}\t{}\t{}", part.number, segment.path, segment.offset, segment.len)?;
+            }
+        }
+        return Ok(());
+    }
+
+    if let (Store::File, Some(_)) = (&store, opts.verify) {
+        return Err("--verify needs a store that keeps the parts of an object, which files don't".into());
+    }
+
+    if let Store::File = store {
+        return run(FsBackend::new(), &bucket, &key, resume, source, opts).await;
+    }
 
     let region = opts
         .region()
         .map_err(|err| format!("get region error: {}", err))?;
     let s3client = S3Client::new(region);
 
-    let mut app = App::new(s3client, &opts.bucket, &opts.key, opts.tries, &opts.log, &opts.pattern).await?;
+    run(S3Backend::new(s3client), &bucket, &key, resume, source, opts).await
+}
+
+/// Run an upload, logging where the options say. A resumed upload starts
+/// from the mirrored log and keeps mirroring it.
+async fn run<B: Backend + 'static>(
+    backend: B,
+    bucket: &str,
+    key: &str,
+    resume: bool,
+    source: Source,
+    opts: &Opts,
+) -> Result<()> {
+    let throttle = match opts.bandwidth_schedule {
+        Some(ref schedule) => Throttle::with_schedule(opts.max_bandwidth, schedule)?,
+        None => Throttle::new(opts.max_bandwidth),
+    };
+
+    let mut job = UploadJob::new(backend, bucket, key)
+        .source(&opts.pattern)
+        .order(opts.order.to_owned())
+        .max_attempts(opts.tries)
+        .buffer_threshold(opts.buffer_threshold)
+        .throttle(Arc::new(throttle));
+
+    job = match (&opts.state_db, &opts.log) {
+        (Some(state_db), _) => job.state_db(state_db),
+        (None, Some(log)) => job.log(log),
+        (None, None) if resume => {
+            let name = Path::new(key).file_name().and_then(|name| name.to_str()).unwrap_or(key);
+            job.log(Mirror::sidecar_key(name))
+        }
+        (None, None) => return Err("--log or --state-db is required".into()),
+    };
 
-    app.run().await?;
+    if resume {
+        job = job.resume_from_mirror();
+    }
+    if resume || opts.mirror_log {
+        job = job.mirror_log(opts.mirror_every);
+    }
+
+    if let Some(size) = opts.pack {
+        job = job.pack(size);
+    }
+    if let Some(codec) = opts.compress {
+        job = job.compress(codec);
+    }
+
+    match source {
+        Source::Files => {}
+        Source::Workers(coordination) => job = job.coordinate(coordination),
+        Source::Archive(archive) => job = job.archive(archive.dir, archive.part_size),
+    }
+
+    if opts.skip_identical {
+        job = job.skip_identical();
+    }
+    if opts.no_overwrite {
+        job = job.no_overwrite();
+    }
+    if let Some(Verify::Full) = opts.verify {
+        job = job.verify();
+    }
+    if opts.index {
+        job = job.index();
+    }
+
+    if opts.lease {
+        job = job.lease(Duration::from_secs(opts.lease_ttl));
+    }
 
+    if opts.events.is_some() {
+        job = job.events(EventSink::open(&opts.events_to)?);
+    }
+
+    let metrics = opts
+        .metrics_file
+        .as_ref()
+        .map(|path| (Arc::new(Metrics::new(bucket, key)), path.to_owned()));
+    if let Some((ref metrics, _)) = metrics {
+        job = job.metrics(metrics.clone());
+    }
+
+    let mut handle = job.start().await?;
+
+    let exporter = metrics.map(|(metrics, path)| {
+        let interval = Duration::from_secs(opts.metrics_interval);
+        tokio::spawn(metrics.export(path, interval))
+    });
+
+    let reporter = if opts.no_progress {
+        None
+    } else {
+        let interval = Duration::from_secs(opts.progress_interval);
+        Some(tokio::spawn(handle.progress().report(interval)))
+    };
+
+    let result = handle.run().await;
+
+    if let Some(reporter) = reporter {
+        let _ = reporter.await;
+    }
+    if let Some(exporter) = exporter {
+        let _ = exporter.await;
+    }
+
+    match result? {
+        Outcome::Completed(ref completion) => print_completion(&mut opts.output(), bucket, key, completion),
+        Outcome::Refused => Err(Box::new(Refused(key.to_owned()))),
+        Outcome::VerificationFailed(reason) => {
+            Err(format!("{} was uploaded but failed verification: {}", key, reason).into())
+        }
+        Outcome::Aborted => Err(Box::new(Unfinished(
+            format!("gave up uploading {} and aborted the upload", key),
+            EXIT_ABORTED,
+        ))),
+        Outcome::Stopped(state) => Err(Box::new(Unfinished(
+            format!("gave up uploading {} in the {} state, run again with the same log to resume", key, state),
+            EXIT_STOPPED,
+        ))),
+        Outcome::Cancelled => Err(Box::new(Unfinished(
+            format!("stopped uploading {}, run again with the same log to resume", key),
+            EXIT_STOPPED,
+        ))),
+    }
+}
+
+/// Print the object an upload created, tab separated like `jobs`, leaving
+/// out what the store didn't say.
+fn print_completion(out: &mut dyn Write, bucket: &str, key: &str, completion: &Completion) -> Result<()> {
+    let mut fields = vec![bucket.to_owned(), key.to_owned()];
+    fields.extend(completion.size.map(|size| size.to_string()));
+    fields.extend(completion.etag.to_owned());
+    fields.extend(completion.version_id.to_owned());
+    fields.extend(completion.location.to_owned());
+    writeln!(out, "{}", fields.join("\t"))?;
     Ok(())
 }
 
+/// The kind of store an upload is written to.
+enum Store {
+    S3,
+    File,
+}
+
+/// How to check an object once it's completed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Verify {
+    Full,
+}
+
+impl FromStr for Verify {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Verify::Full),
+            other => Err(format!("unknown verification {:?}", other).into()),
+        }
+    }
+}
+
 impl Opts {
+    fn destination(&self) -> std::result::Result<(Store, String, String), Error> {
+        destination(&self.bucket, &self.key, &self.dest)
+    }
+
     fn region(&self) -> std::result::Result<Region, Error> {
-        if let Some(ref endpoint) = self.endpoint {
-            Ok(Region::Custom {
-                name: self
-                    .region
-                    .as_ref()
-                    .map(|s| s.to_owned())
-                    .unwrap_or_else(|| "custom".to_string()),
-                endpoint: endpoint.to_owned(),
-            })
+        region(&self.region, &self.endpoint)
+    }
+
+    /// Where to print results: stdout, unless events are going there, when
+    /// stderr so the event stream stays one event per line.
+    fn output(&self) -> Box<dyn Write> {
+        if self.events.is_some() && self.events_to == "-" {
+            Box::new(std::io::stderr())
         } else {
-            self.region
-                .as_ref()
-                .map(|r| Region::from_str(&r))
-                .unwrap_or_else(|| Ok(Region::default()))
-                .map_err(|err| format!("region parse error: {}", err).into())
+            Box::new(std::io::stdout())
         }
     }
 }
+
+/// The store, bucket and key to upload to, from --bucket and --key or
+/// --dest. For files the bucket is the directory holding the object.
+fn destination(
+    bucket: &Option<String>,
+    key: &Option<String>,
+    dest: &Option<String>,
+) -> std::result::Result<(Store, String, String), Error> {
+    let dest = match dest {
+        Some(ref dest) => dest,
+        None => {
+            let bucket = bucket.as_ref().ok_or("--bucket or --dest is required")?;
+            let key = key.as_ref().ok_or("--key or --dest is required")?;
+            return Ok((Store::S3, bucket.to_owned(), key.to_owned()));
+        }
+    };
+
+    if let Some(path) = dest.strip_prefix("file://") {
+        let path = Path::new(path);
+        let dir = path
+            .parent()
+            .and_then(|dir| dir.to_str())
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or(".");
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| format!("no file name in destination {}", dest))?;
+        Ok((Store::File, dir.to_owned(), name.to_owned()))
+    } else if let Some(rest) = dest.strip_prefix("s3://") {
+        let (bucket, key) = rest
+            .split_once('/')
+            .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+            .ok_or_else(|| format!("expected s3://bucket/key, got {}", dest))?;
+        Ok((Store::S3, bucket.to_owned(), key.to_owned()))
+    } else {
+        Err(format!("unsupported destination {}", dest).into())
+    }
+}
+
+fn region(region: &Option<String>, endpoint: &Option<String>) -> std::result::Result<Region, Error> {
+    if let Some(ref endpoint) = endpoint {
+        Ok(Region::Custom {
+            name: region
+                .as_ref()
+                .map(|s| s.to_owned())
+                .unwrap_or_else(|| "custom".to_string()),
+            endpoint: endpoint.to_owned(),
+        })
+    } else {
+        region
+            .as_ref()
+            .map(|r| Region::from_str(r))
+            .unwrap_or_else(|| Ok(Region::default()))
+            .map_err(|err| format!("region parse error: {}", err).into())
+    }
+}
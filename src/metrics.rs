@@ -0,0 +1,305 @@
+use crate::events::EventKind;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+
+/// Upper bounds of the request latency histogram buckets, in seconds.
+static LATENCY_BUCKETS: [f64; 12] = [
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// Every state name, so the state gauge always has a series for each.
+static STATES: [&str; 9] = [
+    "init",
+    "starting",
+    "uploading",
+    "completing",
+    "completed",
+    "aborting",
+    "aborted",
+    "abandoning",
+    "refused",
+];
+
+/// How often the exporter checks whether the upload has finished.
+static TICK: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    bytes_uploaded: u64,
+    parts_completed: u64,
+    parts_failed: u64,
+    retries: BTreeMap<String, u64>,
+    latency: BTreeMap<&'static str, Histogram>,
+    state: &'static str,
+}
+
+/// Upload metrics in the Prometheus text format, for node_exporter's
+/// textfile collector.
+pub struct Metrics {
+    bucket: String,
+    key: String,
+    counters: Mutex<Counters>,
+    finished: AtomicBool,
+}
+
+impl Metrics {
+    pub fn new(bucket: &str, key: &str) -> Self {
+        Metrics {
+            bucket: bucket.to_owned(),
+            key: key.to_owned(),
+            counters: Mutex::new(Counters {
+                state: "init",
+                ..Default::default()
+            }),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    pub fn observe(&self, event: &EventKind) {
+        let mut counters = self.counters.lock().unwrap();
+        match *event {
+            EventKind::OperationApplied {
+                operation: "uploaded_part",
+                bytes,
+                ..
+            } => {
+                counters.parts_completed += 1;
+                counters.bytes_uploaded += bytes.unwrap_or(0);
+            }
+            EventKind::OperationApplied {
+                operation: "failed_part",
+                ..
+            } => counters.parts_failed += 1,
+            EventKind::Retry { ref error_code, .. } => {
+                let code = error_code.as_deref().unwrap_or("unknown").to_owned();
+                *counters.retries.entry(code).or_insert(0) += 1;
+            }
+            EventKind::StateTransition { to, .. } => counters.state = to,
+            _ => {}
+        }
+    }
+
+    pub fn set_state(&self, state: &'static str) {
+        self.counters.lock().unwrap().state = state;
+    }
+
+    pub fn observe_request(&self, request: &'static str, elapsed: Duration) {
+        self.counters
+            .lock()
+            .unwrap()
+            .latency
+            .entry(request)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+    }
+
+    pub fn render(&self) -> String {
+        let counters = self.counters.lock().unwrap();
+        let labels = format!(
+            "bucket=\"{}\",key=\"{}\"",
+            escape(&self.bucket),
+            escape(&self.key)
+        );
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP s3mu_uploaded_bytes_total Bytes in parts uploaded successfully.");
+        let _ = writeln!(out, "# TYPE s3mu_uploaded_bytes_total counter");
+        let _ = writeln!(out, "s3mu_uploaded_bytes_total{{{}}} {}", labels, counters.bytes_uploaded);
+
+        let _ = writeln!(out, "# HELP s3mu_parts_completed_total Parts uploaded successfully.");
+        let _ = writeln!(out, "# TYPE s3mu_parts_completed_total counter");
+        let _ = writeln!(out, "s3mu_parts_completed_total{{{}}} {}", labels, counters.parts_completed);
+
+        let _ = writeln!(out, "# HELP s3mu_parts_failed_total Failed part upload attempts.");
+        let _ = writeln!(out, "# TYPE s3mu_parts_failed_total counter");
+        let _ = writeln!(out, "s3mu_parts_failed_total{{{}}} {}", labels, counters.parts_failed);
+
+        let _ = writeln!(out, "# HELP s3mu_retries_total Failed requests that were retried, by S3 error code.");
+        let _ = writeln!(out, "# TYPE s3mu_retries_total counter");
+        for (code, count) in counters.retries.iter() {
+            let _ = writeln!(out, "s3mu_retries_total{{{},code=\"{}\"}} {}", labels, escape(code), count);
+        }
+
+        let _ = writeln!(out, "# HELP s3mu_request_duration_seconds S3 request latency.");
+        let _ = writeln!(out, "# TYPE s3mu_request_duration_seconds histogram");
+        for (request, histogram) in counters.latency.iter() {
+            let labels = format!("{},request=\"{}\"", labels, request);
+            for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+                let _ = writeln!(
+                    out,
+                    "s3mu_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "s3mu_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(out, "s3mu_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+            let _ = writeln!(out, "s3mu_request_duration_seconds_count{{{}}} {}", labels, histogram.count);
+        }
+
+        let _ = writeln!(out, "# HELP s3mu_state The current state of the upload.");
+        let _ = writeln!(out, "# TYPE s3mu_state gauge");
+        for state in STATES.iter() {
+            let value = if *state == counters.state { 1 } else { 0 };
+            let _ = writeln!(out, "s3mu_state{{{},state=\"{}\"}} {}", labels, state, value);
+        }
+
+        out
+    }
+
+    /// Replace the textfile atomically, so the collector never reads a
+    /// partly written file.
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut temp = PathBuf::from(path);
+        temp.set_extension("prom.tmp");
+        fs::write(&temp, self.render())?;
+        fs::rename(&temp, path)
+    }
+
+    /// Rewrite the textfile every `interval` until the upload finishes, then
+    /// once more with the final values.
+    pub async fn export(self: Arc<Self>, path: PathBuf, interval: Duration) {
+        let mut written = Instant::now();
+
+        loop {
+            delay_for(TICK).await;
+
+            let finished = self.finished.load(Ordering::SeqCst);
+            if !finished && written.elapsed() < interval {
+                continue;
+            }
+            written = Instant::now();
+
+            if let Err(err) = self.write(&path) {
+                log::warn!("error writing metrics to {:?}: {}", path, err);
+            }
+
+            if finished {
+                break;
+            }
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    fn retry(code: Option<&str>) -> EventKind {
+        EventKind::Retry {
+            stage: "upload_part",
+            part_number: Some(1),
+            attempt: 1,
+            error_code: code.map(str::to_owned),
+            error: "error".to_owned(),
+        }
+    }
+
+    fn applied(operation: &'static str, bytes: Option<u64>) -> EventKind {
+        EventKind::OperationApplied {
+            operation,
+            part_number: Some(1),
+            bytes,
+            etag: None,
+            error_code: None,
+        }
+    }
+
+    #[test]
+    fn renders_counters_in_the_textfile_format() {
+        let metrics = Metrics::new("bucket", "dir/\"key\"");
+        metrics.observe(&applied("uploaded_part", Some(100)));
+        metrics.observe(&applied("uploaded_part", Some(50)));
+        metrics.observe(&applied("failed_part", None));
+        metrics.observe(&retry(Some("SlowDown")));
+        metrics.observe(&retry(Some("SlowDown")));
+        metrics.observe(&retry(None));
+        metrics.observe(&EventKind::StateTransition { from: "starting", to: "uploading" });
+
+        let text = metrics.render();
+        let labels = r#"bucket="bucket",key="dir/\"key\"""#;
+        for line in &[
+            "# TYPE s3mu_uploaded_bytes_total counter".to_owned(),
+            format!("s3mu_uploaded_bytes_total{{{}}} 150", labels),
+            format!("s3mu_parts_completed_total{{{}}} 2", labels),
+            format!("s3mu_parts_failed_total{{{}}} 1", labels),
+            format!("s3mu_retries_total{{{},code=\"SlowDown\"}} 2", labels),
+            format!("s3mu_retries_total{{{},code=\"unknown\"}} 1", labels),
+            format!("s3mu_state{{{},state=\"uploading\"}} 1", labels),
+            format!("s3mu_state{{{},state=\"starting\"}} 0", labels),
+        ] {
+            assert!(text.lines().any(|found| found == line), "no {:?} in\n{}", line, text);
+        }
+        assert_eq!(text.lines().filter(|line| line.starts_with("s3mu_state{")).count(), STATES.len());
+    }
+
+    #[test]
+    fn renders_latency_histograms_with_cumulative_buckets() {
+        let metrics = Metrics::new("bucket", "key");
+        metrics.observe_request("upload_part", Duration::from_millis(300));
+        metrics.observe_request("upload_part", Duration::from_secs(3));
+
+        let text = metrics.render();
+        let labels = r#"bucket="bucket",key="key",request="upload_part""#;
+        for (bound, count) in &[("0.25", 0), ("0.5", 1), ("2.5", 1), ("5", 2), ("+Inf", 2)] {
+            let line = format!("s3mu_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+            assert!(text.lines().any(|found| found == line), "no {:?} in\n{}", line, text);
+        }
+        assert!(text.contains(&format!("s3mu_request_duration_seconds_sum{{{}}} 3.3", labels)));
+        assert!(text.contains(&format!("s3mu_request_duration_seconds_count{{{}}} 2", labels)));
+    }
+
+    #[test]
+    fn writes_the_textfile_whole() {
+        let dir = scratch("metrics-write");
+        let path = dir.join("s3mu.prom");
+        let metrics = Metrics::new("bucket", "key");
+        metrics.write(&path).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), metrics.render());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::state::Part;
+use bytes::Bytes;
+use futures::Stream;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::time::delay_for;
+
+/// How far back the current throughput is measured.
+static RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// How often the progress line is redrawn on a terminal.
+static TTY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the reporter checks whether the upload has finished.
+static TICK: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+struct Parts {
+    total: usize,
+    done: usize,
+    /// The bytes sent of each part being uploaded, with the part's size and
+    /// the size of its body, to count compressed bodies in source bytes.
+    in_flight: BTreeMap<i64, (Arc<AtomicU64>, u64, u64)>,
+    retries: BTreeMap<i64, u32>,
+}
+
+/// Upload progress shared between the app and the reporter task.
+pub struct Progress {
+    total_bytes: AtomicU64,
+    done_bytes: AtomicU64,
+    resumed_bytes: AtomicU64,
+    started: Instant,
+    finished: AtomicBool,
+    parts: Mutex<Parts>,
+    samples: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+/// A point in time view of the upload, as shown to the user.
+pub struct Snapshot {
+    pub total_bytes: u64,
+    pub sent_bytes: u64,
+    pub total_parts: usize,
+    pub done_parts: usize,
+    pub current_rate: f64,
+    pub average_rate: f64,
+    pub eta: Option<Duration>,
+    pub retries: BTreeMap<i64, u32>,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress::new()
+    }
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Progress {
+            total_bytes: AtomicU64::new(0),
+            done_bytes: AtomicU64::new(0),
+            resumed_bytes: AtomicU64::new(0),
+            started: Instant::now(),
+            finished: AtomicBool::new(false),
+            parts: Mutex::new(Parts::default()),
+            samples: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Reset the totals from the configured parts. Parts that already have an
+    /// etag were uploaded by an earlier run and count as done. Progress is
+    /// counted in the parts' bytes before any compression.
+    pub fn set_parts(&self, parts: &[Part]) {
+        let mut total = 0;
+        let mut done = 0;
+        let mut done_parts = 0;
+
+        for part in parts {
+            let size = part.source_size().unwrap_or(0);
+            total += size;
+            if !part.etag.is_empty() {
+                done += size;
+                done_parts += 1;
+            }
+        }
+
+        self.total_bytes.store(total, Ordering::SeqCst);
+        self.done_bytes.store(done, Ordering::SeqCst);
+        self.resumed_bytes.store(done, Ordering::SeqCst);
+
+        let mut state = self.parts.lock().unwrap();
+        state.total = parts.len();
+        state.done = done_parts;
+    }
+
+    /// Begin an attempt at uploading a part of `size` bytes in a body of
+    /// `body_len`, returning the counter its body stream should add sent
+    /// bytes to.
+    pub fn start_part(&self, number: i64, size: u64, body_len: u64) -> Arc<AtomicU64> {
+        let sent = Arc::new(AtomicU64::new(0));
+        self.parts
+            .lock()
+            .unwrap()
+            .in_flight
+            .insert(number, (sent.clone(), size, body_len));
+        sent
+    }
+
+    pub fn part_done(&self, number: i64, size: u64) {
+        let mut parts = self.parts.lock().unwrap();
+        parts.in_flight.remove(&number);
+        parts.done += 1;
+        self.done_bytes.fetch_add(size, Ordering::SeqCst);
+    }
+
+    pub fn part_failed(&self, number: i64) {
+        let mut parts = self.parts.lock().unwrap();
+        parts.in_flight.remove(&number);
+        *parts.retries.entry(number).or_insert(0) += 1;
+    }
+
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let parts = self.parts.lock().unwrap();
+        let in_flight: u64 = parts
+            .in_flight
+            .values()
+            .map(|(sent, size, body_len)| match sent.load(Ordering::SeqCst) {
+                sent if body_len == size || *body_len == 0 => sent,
+                sent => (sent as u128 * *size as u128 / *body_len as u128) as u64,
+            })
+            .sum();
+        let total_bytes = self.total_bytes.load(Ordering::SeqCst);
+        let sent_bytes = self.done_bytes.load(Ordering::SeqCst) + in_flight;
+
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back((now, sent_bytes));
+        while samples.len() > 2 && now.duration_since(samples[0].0) > RATE_WINDOW {
+            samples.pop_front();
+        }
+
+        let (first_time, first_bytes) = samples[0];
+        let window = now.duration_since(first_time).as_secs_f64();
+        let current_rate = if window > 0.0 {
+            sent_bytes.saturating_sub(first_bytes) as f64 / window
+        } else {
+            0.0
+        };
+
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let resumed_bytes = self.resumed_bytes.load(Ordering::SeqCst);
+        let average_rate = if elapsed > 0.0 {
+            sent_bytes.saturating_sub(resumed_bytes) as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let remaining = total_bytes.saturating_sub(sent_bytes);
+        let rate = if current_rate > 0.0 { current_rate } else { average_rate };
+        let eta = if rate > 0.0 {
+            Some(Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            None
+        };
+
+        Snapshot {
+            total_bytes,
+            sent_bytes,
+            total_parts: parts.total,
+            done_parts: parts.done,
+            current_rate,
+            average_rate,
+            eta,
+            retries: parts.retries.clone(),
+        }
+    }
+
+    /// Draw progress on stderr until the upload finishes. On a terminal the
+    /// line is redrawn in place, otherwise a plain line is printed every
+    /// `interval`. A final line is always printed once the upload is done.
+    pub async fn report(self: Arc<Self>, interval: Duration) {
+        let tty = atty::is(atty::Stream::Stderr);
+        let interval = if tty { TTY_INTERVAL } else { interval };
+        let mut printed = Instant::now();
+
+        loop {
+            delay_for(TICK).await;
+
+            let finished = self.is_finished();
+            if !finished && printed.elapsed() < interval {
+                continue;
+            }
+            printed = Instant::now();
+
+            let line = self.snapshot().to_string();
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            let _ = match (tty, finished) {
+                (true, false) => write!(stderr, "\r\x1b[K{}", line),
+                (true, true) => writeln!(stderr, "\r\x1b[K{}", line),
+                (false, _) => writeln!(stderr, "{}", line),
+            };
+            let _ = stderr.flush();
+
+            if finished {
+                break;
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let percent = if self.total_bytes > 0 {
+            self.sent_bytes as f64 * 100.0 / self.total_bytes as f64
+        } else {
+            0.0
+        };
+
+        write!(
+            f,
+            "{} / {} ({:.1}%), parts {} / {}, {}/s now, {}/s average",
+            format_bytes(self.sent_bytes as f64),
+            format_bytes(self.total_bytes as f64),
+            percent,
+            self.done_parts,
+            self.total_parts,
+            format_bytes(self.current_rate),
+            format_bytes(self.average_rate),
+        )?;
+
+        if let Some(eta) = self.eta {
+            write!(f, ", eta {}", format_duration(eta))?;
+        }
+
+        if !self.retries.is_empty() {
+            let retries: Vec<String> = self
+                .retries
+                .iter()
+                .map(|(part, count)| format!("{}x{}", part, count))
+                .collect();
+            write!(f, ", retries (part x count): {}", retries.join(" "))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, units[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// A body stream that counts the bytes handed to the http client.
+pub struct Metered<S> {
+    inner: S,
+    sent: Arc<AtomicU64>,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, sent: Arc<AtomicU64>) -> Self {
+        Metered { inner, sent }
+    }
+}
+
+impl<S> Stream for Metered<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(ref chunk))) = poll {
+            self.sent.fetch_add(chunk.len() as u64, Ordering::SeqCst);
+        }
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(number: i64, size: u64, etag: &str) -> Part {
+        Part {
+            size: Some(size),
+            etag: etag.to_owned(),
+            ..Part::new(number, format!("missing-part-{}", number))
+        }
+    }
+
+    /// Progress that started `secs` ago, with nothing measured since.
+    fn started_ago(secs: u64) -> Progress {
+        let mut progress = Progress::new();
+        progress.started = Instant::now() - Duration::from_secs(secs);
+        progress
+    }
+
+    fn near(duration: Duration, secs: f64) -> bool {
+        (duration.as_secs_f64() - secs).abs() < 0.1
+    }
+
+    #[test]
+    fn resumed_parts_count_as_done() {
+        let progress = Progress::new();
+        progress.set_parts(&[part(1, 100, "\"a\""), part(2, 300, ""), part(3, 600, "")]);
+
+        let snapshot = progress.snapshot();
+        assert_eq!((snapshot.sent_bytes, snapshot.total_bytes), (100, 1000));
+        assert_eq!((snapshot.done_parts, snapshot.total_parts), (1, 3));
+    }
+
+    #[test]
+    fn counts_compressed_bodies_in_source_bytes() {
+        let progress = Progress::new();
+        progress.set_parts(&[part(1, 1000, ""), part(2, 1000, "")]);
+        progress.start_part(1, 1000, 250).store(125, Ordering::SeqCst);
+        progress.start_part(2, 1000, 1000).store(300, Ordering::SeqCst);
+        assert_eq!(progress.snapshot().sent_bytes, 800);
+
+        progress.part_done(1, 1000);
+        progress.part_failed(2);
+        let snapshot = progress.snapshot();
+        assert_eq!((snapshot.sent_bytes, snapshot.done_parts), (1000, 1));
+        assert_eq!(snapshot.retries.get(&2), Some(&1));
+    }
+
+    #[test]
+    fn estimates_from_the_current_rate() {
+        let progress = started_ago(100);
+        progress.set_parts(&[part(1, 400, ""), part(2, 600, "")]);
+        progress.samples.lock().unwrap().push_back((Instant::now() - Duration::from_secs(4), 0));
+        progress.part_done(1, 400);
+
+        // 400 bytes in the last 4 seconds leaves 600 bytes for 6 more
+        let snapshot = progress.snapshot();
+        assert!((snapshot.current_rate - 100.0).abs() < 1.0, "{}", snapshot.current_rate);
+        assert!((snapshot.average_rate - 4.0).abs() < 0.1, "{}", snapshot.average_rate);
+        assert!(near(snapshot.eta.unwrap(), 6.0), "{:?}", snapshot.eta);
+    }
+
+    #[test]
+    fn estimates_from_the_average_when_stalled() {
+        let progress = started_ago(10);
+        progress.set_parts(&[part(1, 100, "\"a\""), part(2, 200, ""), part(3, 300, "")]);
+        progress.part_done(2, 200);
+        progress.samples.lock().unwrap().push_back((Instant::now() - Duration::from_secs(4), 300));
+
+        // the resumed part wasn't sent in this run, so 200 bytes in 10 seconds
+        let snapshot = progress.snapshot();
+        assert_eq!(snapshot.current_rate, 0.0);
+        assert!((snapshot.average_rate - 20.0).abs() < 0.1, "{}", snapshot.average_rate);
+        assert!(near(snapshot.eta.unwrap(), 15.0), "{:?}", snapshot.eta);
+    }
+
+    #[test]
+    fn has_no_estimate_before_sending_anything() {
+        let progress = started_ago(10);
+        progress.set_parts(&[part(1, 100, "")]);
+        assert_eq!(progress.snapshot().eta, None);
+    }
+
+    #[test]
+    fn describes_a_snapshot_on_one_line() {
+        let mut retries = BTreeMap::new();
+        retries.insert(3, 2);
+        let snapshot = Snapshot {
+            total_bytes: 4 << 30,
+            sent_bytes: 1 << 30,
+            total_parts: 8,
+            done_parts: 2,
+            current_rate: 3.0 * 1024.0 * 1024.0,
+            average_rate: 1536.0,
+            eta: Some(Duration::from_secs(3725)),
+            retries,
+        };
+        assert_eq!(
+            snapshot.to_string(),
+            "1.0GiB / 4.0GiB (25.0%), parts 2 / 8, 3.0MiB/s now, 1.5KiB/s average, eta 1:02:05, \
+             retries (part x count): 3x2"
+        );
+    }
+}
@@ -1,4 +1,6 @@
-use serde::{Deserialize, Serialize};
+use crate::archive::ArchiveSlice;
+use crate::backend::Completion;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 
 #[derive(Debug, Clone)]
@@ -22,6 +24,28 @@ pub struct Part {
     pub number: i64,
     pub path: String,
     pub etag: String,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub md5: Option<String>,
+    /// For a part packed from several files, the slices of them it is made
+    /// of, in order. `path` is then the first of those files. Empty for a
+    /// part that is a whole file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<Segment>,
+    /// For a part of an archive made on the fly, where in the archive it
+    /// lies. `path` is then the file of its first entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<ArchiveSlice>,
+}
+
+/// A slice of a file, `len` bytes from `offset`, that makes up some of a
+/// packed part.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Segment {
+    pub path: String,
+    pub offset: u64,
+    pub len: u64,
 }
 
 impl Part {
@@ -30,47 +54,237 @@ impl Part {
             number,
             path,
             etag: String::new(),
+            size: None,
+            md5: None,
+            segments: vec![],
+            archive: None,
+        }
+    }
+
+    /// A part made of slices of files, named after the first of them.
+    pub fn packed(number: i64, segments: Vec<Segment>) -> Self {
+        let path = segments.first().map(|segment| segment.path.to_owned()).unwrap_or_default();
+        Part {
+            segments,
+            ..Part::new(number, path)
+        }
+    }
+
+    /// A part of an archive, named after the file of its first entry.
+    pub fn archived(number: i64, slice: ArchiveSlice) -> Self {
+        let path = slice.entries.first().map(|entry| entry.path.to_owned()).unwrap_or_default();
+        Part {
+            archive: Some(slice),
+            ..Part::new(number, path)
+        }
+    }
+
+    /// The size of the part's data before any compression: the sum of what
+    /// it's made of, else the size of its file, else its size as hashed.
+    /// Once a compressed part is uploaded, `size` is the compressed size.
+    pub fn source_size(&self) -> Option<u64> {
+        if let Some(ref slice) = self.archive {
+            return Some(slice.len);
+        }
+        if !self.segments.is_empty() {
+            return Some(self.segments.iter().map(|segment| segment.len).sum());
+        }
+        std::fs::metadata(&self.path).map(|m| m.len()).ok().or(self.size)
+    }
+
+    /// The size and base64 md5 recorded for this part, if it has been hashed.
+    pub fn digest(&self) -> Option<(u64, String)> {
+        match (self.size, self.md5.as_ref()) {
+            (Some(size), Some(md5)) => Some((size, md5.to_owned())),
+            _ => None,
         }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(remote = "Self")]
 pub enum Operation {
     ConfiguredParts(Vec<Part>),
+    ListedUploads {
+        upload_ids: Vec<String>,
+    },
     Started {
         upload_id: String,
     },
     FailedStart {
         attempt: u32,
         msg: String,
+        #[serde(default)]
+        code: Option<String>,
+    },
+    HashedPart {
+        index: usize,
+        size: u64,
+        md5: String,
     },
+    /// A part was uploaded. Parts uploaded elsewhere, e.g. by a worker,
+    /// bring their size and md5, since they weren't hashed here.
     UploadedPart {
         index: usize,
         etag: String,
+        #[serde(default)]
+        size: Option<u64>,
+        #[serde(default)]
+        md5: Option<String>,
     },
     FailedPart {
         index: usize,
         attempt: u32,
         msg: String,
+        #[serde(default)]
+        code: Option<String>,
     },
     FailedComplete {
         attempt: u32,
         msg: String,
+        #[serde(default)]
+        code: Option<String>,
+    },
+    /// The upload was completed, creating this object.
+    Completed {
+        #[serde(default)]
+        etag: Option<String>,
+        #[serde(default)]
+        version_id: Option<String>,
+        #[serde(default)]
+        location: Option<String>,
+        #[serde(default)]
+        size: Option<u64>,
+    },
+    /// The destination already holds what the upload would create, so it
+    /// was never started.
+    Skipped {
+        reason: String,
+        #[serde(default)]
+        etag: Option<String>,
+        #[serde(default)]
+        version_id: Option<String>,
+        #[serde(default)]
+        size: Option<u64>,
+    },
+    /// The destination was checked before starting, and the upload goes
+    /// ahead.
+    CheckedDestination,
+    /// There is already an object at the key, which mustn't be overwritten.
+    Refused {
+        reason: String,
     },
-    Completed,
     FailedAbort {
         attempt: u32,
         msg: String,
+        #[serde(default)]
+        code: Option<String>,
     },
     Aborted,
+    /// Every part of the completed object was read back and matched the md5
+    /// it was uploaded with.
+    Verified,
+    /// A part of the completed object was read back and didn't match.
+    VerificationFailed {
+        part_number: i64,
+        reason: String,
+    },
+    /// Reading the completed object back failed, so it is still unverified.
+    FailedVerify {
+        attempt: u32,
+        msg: String,
+        #[serde(default)]
+        code: Option<String>,
+    },
+    /// The index of the source files is in place, written to this sidecar
+    /// key, or already in the object's metadata if there is none.
+    Indexed {
+        #[serde(default)]
+        key: Option<String>,
+    },
+    FailedIndex {
+        attempt: u32,
+        msg: String,
+        #[serde(default)]
+        code: Option<String>,
+    },
 }
 
-#[derive(Debug, Clone)]
+impl Operation {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::ConfiguredParts(_) => "configured_parts",
+            Operation::ListedUploads { .. } => "listed_uploads",
+            Operation::Started { .. } => "started",
+            Operation::FailedStart { .. } => "failed_start",
+            Operation::HashedPart { .. } => "hashed_part",
+            Operation::UploadedPart { .. } => "uploaded_part",
+            Operation::FailedPart { .. } => "failed_part",
+            Operation::FailedComplete { .. } => "failed_complete",
+            Operation::Completed { .. } => "completed",
+            Operation::Skipped { .. } => "skipped",
+            Operation::CheckedDestination => "checked_destination",
+            Operation::Refused { .. } => "refused",
+            Operation::FailedAbort { .. } => "failed_abort",
+            Operation::Aborted => "aborted",
+            Operation::Verified => "verified",
+            Operation::VerificationFailed { .. } => "verification_failed",
+            Operation::FailedVerify { .. } => "failed_verify",
+            Operation::Indexed { .. } => "indexed",
+            Operation::FailedIndex { .. } => "failed_index",
+        }
+    }
+
+    /// The operation recording a completed upload.
+    pub fn completed(completion: Completion) -> Self {
+        Operation::Completed {
+            etag: completion.etag,
+            version_id: completion.version_id,
+            location: completion.location,
+            size: completion.size,
+        }
+    }
+}
+
+impl Serialize for Operation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Operation::serialize(self, serializer)
+    }
+}
+
+/// Logs written before completions recorded the object hold `Completed` as
+/// a bare name.
+impl<'de> Deserialize<'de> for Operation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        if value.as_str() == Some("Completed") {
+            return Ok(Operation::completed(Completion::default()));
+        }
+        Operation::deserialize(value).map_err(serde::de::Error::custom)
+    }
+}
+
+/// The result of reading a completed object back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verification {
+    Verified,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Default)]
 pub enum State {
+    #[default]
     Init,
+    /// Creating the upload. `existing` holds the uploads already in progress
+    /// for the key before the first attempt, so any others found later are
+    /// ones an attempt created without the upload id reaching the log.
+    /// `checked` is set once the destination has been checked, for jobs that
+    /// check it.
     Starting {
         parts: Vec<Part>,
         attempt: u32,
+        existing: Option<Vec<String>>,
+        checked: bool,
     },
     Uploading {
         parts: Vec<Part>,
@@ -83,12 +297,30 @@ pub enum State {
         attempt: u32,
         parts: Vec<Part>,
     },
-    Completed,
+    /// Completed, creating this object. Jobs that verify it then read it
+    /// back, setting `verification`, and jobs that index it set `indexed`
+    /// once the index is in place.
+    Completed {
+        completion: Completion,
+        parts: Vec<Part>,
+        attempt: u32,
+        verification: Option<Verification>,
+        indexed: bool,
+    },
+    /// Aborting the upload, and then refused if `refused` is set.
     Aborting {
         upload_id: String,
         attempt: u32,
+        refused: bool,
     },
     Aborted,
+    /// Stopped without overwriting the object already at the key.
+    Refused,
+    /// Giving up on starting, and aborting uploads left by failed attempts.
+    Abandoning {
+        existing: Vec<String>,
+        attempt: u32,
+    },
 }
 
 impl State {
@@ -96,6 +328,48 @@ impl State {
         State::Init
     }
 
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Init => "init",
+            State::Starting { .. } => "starting",
+            State::Uploading { .. } => "uploading",
+            State::Completing { .. } => "completing",
+            State::Completed { .. } => "completed",
+            State::Aborting { .. } => "aborting",
+            State::Aborted => "aborted",
+            State::Refused => "refused",
+            State::Abandoning { .. } => "abandoning",
+        }
+    }
+
+    pub fn upload_id(&self) -> Option<&str> {
+        match self {
+            State::Uploading { upload_id, .. }
+            | State::Completing { upload_id, .. }
+            | State::Aborting { upload_id, .. } => Some(upload_id),
+            _ => None,
+        }
+    }
+
+    /// What completing the upload created, once it has.
+    pub fn completion(&self) -> Option<&Completion> {
+        match self {
+            State::Completed { completion, .. } => Some(completion),
+            _ => None,
+        }
+    }
+
+    /// The configured parts, in the states that have them.
+    pub fn parts(&self) -> Option<&[Part]> {
+        match self {
+            State::Starting { parts, .. }
+            | State::Uploading { parts, .. }
+            | State::Completing { parts, .. }
+            | State::Completed { parts, .. } => Some(parts),
+            _ => None,
+        }
+    }
+
     pub fn apply(self, op: Operation) -> Result<State> {
         log::info!("state: {:?}", self);
         log::info!("op: {:?}", op);
@@ -104,9 +378,14 @@ impl State {
             State::Init => match op {
                 Operation::ConfiguredParts(parts) => {
                     if parts.is_empty() {
-                        Err(Error::InvalidState(format!("no parts configured")))
+                        Err(Error::InvalidState("no parts configured".to_string()))
                     } else {
-                        Ok(State::Starting { parts, attempt: 0 })
+                        Ok(State::Starting {
+                            parts,
+                            attempt: 0,
+                            existing: None,
+                            checked: false,
+                        })
                     }
                 },
                 op => Err(Error::InvalidState(format!(
@@ -114,16 +393,76 @@ impl State {
                     op
                 ))),
             },
-            State::Starting { parts, attempt } => match op {
+            State::Starting {
+                mut parts,
+                attempt,
+                existing,
+                checked,
+            } => match op {
+                Operation::ListedUploads { upload_ids } => Ok(State::Starting {
+                    parts,
+                    attempt: 0,
+                    existing: Some(upload_ids),
+                    checked,
+                }),
+                Operation::HashedPart { index, size, md5 } => {
+                    let part = parts.get_mut(index).ok_or(Error::IndexOutOfBounds)?;
+                    part.size = Some(size);
+                    part.md5 = Some(md5);
+
+                    Ok(State::Starting {
+                        parts,
+                        attempt,
+                        existing,
+                        checked,
+                    })
+                }
+                Operation::FailedPart { attempt, .. } => Ok(State::Starting {
+                    parts,
+                    attempt: attempt + 1,
+                    existing,
+                    checked,
+                }),
+                Operation::CheckedDestination => Ok(State::Starting {
+                    parts,
+                    attempt: 0,
+                    existing,
+                    checked: true,
+                }),
+                Operation::Skipped {
+                    etag,
+                    version_id,
+                    size,
+                    ..
+                } => Ok(State::Completed {
+                    completion: Completion {
+                        etag,
+                        version_id,
+                        location: None,
+                        size,
+                    },
+                    parts,
+                    attempt: 0,
+                    verification: None,
+                    indexed: false,
+                }),
+                Operation::Refused { .. } => Ok(State::Refused),
                 Operation::Started { upload_id } => Ok(State::Uploading {
                     upload_id,
                     parts,
                     index: 0,
                     attempt: 0,
                 }),
-                Operation::FailedStart { attempt, msg } => Ok(State::Starting {
+                Operation::FailedStart { attempt, .. } => Ok(State::Starting {
                     parts,
-                    attempt,
+                    attempt: attempt + 1,
+                    existing,
+                    checked,
+                }),
+                Operation::Aborted => Ok(State::Aborted),
+                Operation::FailedAbort { attempt, .. } => Ok(State::Abandoning {
+                    existing: existing.unwrap_or_default(),
+                    attempt: attempt + 1,
                 }),
                 op => Err(Error::InvalidState(format!(
                     "invalid operation {:?} in ready state",
@@ -136,87 +475,190 @@ impl State {
                 index,
                 attempt,
             } => match op {
-                Operation::UploadedPart { mut index, etag } => {
-                    parts
-                        .get_mut(index)
-                        .ok_or_else(|| Error::IndexOutOfBounds)?
-                        .etag = etag;
-                    
-                    index += 1;
-
-                    if index == parts.len() {
-                        Ok(State::Completing {
+                Operation::HashedPart {
+                    index: hashed,
+                    size,
+                    md5,
+                } => {
+                    let part = parts.get_mut(hashed).ok_or(Error::IndexOutOfBounds)?;
+                    part.size = Some(size);
+                    part.md5 = Some(md5);
+
+                    Ok(State::Uploading {
+                        upload_id,
+                        index,
+                        parts,
+                        attempt,
+                    })
+                }
+                Operation::UploadedPart {
+                    index: uploaded,
+                    etag,
+                    size,
+                    md5,
+                } => {
+                    let part = parts.get_mut(uploaded).ok_or(Error::IndexOutOfBounds)?;
+                    part.etag = etag;
+                    if size.is_some() {
+                        part.size = size;
+                    }
+                    if md5.is_some() {
+                        part.md5 = md5;
+                    }
+
+                    // parts may be reported out of order, so carry on with
+                    // the first one still to upload
+                    match parts.iter().position(|part| part.etag.is_empty()) {
+                        None => Ok(State::Completing {
                             upload_id,
                             attempt: 0,
                             parts,
-                        })
-                    } else {
-                        Ok(State::Uploading {
+                        }),
+                        Some(next) => Ok(State::Uploading {
                             upload_id,
-                            index,
+                            attempt: if next == index { attempt } else { 0 },
+                            index: next,
                             parts,
-                            attempt: 0,
-                        })
+                        }),
                     }
                 }
-                Operation::FailedPart {
-                    index,
-                    attempt,
-                    msg,
-                } => Ok(State::Uploading {
+                Operation::FailedPart { index, attempt, .. } => Ok(State::Uploading {
                     upload_id,
                     index,
                     parts,
                     attempt: attempt + 1,
                 }),
+                Operation::Aborted => Ok(State::Aborted),
+                Operation::FailedAbort { attempt, .. } => Ok(State::Aborting {
+                    upload_id,
+                    attempt: attempt + 1,
+                    refused: false,
+                }),
                 op => Err(Error::InvalidState(format!(
                     "invalid operation {:?} in uploading state",
                     op
                 ))),
             },
-            State::Completing {
-                upload_id,
-                attempt,
-                parts,
-            } => match op {
-                Operation::Completed => Ok(State::Completed),
-                Operation::FailedComplete { attempt, msg } => Ok(State::Completing {
-                    upload_id: upload_id.to_owned(),
+            State::Completing { upload_id, parts, .. } => match op {
+                Operation::Completed {
+                    etag,
+                    version_id,
+                    location,
+                    size,
+                } => Ok(State::Completed {
+                    completion: Completion {
+                        etag,
+                        version_id,
+                        location,
+                        size,
+                    },
+                    parts,
+                    attempt: 0,
+                    verification: None,
+                    indexed: false,
+                }),
+                Operation::Refused { .. } => Ok(State::Aborting {
+                    upload_id,
+                    attempt: 0,
+                    refused: true,
+                }),
+                Operation::FailedComplete { attempt, .. } => Ok(State::Completing {
+                    upload_id,
                     attempt: attempt + 1,
                     parts,
                 }),
                 Operation::Aborted => Ok(State::Aborted),
+                Operation::FailedAbort { attempt, .. } => Ok(State::Aborting {
+                    upload_id,
+                    attempt: attempt + 1,
+                    refused: false,
+                }),
                 op => Err(Error::InvalidState(format!(
                     "invalid operation {:?} in completing state",
                     op
                 ))),
             },
             State::Aborting {
-                ref upload_id,
-                attempt,
+                upload_id, refused, ..
             } => match op {
+                Operation::Aborted if refused => Ok(State::Refused),
                 Operation::Aborted => Ok(State::Aborted),
-                Operation::FailedAbort { msg, attempt } => Ok(State::Aborting {
+                Operation::FailedAbort { attempt, .. } => Ok(State::Aborting {
                     attempt: attempt + 1,
-                    upload_id: upload_id.to_owned(),
+                    upload_id,
+                    refused,
                 }),
                 op => Err(Error::InvalidState(format!(
                     "invalid operation {:?} in aborting state",
                     op
                 ))),
             },
-            State::Completed => match op {
+            State::Abandoning { existing, .. } => match op {
+                Operation::Aborted => Ok(State::Aborted),
+                Operation::FailedAbort { attempt, .. } => Ok(State::Abandoning {
+                    existing,
+                    attempt: attempt + 1,
+                }),
                 op => Err(Error::InvalidState(format!(
-                    "invalid operation {:?} in completed state",
+                    "invalid operation {:?} in abandoning state",
                     op
                 ))),
             },
-            State::Aborted => match op {
+            State::Completed {
+                completion,
+                parts,
+                verification,
+                indexed,
+                ..
+            } => match op {
+                Operation::Verified if verification.is_none() => Ok(State::Completed {
+                    completion,
+                    parts,
+                    attempt: 0,
+                    verification: Some(Verification::Verified),
+                    indexed,
+                }),
+                Operation::VerificationFailed { reason, .. } if verification.is_none() => Ok(State::Completed {
+                    completion,
+                    parts,
+                    attempt: 0,
+                    verification: Some(Verification::Failed(reason)),
+                    indexed,
+                }),
+                Operation::FailedVerify { attempt, .. } if verification.is_none() => Ok(State::Completed {
+                    completion,
+                    parts,
+                    attempt: attempt + 1,
+                    verification,
+                    indexed,
+                }),
+                Operation::Indexed { .. } if !indexed => Ok(State::Completed {
+                    completion,
+                    parts,
+                    attempt: 0,
+                    verification,
+                    indexed: true,
+                }),
+                Operation::FailedIndex { attempt, .. } if !indexed => Ok(State::Completed {
+                    completion,
+                    parts,
+                    attempt: attempt + 1,
+                    verification,
+                    indexed,
+                }),
                 op => Err(Error::InvalidState(format!(
-                    "invalid operation {:?} in aborted state",
+                    "invalid operation {:?} in completed state",
                     op
                 ))),
-            }
+            },
+            State::Aborted => Err(Error::InvalidState(format!(
+                "invalid operation {:?} in aborted state",
+                op
+            ))),
+            State::Refused => Err(Error::InvalidState(format!(
+                "invalid operation {:?} in refused state",
+                op
+            ))),
         }
     }
 }
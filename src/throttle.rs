@@ -0,0 +1,460 @@
+use crate::error::Error;
+use bytes::Bytes;
+use chrono::{Local, NaiveTime};
+use futures::Stream;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time::{delay_for, Delay};
+
+/// How often a running job looks for changes to the schedule file.
+static SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The most bytes that may be sent in one go after the link has been idle.
+static MAX_BURST: Duration = Duration::from_secs(1);
+
+/// Throttled bodies are split into chunks of this size so the limit is
+/// applied smoothly even to parts held in memory.
+pub static CHUNK_SIZE: usize = 64 * 1024;
+
+/// A transfer rate in bytes per second, or unlimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rate {
+    Unlimited,
+    BytesPerSecond(u64),
+}
+
+impl FromStr for Rate {
+    type Err = Error;
+
+    /// Parse rates like `50MB/s`, `512KiB/s` or `unlimited`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("unlimited") {
+            return Ok(Rate::Unlimited);
+        }
+
+        let amount = s
+            .strip_suffix("/s")
+            .ok_or_else(|| format!("rate {:?} must end in /s", s))?;
+        let digits = amount
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(amount.len());
+        let (number, unit) = amount.split_at(digits);
+
+        let number: f64 = number
+            .parse()
+            .map_err(|err| format!("invalid rate {:?}: {}", s, err))?;
+        let multiplier: u64 = match unit.trim() {
+            "" | "B" => 1,
+            "KB" | "kB" => 1000,
+            "MB" => 1000 * 1000,
+            "GB" => 1000 * 1000 * 1000,
+            "KiB" => 1024,
+            "MiB" => 1024 * 1024,
+            "GiB" => 1024 * 1024 * 1024,
+            unit => return Err(format!("unknown unit {:?} in rate {:?}", unit, s).into()),
+        };
+
+        let bytes = (number * multiplier as f64) as u64;
+        if bytes == 0 {
+            return Err(format!("rate {:?} must be greater than zero", s).into());
+        }
+
+        Ok(Rate::BytesPerSecond(bytes))
+    }
+}
+
+/// A rate that applies between two times of day. Windows may wrap midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub rate: Rate,
+}
+
+impl Window {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+/// Time of day bandwidth limits, read from a file like:
+///
+/// ```text
+/// # full speed overnight
+/// 20:00-06:00 unlimited
+/// * 10MB/s
+/// ```
+///
+/// The first matching window wins. The `*` line, if any, applies at all
+/// other times; without one the `--max-bandwidth` limit is used.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Schedule {
+    pub windows: Vec<Window>,
+    pub default: Option<Rate>,
+}
+
+impl Schedule {
+    pub fn load(path: &Path) -> std::result::Result<Self, Error> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("error reading bandwidth schedule {:?}: {}", path, err))?;
+        contents.parse()
+    }
+
+    pub fn rate_at(&self, time: NaiveTime) -> Option<Rate> {
+        self.windows
+            .iter()
+            .find(|window| window.contains(time))
+            .map(|window| window.rate)
+            .or(self.default)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut schedule = Schedule::default();
+
+        for (n, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let (when, rate) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(when), Some(rate), None) => (when, rate.parse()?),
+                _ => return Err(format!("schedule line {}: expected '<start>-<end> <rate>'", n + 1).into()),
+            };
+
+            if when == "*" {
+                schedule.default = Some(rate);
+                continue;
+            }
+
+            let (start, end) = when
+                .split_once('-')
+                .ok_or_else(|| format!("schedule line {}: invalid window {:?}", n + 1, when))?;
+            let parse_time = |t: &str| {
+                NaiveTime::parse_from_str(t, "%H:%M")
+                    .map_err(|err| format!("schedule line {}: invalid time {:?}: {}", n + 1, t, err))
+            };
+
+            schedule.windows.push(Window {
+                start: parse_time(start)?,
+                end: parse_time(end)?,
+                rate,
+            });
+        }
+
+        Ok(schedule)
+    }
+}
+
+struct ScheduleFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    checked: Instant,
+    schedule: Schedule,
+}
+
+impl ScheduleFile {
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    fn refresh(&mut self) {
+        if self.checked.elapsed() < SCHEDULE_CHECK_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+
+        let modified = ScheduleFile::modified(&self.path);
+        if modified == self.modified {
+            return;
+        }
+
+        match Schedule::load(&self.path) {
+            Ok(schedule) => {
+                log::info!("reloaded bandwidth schedule {:?}", self.path);
+                self.schedule = schedule;
+                self.modified = modified;
+            }
+            Err(err) => log::warn!("keeping previous bandwidth schedule: {}", err),
+        }
+    }
+}
+
+struct Bucket {
+    rate: Rate,
+    tokens: f64,
+    refilled: Instant,
+    schedule: Option<ScheduleFile>,
+}
+
+/// A token bucket shared by every part upload in the process.
+pub struct Throttle {
+    max_rate: Rate,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(max_rate: Rate) -> Self {
+        Throttle {
+            max_rate,
+            bucket: Mutex::new(Bucket {
+                rate: max_rate,
+                tokens: 0.0,
+                refilled: Instant::now(),
+                schedule: None,
+            }),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Throttle::new(Rate::Unlimited)
+    }
+
+    pub fn with_schedule(max_rate: Rate, path: &Path) -> std::result::Result<Self, Error> {
+        let schedule = Schedule::load(path)?;
+        let throttle = Throttle::new(max_rate);
+        throttle.bucket.lock().unwrap().schedule = Some(ScheduleFile {
+            path: path.to_owned(),
+            modified: ScheduleFile::modified(path),
+            checked: Instant::now(),
+            schedule,
+        });
+        Ok(throttle)
+    }
+
+    /// The limit in force right now.
+    pub fn rate(&self) -> Rate {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.update_rate(&mut bucket);
+        bucket.rate
+    }
+
+    fn update_rate(&self, bucket: &mut Bucket) {
+        let rate = match bucket.schedule {
+            Some(ref mut file) => {
+                file.refresh();
+                let time = Local::now().time();
+                file.schedule.rate_at(time).unwrap_or(self.max_rate)
+            }
+            None => self.max_rate,
+        };
+
+        if rate != bucket.rate {
+            log::info!("bandwidth limit is now {:?}", rate);
+            bucket.rate = rate;
+            bucket.tokens = 0.0;
+            bucket.refilled = Instant::now();
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long the caller must wait
+    /// before sending them. The bucket may go into debt so that concurrent
+    /// callers queue up behind each other.
+    pub fn reserve(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.update_rate(&mut bucket);
+
+        let rate = match bucket.rate {
+            Rate::Unlimited => return Duration::from_secs(0),
+            Rate::BytesPerSecond(rate) => rate as f64,
+        };
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.refilled = now;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate * MAX_BURST.as_secs_f64());
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+/// A body stream that waits for the shared throttle before yielding each chunk.
+pub struct Throttled<S> {
+    inner: S,
+    throttle: Arc<Throttle>,
+    delay: Option<(Delay, Bytes)>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, throttle: Arc<Throttle>) -> Self {
+        Throttled {
+            inner,
+            throttle,
+            delay: None,
+        }
+    }
+}
+
+impl<S> Stream for Throttled<S>
+where
+    S: Stream<Item = io::Result<Bytes>> + Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some((ref mut delay, _)) = self.delay {
+            if Pin::new(delay).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let (_, chunk) = self.delay.take().unwrap();
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let wait = self.throttle.reserve(chunk.len());
+                if wait == Duration::from_secs(0) {
+                    return Poll::Ready(Some(Ok(chunk)));
+                }
+
+                let mut delay = delay_for(wait);
+                if Pin::new(&mut delay).poll(cx).is_ready() {
+                    return Poll::Ready(Some(Ok(chunk)));
+                }
+                self.delay = Some((delay, chunk));
+                Poll::Pending
+            }
+            other => other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    fn rate(s: &str) -> Rate {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(rate("unlimited"), Rate::Unlimited);
+        assert_eq!(rate(" Unlimited "), Rate::Unlimited);
+        assert_eq!(rate("100/s"), Rate::BytesPerSecond(100));
+        assert_eq!(rate("100B/s"), Rate::BytesPerSecond(100));
+        assert_eq!(rate("50MB/s"), Rate::BytesPerSecond(50_000_000));
+        assert_eq!(rate("2kB/s"), Rate::BytesPerSecond(2000));
+        assert_eq!(rate("512KiB/s"), Rate::BytesPerSecond(512 * 1024));
+        assert_eq!(rate("1.5 MiB/s"), Rate::BytesPerSecond(3 * 512 * 1024));
+        assert_eq!(rate("1GiB/s"), Rate::BytesPerSecond(1 << 30));
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        for invalid in &["50MB", "MB/s", "10XB/s", "0/s", "0.1/s", "-1MB/s", "1..5MB/s"] {
+            assert!(invalid.parse::<Rate>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn windows_may_wrap_midnight() {
+        let day = Window {
+            start: time("09:00"),
+            end: time("17:00"),
+            rate: Rate::Unlimited,
+        };
+        assert!(day.contains(time("09:00")));
+        assert!(day.contains(time("16:59")));
+        assert!(!day.contains(time("17:00")));
+        assert!(!day.contains(time("08:59")));
+
+        let night = Window {
+            start: time("20:00"),
+            end: time("06:00"),
+            rate: Rate::Unlimited,
+        };
+        assert!(night.contains(time("23:59")));
+        assert!(night.contains(time("00:00")));
+        assert!(night.contains(time("05:59")));
+        assert!(!night.contains(time("06:00")));
+        assert!(!night.contains(time("12:00")));
+    }
+
+    #[test]
+    fn parses_schedules() {
+        let schedule: Schedule = "\
+            # full speed overnight\n\
+            20:00-06:00 unlimited\n\
+            \n\
+            12:00-13:00 1MB/s  # lunch\n\
+            00:00-23:59 2MB/s\n\
+            * 10MB/s\n"
+            .parse()
+            .unwrap();
+        assert_eq!(schedule.windows.len(), 3);
+        assert_eq!(schedule.default, Some(Rate::BytesPerSecond(10_000_000)));
+
+        // the first matching window wins
+        assert_eq!(schedule.rate_at(time("22:00")), Some(Rate::Unlimited));
+        assert_eq!(schedule.rate_at(time("12:30")), Some(Rate::BytesPerSecond(1_000_000)));
+        assert_eq!(schedule.rate_at(time("15:00")), Some(Rate::BytesPerSecond(2_000_000)));
+        assert_eq!(schedule.rate_at(time("23:59")), Some(Rate::Unlimited));
+    }
+
+    #[test]
+    fn schedules_without_a_default_leave_other_times_alone() {
+        let schedule: Schedule = "09:00-17:00 1MB/s".parse().unwrap();
+        assert_eq!(schedule.default, None);
+        assert_eq!(schedule.rate_at(time("10:00")), Some(Rate::BytesPerSecond(1_000_000)));
+        assert_eq!(schedule.rate_at(time("18:00")), None);
+        assert_eq!("".parse::<Schedule>().unwrap(), Schedule::default());
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for invalid in &[
+            "09:00-17:00",
+            "09:00-17:00 1MB/s extra",
+            "09:00 1MB/s",
+            "09:00-25:00 1MB/s",
+            "9am-5pm 1MB/s",
+            "09:00-17:00 fast",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn unlimited_throttles_never_wait() {
+        let throttle = Throttle::unlimited();
+        assert_eq!(throttle.reserve(1 << 30), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn throttles_wait_for_bytes_beyond_the_rate() {
+        let throttle = Throttle::new(Rate::BytesPerSecond(1000));
+        // the bucket starts empty, so a second's worth waits about a second
+        let wait = throttle.reserve(1000);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1), "{:?}", wait);
+        // and callers after it queue up behind
+        let wait = throttle.reserve(500);
+        assert!(wait > Duration::from_millis(1400), "{:?}", wait);
+    }
+}
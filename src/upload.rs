@@ -1,184 +1,588 @@
+use crate::app::CancelToken;
+use crate::backend::{self, Backend, Body, CompletedPart, MAX_PART_NUMBER};
 use crate::error::Error;
+use crate::progress::Metered;
 use crate::result::Result;
+use crate::state::{Part, Segment};
+use crate::throttle::{self, Throttle, Throttled};
+use bytes::Bytes;
 use glob;
+use regex::Regex;
 use rusoto_core::ByteStream;
-use rusoto_s3::{
-    AbortMultipartUploadRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
-    CompletedPart, CreateMultipartUploadRequest, S3Client, UploadPartRequest, S3,
-};
 use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::fs;
-use tokio::io::{reader_stream, AsyncReadExt, BufReader};
+use tokio::io::{reader_stream, AsyncReadExt, BufReader, SeekFrom, Take};
 
-static DEFAULT_BUFFER_SIZE: usize = 1000;
+static DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
 
-pub fn get_parts(src: &str) -> std::result::Result<Vec<PathBuf>, Error> {
-    let mut parts = vec![];
+/// Parts no larger than this are read into memory once, hashed and uploaded
+/// from the buffer. Larger parts are hashed, ideally while the part before
+/// uploads, and then streamed from disk.
+pub static DEFAULT_BUFFER_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// The bytes of a part ready to be sent, along with its length and base64 md5.
+pub struct PartData {
+    pub len: u64,
+    pub md5: String,
+    pub body: PartBody,
+}
+
+pub enum PartBody {
+    Memory(Vec<u8>),
+    File(PathBuf),
+    Pieces(Vec<Piece>),
+    /// A temporary file holding the part, already unlinked.
+    Spool(std::fs::File),
+}
+
+/// Some of the bytes of a part made of more than one file, or of data that
+/// isn't in any file, like archive headers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece {
+    Bytes(Vec<u8>),
+    File(Segment),
+}
+
+impl Piece {
+    pub fn len(&self) -> u64 {
+        match self {
+            Piece::Bytes(bytes) => bytes.len() as u64,
+            Piece::File(segment) => segment.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// How a part body is sent: the throttle shared by all uploads, a counter
+/// of the bytes sent so far for this attempt, and the token whose interrupt
+/// cuts the body short.
+#[derive(Clone)]
+pub struct Transfer {
+    pub throttle: Arc<Throttle>,
+    pub sent: Arc<AtomicU64>,
+    pub cancel: CancelToken,
+}
+
+impl Transfer {
+    pub fn unlimited() -> Self {
+        Transfer {
+            throttle: Arc::new(Throttle::unlimited()),
+            sent: Arc::new(AtomicU64::new(0)),
+            cancel: CancelToken::new(),
+        }
+    }
+
+    fn wrap<S>(&self, stream: S) -> ByteStream
+    where
+        S: futures::Stream<Item = std::io::Result<Bytes>> + Send + Sync + Unpin + 'static,
+    {
+        let cancel = self.cancel.clone();
+        let stream = futures::stream::StreamExt::map(stream, move |chunk| {
+            if cancel.is_interrupted() {
+                return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "upload interrupted"));
+            }
+            chunk
+        });
+        let throttled = Throttled::new(stream, self.throttle.clone());
+        ByteStream::new(Metered::new(throttled, self.sent.clone()))
+    }
+}
+
+type PieceStream = Box<dyn futures::Stream<Item = std::io::Result<Bytes>> + Send + Sync + Unpin>;
+
+impl PartData {
+    pub async fn into_byte_stream(self, transfer: &Transfer) -> Result<ByteStream> {
+        match self.body {
+            PartBody::Memory(buffer) => {
+                let mut buffer = Bytes::from(buffer);
+                let mut chunks = vec![];
+                while !buffer.is_empty() {
+                    let len = cmp::min(buffer.len(), throttle::CHUNK_SIZE);
+                    chunks.push(Ok(buffer.split_to(len)));
+                }
+                let stream = futures::stream::iter(chunks);
+                Ok(transfer.wrap(stream))
+            }
+            PartBody::File(path) => {
+                let f = fs::File::open(&path)
+                    .await
+                    .map_err(|err| format!("error opening part file for upload: {}", err))?;
+                let stream = reader_stream(BufReader::new(f));
+                Ok(transfer.wrap(stream))
+            }
+            PartBody::Pieces(pieces) => {
+                let mut streams: Vec<PieceStream> = vec![];
+                for piece in pieces {
+                    streams.push(match piece {
+                        Piece::Bytes(bytes) => {
+                            Box::new(futures::stream::once(futures::future::ready(Ok(Bytes::from(bytes)))))
+                        }
+                        Piece::File(ref segment) => {
+                            Box::new(reader_stream(BufReader::new(open_segment(segment).await?)))
+                        }
+                    });
+                }
+                let stream = futures::stream::StreamExt::flatten(futures::stream::iter(streams));
+                Ok(transfer.wrap(stream))
+            }
+            PartBody::Spool(f) => {
+                let stream = reader_stream(BufReader::new(fs::File::from_std(f)));
+                Ok(transfer.wrap(stream))
+            }
+        }
+    }
+}
+
+/// How part files are put in order.
+#[derive(Debug, Clone, Default)]
+pub enum Order {
+    /// By name, comparing runs of digits as numbers, so `part2` comes
+    /// before `part10`.
+    #[default]
+    Natural,
+    /// By modification time, oldest first, and by name for equal times.
+    Mtime,
+    /// By the number the regex captures from each file name, its first
+    /// group or else the whole match, which becomes the part's number.
+    Regex(Regex),
+    /// As listed in a file instead of the pattern. See `read_part_list`.
+    List(PathBuf),
+}
+
+impl FromStr for Order {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Some(regex) = s.strip_prefix("regex:") {
+            let regex = Regex::new(regex).map_err(|err| format!("invalid part order regex: {}", err))?;
+            return Ok(Order::Regex(regex));
+        }
+        if let Some(list) = s.strip_prefix("list:") {
+            return Ok(Order::List(PathBuf::from(list)));
+        }
+        match s {
+            "natural" => Ok(Order::Natural),
+            "mtime" => Ok(Order::Mtime),
+            other => Err(format!(
+                "unknown part order {:?}, expected natural, mtime, regex:<regex> or list:<file>",
+                other
+            )
+            .into()),
+        }
+    }
+}
+
+/// The part files matching a pattern, or listed in the order's file,
+/// numbered and in part order. Parts are numbered in turn from 1, except by
+/// the regex order, which numbers them with what it captures, and by lists
+/// that number them. Numbers may have gaps, but must be unique and within
+/// the range S3 accepts.
+pub fn get_parts(src: &str, order: &Order) -> std::result::Result<Vec<Part>, Error> {
+    if let Order::List(ref list) = order {
+        return read_part_list(list);
+    }
+
+    let mut paths = vec![];
     for entry in glob::glob(src).expect("read dir") {
         let f = entry?;
         if f.is_file() {
-            parts.push(f);
+            paths.push(f);
         }
     }
 
-    parts.sort_by(|a, b| compare_file_names(a, b));
+    paths.sort_by(|a, b| compare_file_names(a, b));
+
+    let numbered: Vec<(i64, PathBuf)> = match order {
+        Order::Mtime => {
+            let mut timed = vec![];
+            for path in paths {
+                let modified = std::fs::metadata(&path)
+                    .and_then(|metadata| metadata.modified())
+                    .map_err(|err| format!("error reading modification time of {:?}: {}", path, err))?;
+                timed.push((modified, path));
+            }
+            // stable, so equal times stay in name order
+            timed.sort_by_key(|(modified, _)| *modified);
+            (1..).zip(timed.into_iter().map(|(_, path)| path)).collect()
+        }
+        Order::Regex(ref regex) => {
+            let mut numbered = vec![];
+            for path in paths {
+                numbered.push((part_number(regex, &path)?, path));
+            }
+            numbered.sort_by_key(|(number, _)| *number);
+            numbered
+        }
+        Order::Natural | Order::List(_) => (1..).zip(paths).collect(),
+    };
 
+    let mut parts = vec![];
+    for (number, path) in numbered {
+        parts.push(Part::new(number, path.to_str().ok_or("error handling non utf8 path")?.to_owned()));
+    }
+    check_part_numbers(&parts)?;
     Ok(parts)
 }
 
-fn compare_file_names<A: AsRef<Path>, B: AsRef<Path>>(a: A, b: B) -> cmp::Ordering {
-    return a.as_ref().partial_cmp(b.as_ref()).unwrap();
+/// The parts listed in a file, one path per line in part order, or one
+/// `<number>\t<path>` per line to number them explicitly.
+pub fn read_part_list(list: &Path) -> std::result::Result<Vec<Part>, Error> {
+    let text = std::fs::read_to_string(list).map_err(|err| format!("error reading part list {:?}: {}", list, err))?;
+    let lines: Vec<&str> = text.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+
+    let explicit = |line: &str| {
+        line.split_once('\t')
+            .and_then(|(number, path)| Some((number.parse::<i64>().ok()?, path.to_owned())))
+    };
+    let mut parts = match lines.first() {
+        Some(first) if explicit(first).is_some() => {
+            let mut parts = vec![];
+            for line in lines {
+                let (number, path) =
+                    explicit(line).ok_or_else(|| format!("part list {:?} line {:?} has no part number", list, line))?;
+                parts.push(Part::new(number, path));
+            }
+            parts
+        }
+        _ => (1..).zip(lines).map(|(number, path)| Part::new(number, path.to_owned())).collect(),
+    };
+
+    parts.sort_by_key(|part| part.number);
+    check_part_numbers(&parts)?;
+    Ok(parts)
 }
 
-pub async fn start_upload(s3client: &S3Client, bucket: &str, key: &str) -> Result<String> {
-    let multipart_upload = s3client
-        .create_multipart_upload(CreateMultipartUploadRequest {
-            acl: None,
-            bucket: bucket.to_owned(),
-            key: key.to_owned(),
-            ..Default::default()
-        })
-        .await
-        .map_err(|err| format!("error creating multipart upload request: {}", err))?;
+/// Check part numbers, sorted, are unique and within the range S3 accepts.
+fn check_part_numbers(parts: &[Part]) -> std::result::Result<(), Error> {
+    for part in parts {
+        if part.number < 1 || part.number > MAX_PART_NUMBER {
+            return Err(format!(
+                "{} has part number {}, which must be between 1 and {}",
+                part.path, part.number, MAX_PART_NUMBER
+            )
+            .into());
+        }
+    }
+    for pair in parts.windows(2) {
+        if pair[0].number == pair[1].number {
+            return Err(format!("{} and {} both have part number {}", pair[0].path, pair[1].path, pair[0].number).into());
+        }
+    }
+    Ok(())
+}
 
-    let upload_id = multipart_upload
-        .upload_id
-        .ok_or_else(|| format!("no upload id returned by create multipart upload request"))?;
+/// Pack whole-file parts, in order, into parts of `target` bytes, the last
+/// one smaller, so files too small to be parts can still make an object.
+/// A file may be split across two or more parts.
+pub fn pack(files: &[Part], target: u64) -> std::result::Result<Vec<Part>, Error> {
+    if target == 0 {
+        return Err("packed parts need a size of at least one byte".into());
+    }
 
-    Ok(upload_id)
+    let mut parts = vec![];
+    let mut segments = vec![];
+    let mut filled = 0;
+    for file in files {
+        let len = std::fs::metadata(&file.path)
+            .map_err(|err| format!("error reading size of {}: {}", file.path, err))?
+            .len();
+        let mut offset = 0;
+        loop {
+            // a file with nothing left for a new part stays in the full one
+            if filled == target && offset < len {
+                parts.push(Part::packed(parts.len() as i64 + 1, std::mem::take(&mut segments)));
+                filled = 0;
+            }
+            let take = cmp::min(len - offset, target - filled);
+            segments.push(Segment {
+                path: file.path.to_owned(),
+                offset,
+                len: take,
+            });
+            offset += take;
+            filled += take;
+            if offset == len {
+                break;
+            }
+        }
+    }
+    if !segments.is_empty() {
+        parts.push(Part::packed(parts.len() as i64 + 1, segments));
+    }
+
+    check_part_numbers(&parts)?;
+    Ok(parts)
+}
+
+/// The number a regex captures from a part's file name.
+fn part_number(regex: &Regex, part: &Path) -> std::result::Result<i64, Error> {
+    let name = part.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let captures = regex
+        .captures(&name)
+        .ok_or_else(|| format!("part file name {:?} doesn't match {}", name, regex))?;
+    let number = captures.get(1).or_else(|| captures.get(0)).map(|found| found.as_str()).unwrap_or("");
+    number
+        .parse()
+        .map_err(|_| format!("{:?} captured from {:?} isn't a part number", number, name).into())
+}
+
+/// Compare paths by name, with runs of digits compared as numbers.
+fn compare_file_names<A: AsRef<Path>, B: AsRef<Path>>(a: A, b: B) -> cmp::Ordering {
+    let a = a.as_ref().to_string_lossy();
+    let b = b.as_ref().to_string_lossy();
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+
+    while let (Some(&x), Some(&y)) = (a.first(), b.first()) {
+        let ordering = if x.is_ascii_digit() && y.is_ascii_digit() {
+            let (x, rest_a) = split_digits(a);
+            let (y, rest_b) = split_digits(b);
+            a = rest_a;
+            b = rest_b;
+            // leading zeros don't change a number, but break ties so
+            // different names never compare equal
+            let (short_x, short_y) = (trim_zeros(x), trim_zeros(y));
+            short_x
+                .len()
+                .cmp(&short_y.len())
+                .then_with(|| short_x.cmp(short_y))
+                .then_with(|| x.len().cmp(&y.len()))
+        } else {
+            a = &a[1..];
+            b = &b[1..];
+            x.cmp(&y)
+        };
+        if ordering != cmp::Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
 }
 
-pub async fn upload_or_abort<V: IntoIterator<Item = PathBuf>>(
-    s3client: &S3Client,
+fn split_digits(s: &[u8]) -> (&[u8], &[u8]) {
+    let end = s.iter().position(|c| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let start = digits.iter().position(|&c| c != b'0').unwrap_or(digits.len());
+    &digits[start..]
+}
+
+pub async fn upload_or_abort<B: Backend, V: IntoIterator<Item = PathBuf>>(
+    backend: &B,
     parts: V,
     bucket: &str,
     key: &str,
 ) -> std::result::Result<(), Error> {
-    let upload_id = start_upload(s3client, bucket, key).await?;
+    let upload_id = backend.create_upload(bucket, key).await?;
 
-    match upload(s3client, parts, bucket, key, &upload_id).await {
+    match upload(backend, parts, bucket, key, &upload_id).await {
         Ok(()) => Ok(()),
         Err(err) => {
-            println!("aborting upload");
-            abort_upload(s3client, bucket, key, &upload_id).await?;
+            log::info!("aborting upload");
+            backend.abort_upload(bucket, key, &upload_id).await?;
 
             Err(err)
         }
     }
 }
 
-pub async fn abort_upload(
-    s3client: &S3Client,
+pub async fn upload<B: Backend, V: IntoIterator<Item = PathBuf>>(
+    backend: &B,
+    parts: V,
     bucket: &str,
     key: &str,
     upload_id: &str,
-) -> Result<()> {
-    s3client
-        .abort_multipart_upload(AbortMultipartUploadRequest {
-            bucket: bucket.to_owned(),
-            key: key.to_owned(),
-            upload_id: upload_id.to_owned(),
-            ..Default::default()
-        })
-        .await
-        .map_err(|err| format!("error aborting upload: {:?}", err))?;
+) -> std::result::Result<(), Error> {
+    let completed_parts = upload_parts(backend, parts, bucket, key, upload_id).await?;
+
+    backend
+        .complete_upload(bucket, key, upload_id, completed_parts)
+        .await?;
 
     Ok(())
 }
 
-pub async fn upload<V: IntoIterator<Item = PathBuf>>(
-    s3client: &S3Client,
+pub async fn upload_parts<B: Backend, V: IntoIterator<Item = PathBuf>>(
+    backend: &B,
     parts: V,
     bucket: &str,
     key: &str,
     upload_id: &str,
-) -> std::result::Result<(), Error> {
-    let completed_multipart_upload =
-        upload_parts(s3client, parts, bucket, key, upload_id).await?;
+) -> Result<Vec<CompletedPart>> {
+    let mut uploads = vec![];
+    let transfer = Transfer::unlimited();
 
-    complete_upload(s3client, bucket, key, upload_id, completed_multipart_upload).await?;
+    for (part_number, part) in (1..).zip(parts) {
+        log::info!("uploading part {} {:?}", part_number, part);
+        let data = read_part(&part, None, DEFAULT_BUFFER_THRESHOLD).await?;
+        let etag = upload_part(backend, data, &transfer, bucket, key, upload_id, part_number).await?;
+        uploads.push(CompletedPart { part_number, etag });
+    }
 
-    Ok(())
+    Ok(uploads)
 }
 
-pub async fn complete_upload(s3client: &S3Client, bucket: &str, key: &str, upload_id: &str, completed_multipart_upload: CompletedMultipartUpload) -> Result<()> {
-    println!("completing upload");
-    s3client
-        .complete_multipart_upload(CompleteMultipartUploadRequest {
-            bucket: bucket.to_owned(),
-            key: key.to_owned(),
-            multipart_upload: Some(completed_multipart_upload),
-            upload_id: upload_id.to_owned(),
-            ..Default::default()
-        })
-        .await
-        .map_err(|err| format!("error completing multipart upload: {}", err))?;
-    
-    Ok(())
+/// Prepare a part for upload, whether a whole file or made of pieces.
+pub async fn load_part(part: &Part, cached: Option<(u64, String)>, buffer_threshold: u64) -> Result<PartData> {
+    match pieces(part) {
+        None => read_part(Path::new(&part.path), cached, buffer_threshold).await,
+        Some(pieces) => read_pieces(&part.path, pieces, cached, buffer_threshold).await,
+    }
 }
 
-pub async fn upload_parts<V: IntoIterator<Item = PathBuf>>(
-    s3client: &S3Client,
-    parts: V,
-    bucket: &str,
-    key: &str,
-    upload_id: &str,
-) -> Result<CompletedMultipartUpload> {
-    let mut uploads = vec![];
+/// The pieces a part is made of, or `None` for a part that is a whole file.
+pub fn pieces(part: &Part) -> Option<Vec<Piece>> {
+    if let Some(ref slice) = part.archive {
+        return Some(slice.pieces());
+    }
+    if part.segments.is_empty() {
+        return None;
+    }
+    Some(part.segments.iter().cloned().map(Piece::File).collect())
+}
 
-    let mut part_number = 1;
+/// Prepare a part made of pieces for upload, as `read_part` does a single
+/// file. `name` describes the part in errors.
+pub async fn read_pieces(
+    name: &str,
+    pieces: Vec<Piece>,
+    cached: Option<(u64, String)>,
+    buffer_threshold: u64,
+) -> Result<PartData> {
+    let len: u64 = pieces.iter().map(Piece::len).sum();
+
+    if len <= buffer_threshold {
+        let mut buffer = Vec::with_capacity(len as usize);
+        for piece in &pieces {
+            match piece {
+                Piece::Bytes(bytes) => buffer.extend_from_slice(bytes),
+                Piece::File(segment) => {
+                    let count = open_segment(segment).await?.read_to_end(&mut buffer).await?;
+                    if count as u64 != segment.len {
+                        return Err(format!("{} changed since it was packed", segment.path).into());
+                    }
+                }
+            }
+        }
+        let md5 = base64::encode(md5::compute(&buffer).0);
 
-    for part in parts {
-        log::info!("uploading part {} {:?}", part_number, part);
-        uploads.push(upload_part(s3client, &part, bucket, key, upload_id, part_number).await?);
+        log::debug!("buffered {} bytes as {} for part {}", buffer.len(), md5, name);
 
-        part_number += 1;
+        if let Some((cached_len, cached_md5)) = cached {
+            if cached_len != len || cached_md5 != md5 {
+                return Err(format!("part {} changed since it was hashed", name).into());
+            }
+        }
+
+        return Ok(PartData {
+            len,
+            md5,
+            body: PartBody::Memory(buffer),
+        });
     }
 
-    Ok(CompletedMultipartUpload {
-        parts: Some(uploads),
+    let (len, md5) = match cached {
+        Some((cached_len, cached_md5)) if cached_len == len => (cached_len, cached_md5),
+        Some(_) => return Err(format!("part {} changed since it was hashed", name).into()),
+        None => digest_pieces(&pieces).await?,
+    };
+
+    Ok(PartData {
+        len,
+        md5,
+        body: PartBody::Pieces(pieces),
     })
 }
 
-pub async fn upload_part(
-    s3client: &S3Client,
+/// Prepare a part for upload, reading the file as few times as possible.
+///
+/// Small parts are read into memory in a single pass. Large parts are
+/// streamed from disk, which needs a separate hashing pass unless the md5
+/// is already known from an earlier attempt or from hashing the part ahead.
+pub async fn read_part(
     part: &Path,
-    bucket: &str,
-    key: &str,
-    upload_id: &str,
-    part_number: i64,
-) -> Result<CompletedPart> {
-    let (len, hash) = digest_file(&part).await?;
-    let body = fs::File::open(&part)
-        .await
-        .map_err(|err| format!("error opening part file for upload: {}", err))?;
-    let bufreader = BufReader::new(body);
-    let stream = reader_stream(bufreader);
-    let bytestream = ByteStream::new(stream);
-
-    let upload = s3client
-        .upload_part(UploadPartRequest {
-            body: Some(bytestream),
-            bucket: bucket.to_string(),
-            content_md5: Some(hash),
-            content_length: Some(len as i64),
-            key: key.to_string(),
-            part_number: part_number,
-            upload_id: upload_id.to_string(),
-            ..Default::default()
-        })
+    cached: Option<(u64, String)>,
+    buffer_threshold: u64,
+) -> Result<PartData> {
+    let len = fs::metadata(part)
         .await
-        .map_err(|err| format!("error uploading part: {}", err))?;
+        .map_err(|err| format!("error reading part file metadata: {}", err))?
+        .len();
+
+    if len <= buffer_threshold {
+        let buffer = fs::read(part)
+            .await
+            .map_err(|err| format!("error reading part file: {}", err))?;
+        let md5 = base64::encode(md5::compute(&buffer).0);
+
+        log::debug!("buffered {} bytes as {} for part {:?}", buffer.len(), md5, part);
+
+        if let Some((cached_len, cached_md5)) = cached {
+            if cached_len != buffer.len() as u64 || cached_md5 != md5 {
+                return Err(format!("part file {:?} changed since it was hashed", part).into());
+            }
+        }
 
-    let part = CompletedPart {
-        e_tag: upload.e_tag,
-        part_number: Some(part_number),
+        return Ok(PartData {
+            len: buffer.len() as u64,
+            md5,
+            body: PartBody::Memory(buffer),
+        });
+    }
+
+    let (len, md5) = match cached {
+        Some((cached_len, cached_md5)) => {
+            if cached_len != len {
+                return Err(format!("part file {:?} changed since it was hashed", part).into());
+            }
+            (cached_len, cached_md5)
+        }
+        None => digest_file(part).await?,
     };
 
-    log::debug!("uploaded {:?}", part);
+    Ok(PartData {
+        len,
+        md5,
+        body: PartBody::File(part.to_owned()),
+    })
+}
 
-    Ok(part)
+/// Open a file at the start of a segment, reading no further than its end.
+pub async fn open_segment(segment: &Segment) -> Result<Take<fs::File>> {
+    let mut f = fs::File::open(&segment.path)
+        .await
+        .map_err(|err| format!("error opening packed file {}: {}", segment.path, err))?;
+    f.seek(SeekFrom::Start(segment.offset)).await?;
+    Ok(f.take(segment.len))
+}
+
+/// Send a part through the throttle and progress meter, returning its etag.
+pub async fn upload_part<B: Backend>(
+    backend: &B,
+    data: PartData,
+    transfer: &Transfer,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    part_number: i64,
+) -> Result<String> {
+    let len = data.len;
+    let md5 = data.md5.to_owned();
+    let stream = data.into_byte_stream(transfer).await?;
+
+    backend
+        .upload_part(bucket, key, upload_id, part_number, Body { stream, len, md5 })
+        .await
 }
 
 pub async fn digest_file(part: &Path) -> Result<(u64, String)> {
@@ -186,8 +590,7 @@ pub async fn digest_file(part: &Path) -> Result<(u64, String)> {
         .await
         .map_err(|err| format!("error opening part file for hashing: {}", err))?;
     let mut digest = md5::Context::new();
-    let mut buffer = vec![];
-    buffer.resize(DEFAULT_BUFFER_SIZE, 0);
+    let mut buffer = vec![0; DEFAULT_BUFFER_SIZE];
     let mut len = 0;
 
     loop {
@@ -207,3 +610,300 @@ pub async fn digest_file(part: &Path) -> Result<(u64, String)> {
 
     Ok((len, b64hash))
 }
+
+/// The size and base64 md5 of a part made of these pieces.
+pub async fn digest_pieces(pieces: &[Piece]) -> Result<(u64, String)> {
+    let mut digest = md5::Context::new();
+    let mut buffer = vec![0; DEFAULT_BUFFER_SIZE];
+    let mut len = 0;
+
+    for piece in pieces {
+        let segment = match piece {
+            Piece::Bytes(bytes) => {
+                digest.consume(bytes);
+                len += bytes.len() as u64;
+                continue;
+            }
+            Piece::File(segment) => segment,
+        };
+        let mut f = open_segment(segment).await?;
+        let mut read = 0;
+        loop {
+            let count = f.read(&mut buffer[..]).await?;
+            if count == 0 {
+                break;
+            }
+            read += count as u64;
+            digest.consume(&buffer[0..count]);
+        }
+        if read != segment.len {
+            return Err(format!("{} changed since it was packed", segment.path).into());
+        }
+        len += read;
+    }
+    let hash: [u8; 16] = digest.compute().into();
+    let b64hash = base64::encode(hash);
+
+    log::debug!("hashed {} bytes as {} for {} pieces", len, b64hash, pieces.len());
+
+    Ok((len, b64hash))
+}
+
+/// The etag S3 will give the object made from these parts, if they have all
+/// been hashed.
+pub fn composite_etag(parts: &[Part]) -> Option<String> {
+    let mut md5s = vec![];
+    for part in parts {
+        let md5 = base64::decode(part.md5.as_ref()?).ok()?;
+        let mut digest = [0; 16];
+        if md5.len() != digest.len() {
+            return None;
+        }
+        digest.copy_from_slice(&md5);
+        md5s.push(digest);
+    }
+    Some(backend::multipart_etag(&md5s))
+}
+
+/// A writer counting and hashing what goes through it.
+pub(crate) struct Hashing<W> {
+    pub inner: W,
+    pub digest: md5::Context,
+    pub len: u64,
+}
+
+impl<W: Write> Hashing<W> {
+    pub fn new(inner: W) -> Self {
+        Hashing {
+            inner,
+            digest: md5::Context::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.digest.consume(&buf[..count]);
+        self.len += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) fn copy_pieces<W: Write>(pieces: &[Piece], writer: &mut W) -> io::Result<()> {
+    for piece in pieces {
+        let segment = match piece {
+            Piece::Bytes(bytes) => {
+                writer.write_all(bytes)?;
+                continue;
+            }
+            Piece::File(segment) => segment,
+        };
+        let mut f = File::open(&segment.path)
+            .map_err(|err| io::Error::new(err.kind(), format!("error opening {}: {}", segment.path, err)))?;
+        f.seek(SeekFrom::Start(segment.offset))?;
+        if io::copy(&mut f.take(segment.len), writer)? != segment.len {
+            let msg = format!("{} is shorter than when the part was made", segment.path);
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
+        }
+    }
+    Ok(())
+}
+
+/// A new temporary file, already unlinked so it goes when closed.
+pub(crate) fn spool() -> io::Result<File> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "s3mu-{}-{}.spool",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|err| io::Error::new(err.kind(), format!("error creating spool file {:?}: {}", path, err)))?;
+    std::fs::remove_file(&path)?;
+    Ok(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    fn numbers(parts: &[Part]) -> Vec<i64> {
+        parts.iter().map(|part| part.number).collect()
+    }
+
+    fn sorted(names: &[&str]) -> Vec<String> {
+        let mut names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        names.sort_by(|a, b| compare_file_names(a, b));
+        names
+    }
+
+    #[test]
+    fn natural_order_compares_digits_as_numbers() {
+        assert_eq!(
+            sorted(&["part10", "part2", "part1", "part-a", "part"]),
+            vec!["part", "part-a", "part1", "part2", "part10"]
+        );
+        assert_eq!(sorted(&["a10b2", "a10b10", "a9b20"]), vec!["a9b20", "a10b2", "a10b10"]);
+    }
+
+    #[test]
+    fn natural_order_breaks_ties_on_leading_zeros() {
+        assert_eq!(sorted(&["part010", "part9", "part10", "part0010"]), vec!["part9", "part10", "part010", "part0010"]);
+        assert_eq!(compare_file_names("p01x", "p1y"), cmp::Ordering::Greater);
+        assert_eq!(compare_file_names("p00", "p0"), cmp::Ordering::Greater);
+        assert_eq!(compare_file_names("p007", "p007"), cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn parses_orders() {
+        assert!(matches!("natural".parse(), Ok(Order::Natural)));
+        assert!(matches!("mtime".parse(), Ok(Order::Mtime)));
+        match "regex:part(\\d+)".parse() {
+            Ok(Order::Regex(regex)) => assert_eq!(regex.as_str(), "part(\\d+)"),
+            other => panic!("unexpected {:?}", other),
+        }
+        match "list:parts.txt".parse() {
+            Ok(Order::List(path)) => assert_eq!(path, PathBuf::from("parts.txt")),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!("regex:(".parse::<Order>().is_err());
+        assert!("size".parse::<Order>().is_err());
+    }
+
+    #[test]
+    fn numbers_parts_with_regex_captures() {
+        let regex = Regex::new("part(\\d+)").unwrap();
+        assert_eq!(part_number(&regex, Path::new("dir/part007.bin")).unwrap(), 7);
+        assert!(part_number(&regex, Path::new("dir/chunk1")).is_err());
+        let whole = Regex::new("\\d+").unwrap();
+        assert_eq!(part_number(&whole, Path::new("12.bin")).unwrap(), 12);
+    }
+
+    #[test]
+    fn part_numbers_may_have_gaps() {
+        let parts = vec![Part::new(1, "a".into()), Part::new(3, "b".into()), Part::new(MAX_PART_NUMBER, "c".into())];
+        assert!(check_part_numbers(&parts).is_ok());
+        assert!(check_part_numbers(&[]).is_ok());
+    }
+
+    #[test]
+    fn part_numbers_must_be_unique_and_in_range() {
+        let duplicate = vec![Part::new(1, "a".into()), Part::new(2, "b".into()), Part::new(2, "c".into())];
+        let err = check_part_numbers(&duplicate).unwrap_err().to_string();
+        assert!(err.contains("b and c both have part number 2"), "{}", err);
+        assert!(check_part_numbers(&[Part::new(0, "a".into())]).is_err());
+        assert!(check_part_numbers(&[Part::new(MAX_PART_NUMBER + 1, "a".into())]).is_err());
+    }
+
+    #[test]
+    fn reads_part_lists_in_order() {
+        let dir = scratch("upload-list-plain");
+        let list = dir.join("parts");
+        std::fs::write(&list, "b\n\n  a  \nc\n").unwrap();
+        let parts = read_part_list(&list).unwrap();
+        assert_eq!(numbers(&parts), vec![1, 2, 3]);
+        let paths: Vec<&str> = parts.iter().map(|part| part.path.as_str()).collect();
+        assert_eq!(paths, vec!["b", "a", "c"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_numbered_part_lists() {
+        let dir = scratch("upload-list-numbered");
+        let list = dir.join("parts");
+        std::fs::write(&list, "5\tfive\n2\ttwo\n9\tnine\n").unwrap();
+        let parts = read_part_list(&list).unwrap();
+        assert_eq!(numbers(&parts), vec![2, 5, 9]);
+        assert_eq!(parts[0].path, "two");
+
+        std::fs::write(&list, "1\tone\n1\tagain\n").unwrap();
+        assert!(read_part_list(&list).is_err());
+        std::fs::write(&list, "1\tone\ntwo\n").unwrap();
+        assert!(read_part_list(&list).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Write files of the given sizes and pack them into parts of `target`
+    /// bytes, giving each part's segments as (file index, offset, len).
+    fn packed(name: &str, sizes: &[u64], target: u64) -> Vec<Vec<(usize, u64, u64)>> {
+        let dir = scratch(name);
+        let mut files = vec![];
+        for (i, size) in sizes.iter().enumerate() {
+            let path = dir.join(format!("file{}", i));
+            std::fs::write(&path, vec![i as u8; *size as usize]).unwrap();
+            files.push(Part::new(i as i64 + 1, path.to_str().unwrap().to_owned()));
+        }
+        let parts = pack(&files, target).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(numbers(&parts), (1..=parts.len() as i64).collect::<Vec<_>>());
+        parts
+            .iter()
+            .map(|part| {
+                assert_eq!(part.path, part.segments[0].path);
+                part.segments
+                    .iter()
+                    .map(|segment| {
+                        let file = files.iter().position(|file| file.path == segment.path).unwrap();
+                        (file, segment.offset, segment.len)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn packs_small_files_together() {
+        assert_eq!(
+            packed("upload-pack-small", &[3, 4, 2], 10),
+            vec![vec![(0, 0, 3), (1, 0, 4), (2, 0, 2)]]
+        );
+        assert_eq!(
+            packed("upload-pack-exact", &[5, 5, 1], 10),
+            vec![vec![(0, 0, 5), (1, 0, 5)], vec![(2, 0, 1)]]
+        );
+    }
+
+    #[test]
+    fn packs_files_straddling_part_boundaries() {
+        assert_eq!(
+            packed("upload-pack-straddle", &[7, 7, 2], 5),
+            vec![
+                vec![(0, 0, 5)],
+                vec![(0, 5, 2), (1, 0, 3)],
+                vec![(1, 3, 4), (2, 0, 1)],
+                vec![(2, 1, 1)],
+            ]
+        );
+        // a file spanning whole parts, ending exactly on a boundary
+        assert_eq!(
+            packed("upload-pack-span", &[1, 9, 3], 5),
+            vec![vec![(0, 0, 1), (1, 0, 4)], vec![(1, 4, 5)], vec![(2, 0, 3)]]
+        );
+    }
+
+    #[test]
+    fn packs_empty_files_into_the_current_part() {
+        assert_eq!(
+            packed("upload-pack-empty", &[5, 0, 2], 5),
+            vec![vec![(0, 0, 5), (1, 0, 0)], vec![(2, 0, 2)]]
+        );
+    }
+
+    #[test]
+    fn packing_needs_a_part_size() {
+        assert!(pack(&[], 0).is_err());
+        assert!(pack(&[], 5).unwrap().is_empty());
+    }
+}
@@ -1,10 +1,16 @@
+use async_trait::async_trait;
+use fs2::FileExt;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::path::Path;
-use tokio::fs::{self, OpenOptions};
+use tokio::fs;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
 
+pub mod sqlite;
+
+pub use sqlite::SqliteStore;
+
 pub type Result<T> = std::result::Result<T, WalError>;
 
 #[derive(Debug)]
@@ -32,6 +38,29 @@ impl<A: Serialize + DeserializeOwned> WalEntry<A> {
     }
 }
 
+/// Take an exclusive advisory lock on an open file, held until the file is
+/// closed, so two processes can't append to the same log.
+pub fn lock_exclusive(file: &std::fs::File, path: &Path) -> Result<()> {
+    file.try_lock_exclusive().map_err(|err| {
+        if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+            WalError::LoadError(format!("log {:?} is in use by another process", path))
+        } else {
+            WalError::LoadError(format!("error locking log {:?}: {}", path, err))
+        }
+    })
+}
+
+/// Where a log of entries is kept.
+#[async_trait]
+pub trait Storage<A>: Send {
+    /// The entries logged so far, oldest first.
+    fn entries(&self) -> &[WalEntry<A>];
+
+    /// Durably record an entry, along with the name of the state it leads to.
+    async fn append(&mut self, entry: WalEntry<A>, state: &'static str) -> Result<()>;
+}
+
+/// A log kept as a file of JSON lines.
 pub struct Wal<Action> {
     pub stream: BufStream<fs::File>,
     pub entries: Vec<WalEntry<Action>>,
@@ -39,15 +68,16 @@ pub struct Wal<Action> {
 
 impl<A: Serialize + DeserializeOwned + 'static> Wal<A> {
     pub async fn open(file_path: &Path) -> Result<Self> {
-        let f = OpenOptions::new()
+        let f = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(file_path)
-            .await
             .map_err(|err| WalError::LoadError(format!("error opening log: {}", err)))?;
+        lock_exclusive(&f, file_path)?;
 
-        let stream = BufStream::new(f);
+        let stream = BufStream::new(fs::File::from_std(f));
 
         let mut entries = vec![];
 
@@ -85,6 +115,18 @@ impl<A: Serialize + DeserializeOwned + 'static> Wal<A> {
             .await
             .map_err(|err| WalError::AppendError(format!("error flushing log: {}", err)))?;
 
+        self.entries.push(entry);
         Ok(())
     }
 }
+
+#[async_trait]
+impl<A: Serialize + DeserializeOwned + Send + Sync + 'static> Storage<A> for Wal<A> {
+    fn entries(&self) -> &[WalEntry<A>] {
+        &self.entries
+    }
+
+    async fn append(&mut self, entry: WalEntry<A>, _state: &'static str) -> Result<()> {
+        Wal::append(self, entry).await
+    }
+}
@@ -0,0 +1,276 @@
+use super::{Result, Storage, WalEntry, WalError};
+use async_trait::async_trait;
+use chrono::{SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+static SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS jobs (
+        id INTEGER PRIMARY KEY,
+        bucket TEXT NOT NULL,
+        key TEXT NOT NULL,
+        state TEXT NOT NULL,
+        created TEXT NOT NULL,
+        updated TEXT NOT NULL
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS active_jobs ON jobs (bucket, key)
+        WHERE state NOT IN ('completed', 'aborted', 'refused');
+    CREATE TABLE IF NOT EXISTS operations (
+        job_id INTEGER NOT NULL REFERENCES jobs (id),
+        seq INTEGER NOT NULL,
+        operation TEXT NOT NULL,
+        logged TEXT NOT NULL,
+        PRIMARY KEY (job_id, seq)
+    );
+";
+
+/// The states of finished jobs, which are never resumed.
+static FINISHED: [&str; 3] = ["completed", "aborted", "refused"];
+
+/// A job recorded in a state database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobRecord {
+    pub id: i64,
+    pub bucket: String,
+    pub key: String,
+    pub state: String,
+    pub created: String,
+    pub updated: String,
+}
+
+/// Logs kept in a SQLite database shared by many jobs, with a row per job
+/// holding its current state and a row per logged operation. Each job is an
+/// upload to one bucket and key, and a key can have any number of finished
+/// jobs but only one still going.
+pub struct SqliteStore<A> {
+    /// Shared with the blocking tasks writing to it.
+    conn: Arc<Mutex<Connection>>,
+    /// Held open to keep the job locked.
+    _lock: File,
+    lock_path: PathBuf,
+    job_id: i64,
+    next_seq: i64,
+    entries: Vec<WalEntry<A>>,
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn connect(path: &Path) -> std::result::Result<Connection, rusqlite::Error> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(std::time::Duration::from_secs(30))?;
+    conn.execute_batch(SCHEMA)?;
+    Ok(conn)
+}
+
+impl<A: Serialize + DeserializeOwned> SqliteStore<A> {
+    /// Open the log of the unfinished job uploading to a bucket and key,
+    /// creating the database and a new job as needed. Once a job has
+    /// completed, aborted or been refused, the next upload to its key is a
+    /// new job.
+    pub fn open(path: &Path, bucket: &str, key: &str) -> Result<Self> {
+        let load_error = |err: rusqlite::Error| {
+            WalError::LoadError(format!("error loading job from {:?}: {}", path, err))
+        };
+
+        let mut conn = connect(path).map_err(load_error)?;
+
+        // find or create the job in one transaction, so two uploads to a key
+        // starting together share a job rather than one failing on the index
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(load_error)?;
+        let found: Option<i64> = tx
+            .query_row(
+                "SELECT id FROM jobs WHERE bucket = ?1 AND key = ?2
+                 AND state NOT IN ('completed', 'aborted', 'refused')",
+                params![bucket, key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(load_error)?;
+
+        let job_id = match found {
+            Some(job_id) => job_id,
+            None => {
+                let now = now();
+                tx.execute(
+                    "INSERT INTO jobs (bucket, key, state, created, updated) VALUES (?1, ?2, 'init', ?3, ?3)",
+                    params![bucket, key, now],
+                )
+                .map_err(load_error)?;
+                tx.last_insert_rowid()
+            }
+        };
+        tx.commit().map_err(load_error)?;
+
+        let lock_path = SqliteStore::<A>::lock_path(path, job_id);
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(|err| WalError::LoadError(format!("error opening {:?}: {}", lock_path, err)))?;
+        super::lock_exclusive(&lock, &lock_path)?;
+
+        let mut entries = vec![];
+        let mut next_seq = 1;
+        {
+            let mut statement = conn
+                .prepare("SELECT seq, operation FROM operations WHERE job_id = ?1 ORDER BY seq")
+                .map_err(load_error)?;
+            let mut rows = statement.query(params![job_id]).map_err(load_error)?;
+            while let Some(row) = rows.next().map_err(load_error)? {
+                let seq: i64 = row.get(0).map_err(load_error)?;
+                let operation: String = row.get(1).map_err(load_error)?;
+                let action = serde_json::from_str(&operation).map_err(|err| {
+                    WalError::LoadError(format!("error deserialising operation {}: {}", seq, err))
+                })?;
+                entries.push(WalEntry { action });
+                next_seq = seq + 1;
+            }
+        }
+
+        Ok(SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+            _lock: lock,
+            lock_path,
+            job_id,
+            next_seq,
+            entries,
+        })
+    }
+
+    /// Jobs are locked with a file per job beside the database, leaving the
+    /// database free for other jobs. The file is removed once the job has
+    /// finished, as nothing opens the job again.
+    fn lock_path(path: &Path, job_id: i64) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(format!(".job-{}.lock", job_id));
+        path.with_file_name(name)
+    }
+}
+
+/// Write an operation and its job's new state in one transaction.
+fn insert(conn: &mut Connection, job_id: i64, seq: i64, operation: &str, state: &str) -> Result<()> {
+    let append_error = |err: rusqlite::Error| WalError::AppendError(format!("error logging operation: {}", err));
+
+    let now = now();
+    let tx = conn.transaction().map_err(append_error)?;
+    tx.execute(
+        "INSERT INTO operations (job_id, seq, operation, logged) VALUES (?1, ?2, ?3, ?4)",
+        params![job_id, seq, operation, now],
+    )
+    .map_err(append_error)?;
+    tx.execute(
+        "UPDATE jobs SET state = ?1, updated = ?2 WHERE id = ?3",
+        params![state, now, job_id],
+    )
+    .map_err(append_error)?;
+    tx.commit().map_err(append_error)
+}
+
+#[async_trait]
+impl<A: Serialize + DeserializeOwned + Send + Sync + 'static> Storage<A> for SqliteStore<A> {
+    fn entries(&self) -> &[WalEntry<A>] {
+        &self.entries
+    }
+
+    async fn append(&mut self, entry: WalEntry<A>, state: &'static str) -> Result<()> {
+        let operation = serde_json::to_string(&entry.action).map_err(|err| {
+            WalError::AppendError(format!("error serialising log entry: {}", err))
+        })?;
+        let conn = self.conn.clone();
+        let (job_id, seq) = (self.job_id, self.next_seq);
+        tokio::task::spawn_blocking(move || insert(&mut conn.lock().unwrap(), job_id, seq, &operation, state))
+            .await
+            .map_err(|err| WalError::AppendError(format!("error logging operation: {}", err)))??;
+        self.next_seq += 1;
+        self.entries.push(entry);
+
+        if FINISHED.contains(&state) {
+            // removed while still locked, and no one opens a finished job
+            let _ = fs::remove_file(&self.lock_path);
+        }
+        Ok(())
+    }
+}
+
+/// The jobs in a state database, optionally leaving out completed ones.
+pub fn jobs(path: &Path, include_completed: bool) -> Result<Vec<JobRecord>> {
+    let load_error =
+        |err: rusqlite::Error| WalError::LoadError(format!("error listing jobs in {:?}: {}", path, err));
+
+    let conn = connect(path).map_err(load_error)?;
+    let mut statement = conn
+        .prepare(
+            "SELECT id, bucket, key, state, created, updated FROM jobs
+             WHERE ?1 OR state != 'completed' ORDER BY id",
+        )
+        .map_err(load_error)?;
+    let rows = statement
+        .query_map(params![include_completed], |row| {
+            Ok(JobRecord {
+                id: row.get(0)?,
+                bucket: row.get(1)?,
+                key: row.get(2)?,
+                state: row.get(3)?,
+                created: row.get(4)?,
+                updated: row.get(5)?,
+            })
+        })
+        .map_err(load_error)?;
+
+    rows.collect::<std::result::Result<_, _>>().map_err(load_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::scratch;
+
+    fn entry(action: &str) -> WalEntry<String> {
+        WalEntry { action: action.to_owned() }
+    }
+
+    #[tokio::test]
+    async fn resumes_the_unfinished_job() {
+        let db = scratch("sqlite-resume").join("state.db");
+        let mut store = SqliteStore::open(&db, "bucket", "key").unwrap();
+        store.append(entry("first"), "uploading").await.unwrap();
+        store.append(entry("second"), "uploading").await.unwrap();
+        drop(store);
+
+        let mut store = SqliteStore::<String>::open(&db, "bucket", "key").unwrap();
+        let actions: Vec<&str> = store.entries().iter().map(|entry| entry.action.as_str()).collect();
+        assert_eq!(actions, vec!["first", "second"]);
+        assert!(SqliteStore::<String>::open(&db, "bucket", "key").is_err(), "locked");
+        assert_eq!(SqliteStore::<String>::open(&db, "bucket", "other").unwrap().job_id, 2);
+
+        store.append(entry("third"), "completing").await.unwrap();
+        let jobs = jobs(&db, true).unwrap();
+        assert_eq!(jobs[0].state, "completing");
+    }
+
+    #[tokio::test]
+    async fn finished_jobs_leave_no_lock_file() {
+        let db = scratch("sqlite-finished").join("state.db");
+        let mut store = SqliteStore::open(&db, "bucket", "key").unwrap();
+        let lock_path = store.lock_path.clone();
+        store.append(entry("first"), "uploading").await.unwrap();
+        assert!(lock_path.exists());
+        store.append(entry("done"), "completed").await.unwrap();
+        assert!(!lock_path.exists());
+        drop(store);
+
+        // and the next upload to the key is a new job
+        let store = SqliteStore::<String>::open(&db, "bucket", "key").unwrap();
+        assert_eq!(store.job_id, 2);
+        assert!(store.entries().is_empty());
+    }
+}
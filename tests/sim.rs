@@ -0,0 +1,662 @@
+//! Uploads against an in-memory store with injected failures and crashes,
+//! each of which must end completed, or cleanly aborted when a failure
+//! can't be retried away, with the store matching the outcome.
+
+use s3mu::app::App;
+use s3mu::backend::{
+    multipart_etag, Backend, Body, CompletedPart, Completion, ListedPart, MemoryBackend,
+    ObjectInfo, ObjectSettings, PartInfo, S3Error,
+};
+use s3mu::backend::memory::Object;
+use s3mu::compress::Codec;
+use s3mu::index::Index;
+use s3mu::result::Result;
+use s3mu::state::{Part, State, Verification};
+use s3mu::wal::Wal;
+use async_trait::async_trait;
+use futures::FutureExt;
+use rusoto_core::ByteStream;
+use std::fs;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once};
+
+static BUCKET: &str = "sim-bucket";
+static KEY: &str = "sim/object";
+
+/// The error codes used for injected transient failures.
+static TRANSIENT_CODES: [&str; 3] = ["InternalError", "SlowDown", "ServiceUnavailable"];
+
+/// A run that restarts more often than this is reported as stuck.
+static MAX_RESTARTS: u32 = 100;
+
+/// The panic payload used to stop the app as if the process had died.
+struct Crash;
+
+/// Simulation settings. Each run uses its own seed, `seed + run`, so a
+/// failing run can be repeated alone with that seed and `runs: 1`.
+#[derive(Debug, Clone)]
+struct Config {
+    seed: u64,
+    runs: u64,
+    tries: u32,
+    fault_rate: f64,
+    crash_rate: f64,
+    max_crashes: u32,
+    dir: PathBuf,
+}
+
+/// A small deterministic random number generator (xorshift64*), so runs
+/// repeat exactly for a seed.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // the state must never be zero
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `low..=high`.
+    fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next_u64() % (high - low + 1)
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        let unit = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        unit < probability
+    }
+}
+
+/// A failure every attempt runs into, so the run is expected to abort.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Doom {
+    None,
+    /// Every create request succeeds in the store but its response is lost.
+    Start,
+    /// Every upload of this part number is refused.
+    Part(i64),
+}
+
+struct Faults {
+    rng: Rng,
+    fault_rate: f64,
+    crash_rate: f64,
+    /// Injected errors left. Kept below the number of tries, so transient
+    /// faults alone can never exhaust the attempts at a step.
+    faults_left: u32,
+    crashes_left: u32,
+    doom: Doom,
+    injected: u32,
+    crashed: u32,
+}
+
+/// Wraps a backend, failing requests before they reach it, losing the
+/// responses of requests that succeeded, and crashing after a request
+/// succeeds but before the app can log the result.
+struct FaultyBackend<B: Backend> {
+    inner: Arc<B>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl<B: Backend> FaultyBackend<B> {
+    fn new(inner: Arc<B>, faults: Arc<Mutex<Faults>>) -> Self {
+        FaultyBackend { inner, faults }
+    }
+
+    fn before(&self, request: &str) -> Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        if faults.faults_left > 0 {
+            let rate = faults.fault_rate;
+            if faults.rng.chance(rate) {
+                faults.faults_left -= 1;
+                faults.injected += 1;
+                let code = TRANSIENT_CODES[faults.rng.range(0, 2) as usize];
+                return Err(S3Error::coded(code, format!("simulated {} failure", request)).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn after<T>(&self, request: &str, result: Result<T>) -> Result<T> {
+        let value = result?;
+
+        let mut faults = self.faults.lock().unwrap();
+        if faults.crashes_left > 0 {
+            let rate = faults.crash_rate;
+            if faults.rng.chance(rate) {
+                faults.crashes_left -= 1;
+                faults.crashed += 1;
+                drop(faults);
+                panic::panic_any(Crash);
+            }
+        }
+        if faults.faults_left > 0 {
+            let rate = faults.fault_rate;
+            if faults.rng.chance(rate) {
+                faults.faults_left -= 1;
+                faults.injected += 1;
+                return Err(lost_response(request));
+            }
+        }
+        Ok(value)
+    }
+
+    fn doom(&self) -> Doom {
+        self.faults.lock().unwrap().doom
+    }
+}
+
+fn lost_response(request: &str) -> s3mu::error::Error {
+    S3Error::coded("RequestTimeout", format!("simulated lost {} response", request)).into()
+}
+
+#[async_trait]
+impl<B: Backend> Backend for FaultyBackend<B> {
+    async fn create_upload(&self, bucket: &str, key: &str) -> Result<String> {
+        self.before("create_upload")?;
+        let result = self.inner.create_upload(bucket, key).await;
+        if self.doom() == Doom::Start {
+            return result.and_then(|_| Err(lost_response("create_upload")));
+        }
+        self.after("create_upload", result)
+    }
+
+    async fn create_upload_with(&self, bucket: &str, key: &str, settings: ObjectSettings) -> Result<String> {
+        self.before("create_upload")?;
+        let result = self.inner.create_upload_with(bucket, key, settings).await;
+        if self.doom() == Doom::Start {
+            return result.and_then(|_| Err(lost_response("create_upload")));
+        }
+        self.after("create_upload", result)
+    }
+
+    async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i64,
+        body: Body,
+    ) -> Result<String> {
+        if self.doom() == Doom::Part(part_number) {
+            return Err(S3Error::coded("AccessDenied", format!("part {} refused", part_number)).into());
+        }
+        self.before("upload_part")?;
+        let result = self
+            .inner
+            .upload_part(bucket, key, upload_id, part_number, body)
+            .await;
+        self.after("upload_part", result)
+    }
+
+    async fn complete_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        self.before("complete_upload")?;
+        let result = self.inner.complete_upload(bucket, key, upload_id, parts).await;
+        self.after("complete_upload", result)
+    }
+
+    async fn complete_upload_if_absent(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Completion> {
+        self.before("complete_upload")?;
+        let result = self
+            .inner
+            .complete_upload_if_absent(bucket, key, upload_id, parts)
+            .await;
+        self.after("complete_upload", result)
+    }
+
+    async fn abort_upload(&self, bucket: &str, key: &str, upload_id: &str) -> Result<()> {
+        self.before("abort_upload")?;
+        let result = self.inner.abort_upload(bucket, key, upload_id).await;
+        self.after("abort_upload", result)
+    }
+
+    async fn list_parts(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<Vec<ListedPart>> {
+        self.before("list_parts")?;
+        let result = self.inner.list_parts(bucket, key, upload_id).await;
+        self.after("list_parts", result)
+    }
+
+    async fn list_uploads(&self, bucket: &str, key: &str) -> Result<Vec<String>> {
+        self.before("list_uploads")?;
+        let result = self.inner.list_uploads(bucket, key).await;
+        self.after("list_uploads", result)
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<ObjectInfo>> {
+        self.before("head_object")?;
+        let result = self.inner.head_object(bucket, key).await;
+        self.after("head_object", result)
+    }
+
+    async fn put_object(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        self.before("put_object")?;
+        let result = self.inner.put_object(bucket, key, data).await;
+        self.after("put_object", result)
+    }
+
+    async fn get_object(&self, bucket: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        self.before("get_object")?;
+        let result = self.inner.get_object(bucket, key).await;
+        self.after("get_object", result)
+    }
+
+    async fn delete_object(&self, bucket: &str, key: &str) -> Result<()> {
+        self.before("delete_object")?;
+        let result = self.inner.delete_object(bucket, key).await;
+        self.after("delete_object", result)
+    }
+
+    async fn get_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        version_id: Option<&str>,
+        part_number: i64,
+    ) -> Result<Option<ByteStream>> {
+        self.before("get_part")?;
+        let result = self.inner.get_part(bucket, key, version_id, part_number).await;
+        self.after("get_part", result)
+    }
+
+    async fn head_part(&self, bucket: &str, key: &str, part_number: i64) -> Result<Option<PartInfo>> {
+        self.before("head_part")?;
+        let result = self.inner.head_part(bucket, key, part_number).await;
+        self.after("head_part", result)
+    }
+
+    async fn get_range(&self, bucket: &str, key: &str, offset: u64, len: u64) -> Result<Option<ByteStream>> {
+        self.before("get_range")?;
+        let result = self.inner.get_range(bucket, key, offset, len).await;
+        self.after("get_range", result)
+    }
+}
+
+/// Check the index of a completed object locates each part file in it.
+async fn check_index(store: &Arc<MemoryBackend>, data: &[u8]) -> Result<()> {
+    let index = Index::load(store, BUCKET, KEY)
+        .await?
+        .ok_or("completed without an index")?;
+    let mut end = 0;
+    for file in &index.files {
+        let range = file.offset as usize..(file.offset + file.len) as usize;
+        if file.offset != end || range.end > data.len() {
+            return Err(format!("index has {} out of place at {}", file.path, file.offset).into());
+        }
+        let contents = fs::read(&file.path)?;
+        if contents != data[range] || base64::encode(md5::compute(&contents).0) != file.md5 {
+            return Err(format!("index entry for {} doesn't match the object", file.path).into());
+        }
+        end += file.len;
+    }
+    if end != data.len() as u64 {
+        return Err(format!("index covers {} of {} bytes", end, data.len()).into());
+    }
+    Ok(())
+}
+
+/// Check a compressed object decompresses to the parts' data, and that the
+/// size and md5 logged for each part are those of the part the store has.
+fn check_compressed(codec: Codec, object: &Object, parts: &[Part], expected: &[u8]) -> Result<()> {
+    if object.content_encoding.as_deref() != Some(codec.content_encoding()) {
+        return Err(format!("compressed object has encoding {:?}", object.content_encoding).into());
+    }
+
+    let mut data = vec![];
+    match codec {
+        Codec::Gzip(_) => {
+            flate2::read::MultiGzDecoder::new(&object.data[..]).read_to_end(&mut data)?;
+        }
+        Codec::Zstd(_) => data = zstd::stream::decode_all(&object.data[..])?,
+    }
+    if data != expected {
+        return Err("compressed object decompresses to something other than the parts".into());
+    }
+
+    if object.part_sizes.len() != parts.len() {
+        return Err(format!("object has {} parts but {} were logged", object.part_sizes.len(), parts.len()).into());
+    }
+    let mut offset = 0;
+    for (part, &size) in parts.iter().zip(&object.part_sizes) {
+        let stored = &object.data[offset as usize..(offset + size) as usize];
+        if part.digest() != Some((size, base64::encode(md5::compute(stored).0))) {
+            return Err(format!("part {} was logged with {:?}, not what was stored", part.number, part.digest()).into());
+        }
+        offset += size;
+    }
+    Ok(())
+}
+
+/// Totals over all runs.
+#[derive(Debug, Default)]
+struct Report {
+    runs: u64,
+    completed: u64,
+    aborted: u64,
+    faults: u64,
+    crashes: u64,
+    failures: Vec<(u64, String)>,
+}
+
+/// What one run did, when it ended as expected.
+struct Outcome {
+    completed: bool,
+    faults: u32,
+    crashes: u32,
+}
+
+/// Run the simulation, collecting the seeds of runs that broke an invariant.
+async fn simulate(config: &Config) -> Result<Report> {
+    quiet_crashes();
+
+    let mut report = Report::default();
+    for run in 0..config.runs {
+        let seed = config.seed.wrapping_add(run);
+        let dir = config.dir.join(format!("run-{}", seed));
+        let result = simulate_run(config, seed, &dir).await;
+        let _ = fs::remove_dir_all(&dir);
+
+        report.runs += 1;
+        match result {
+            Ok(outcome) => {
+                if outcome.completed {
+                    report.completed += 1;
+                } else {
+                    report.aborted += 1;
+                }
+                report.faults += outcome.faults as u64;
+                report.crashes += outcome.crashes as u64;
+            }
+            Err(err) => {
+                log::error!("run with seed {} failed: {}", seed, err);
+                report.failures.push((seed, err.to_string()));
+            }
+        }
+    }
+    let _ = fs::remove_dir(&config.dir);
+
+    Ok(report)
+}
+
+/// Upload a random set of parts through a faulty backend, restarting from
+/// the log after each crash, then check the store against the outcome.
+async fn simulate_run(config: &Config, seed: u64, dir: &Path) -> Result<Outcome> {
+    let mut rng = Rng::new(seed);
+
+    let parts_dir = dir.join("parts");
+    fs::create_dir_all(&parts_dir)?;
+    let mut store = MemoryBackend::new();
+    store.min_part_size = 1024;
+    let store = Arc::new(store);
+
+    // some runs pack files smaller than the minimum part size into parts,
+    // splitting files across them
+    let pack = if rng.chance(0.25) {
+        Some(rng.range(store.min_part_size, 4096))
+    } else {
+        None
+    };
+
+    let files = rng.range(1, 6);
+    let mut expected = vec![];
+    let mut md5s = vec![];
+    for i in 0..files {
+        let size = if pack.is_some() {
+            rng.range(0, 2048)
+        } else if i == files - 1 {
+            rng.range(1, 4096)
+        } else {
+            rng.range(store.min_part_size, 4096)
+        };
+        let data: Vec<u8> = (0..size).map(|_| rng.next_u64() as u8).collect();
+        fs::write(parts_dir.join(format!("part-{:03}", i)), &data)?;
+        if pack.is_none() {
+            md5s.push(md5::compute(&data).0);
+        }
+        expected.extend_from_slice(&data);
+    }
+    if let Some(size) = pack {
+        if expected.is_empty() {
+            // nothing to pack into a part
+            expected.push(0);
+            fs::write(parts_dir.join("part-999"), &expected)?;
+        }
+        md5s = expected.chunks(size as usize).map(|chunk| md5::compute(chunk).0).collect();
+    }
+    let count = md5s.len() as u64;
+
+    let doom = match rng.range(0, 9) {
+        0 => Doom::Start,
+        1 => Doom::Part(rng.range(1, count) as i64),
+        _ => Doom::None,
+    };
+    // parts between 512 bytes and the threshold are buffered, larger ones
+    // streamed from disk
+    let buffer_threshold = rng.range(512, 4096);
+    // half the runs refuse to overwrite, which mustn't mistake the object
+    // from a completion whose response was lost for someone else's
+    let no_overwrite = rng.chance(0.5);
+    let verify = rng.chance(0.5);
+    // some runs compress the parts, whose random data comes out no smaller,
+    // and offsets in the object then aren't the files' to index
+    let compress = match rng.range(0, 5) {
+        0 => Some(Codec::Gzip(rng.range(0, 9) as u32)),
+        1 => Some(Codec::Zstd(rng.range(1, 19) as i32)),
+        _ => None,
+    };
+    let index = compress.is_none() && rng.chance(0.5);
+
+    let faults = Arc::new(Mutex::new(Faults {
+        rng,
+        fault_rate: config.fault_rate,
+        crash_rate: config.crash_rate,
+        faults_left: config.tries - 1,
+        crashes_left: config.max_crashes,
+        doom,
+        injected: 0,
+        crashed: 0,
+    }));
+
+    let log = dir.join("upload.log");
+    let pattern = parts_dir.join("part-*");
+    let pattern = pattern.to_str().ok_or("non utf8 simulation directory")?;
+
+    let mut restarts = 0;
+    let state = loop {
+        let mut app = App::new(
+            FaultyBackend::new(store.clone(), faults.clone()),
+            BUCKET,
+            KEY,
+            config.tries,
+            Box::new(Wal::open(&log).await?),
+            pattern,
+            buffer_threshold,
+        )
+        .await?;
+        app.no_overwrite = no_overwrite;
+        app.verify = verify;
+        app.pack = pack;
+        app.index = index;
+        app.compress = compress;
+        app.min_part_size = store.min_part_size;
+
+        match AssertUnwindSafe(app.run()).catch_unwind().await {
+            Ok(result) => {
+                result?;
+                break app.state;
+            }
+            Err(payload) => {
+                if !payload.is::<Crash>() {
+                    panic::resume_unwind(payload);
+                }
+                restarts += 1;
+                if restarts > MAX_RESTARTS {
+                    return Err(format!("still running after {} restarts", restarts).into());
+                }
+                log::debug!("simulated crash, restarting from the log");
+            }
+        }
+    };
+
+    let leaked = store.upload_ids();
+    if !leaked.is_empty() {
+        return Err(format!("ended {} with uploads left in progress: {:?}", state.name(), leaked).into());
+    }
+
+    let object = store.object(BUCKET, KEY);
+    let completed = match state {
+        State::Completed {
+            ref verification,
+            indexed,
+            ref parts,
+            ..
+        } => {
+            let object = object.ok_or("completed without creating the object")?;
+            match compress {
+                Some(codec) => check_compressed(codec, &object, parts, &expected)?,
+                None => {
+                    if object.data != expected {
+                        return Err("completed object content differs from the parts".into());
+                    }
+                    if object.etag != multipart_etag(&md5s) {
+                        return Err(format!("completed object has unexpected etag {}", object.etag).into());
+                    }
+                }
+            }
+            if doom != Doom::None {
+                return Err(format!("completed despite {:?}", doom).into());
+            }
+            if verify && *verification != Some(Verification::Verified) {
+                return Err(format!("completed with verification {:?}", verification).into());
+            }
+            if index {
+                if !indexed {
+                    return Err("completed without indexing".into());
+                }
+                check_index(&store, &object.data).await?;
+            }
+            true
+        }
+        State::Aborted => {
+            if object.is_some() {
+                return Err("aborted but the object was created".into());
+            }
+            if doom == Doom::None {
+                return Err("aborted with only transient faults".into());
+            }
+            false
+        }
+        state => return Err(format!("stopped in the {} state", state.name()).into()),
+    };
+
+    let faults = faults.lock().unwrap();
+    log::info!(
+        "run with seed {} {} after {} faults and {} crashes",
+        seed,
+        if completed { "completed" } else { "aborted" },
+        faults.injected,
+        faults.crashed
+    );
+
+    Ok(Outcome {
+        completed,
+        faults: faults.injected,
+        crashes: faults.crashed,
+    })
+}
+
+/// Keep simulated crashes out of the panic output, leaving real panics as
+/// they were.
+fn quiet_crashes() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !info.payload().is::<Crash>() {
+                default(info);
+            }
+        }));
+    });
+}
+
+impl Config {
+    /// Runs from `seed` with the default fault and crash rates, in a
+    /// directory of their own so tests can run at the same time.
+    fn new(name: &str, seed: u64, runs: u64) -> Self {
+        Config {
+            seed,
+            runs,
+            tries: 4,
+            fault_rate: 0.1,
+            crash_rate: 0.05,
+            max_crashes: 10,
+            dir: std::env::temp_dir().join(format!("s3mu-sim-{}-{}", std::process::id(), name)),
+        }
+    }
+}
+
+/// Simulate and fail with the seeds and errors of any runs that broke an
+/// invariant.
+async fn check(config: Config) -> Report {
+    let report = simulate(&config).await.unwrap();
+    assert!(report.failures.is_empty(), "{:?} failed: {:#?}", config, report.failures);
+    assert_eq!(report.runs, config.runs);
+    report
+}
+
+#[tokio::test]
+async fn runs_without_faults_complete() {
+    let mut config = Config::new("clean", 1, 50);
+    config.fault_rate = 0.0;
+    config.crash_rate = 0.0;
+    let report = check(config).await;
+    assert_eq!(report.faults + report.crashes, 0);
+    assert!(report.completed > 0);
+}
+
+#[tokio::test]
+async fn runs_with_faults_and_crashes_end_cleanly() {
+    let report = check(Config::new("faults", 1, 300)).await;
+    assert!(report.faults > 0 && report.crashes > 0);
+    assert!(report.completed > 0 && report.aborted > 0);
+}
+
+#[tokio::test]
+async fn runs_with_frequent_crashes_end_cleanly() {
+    let mut config = Config::new("crashes", 1000, 150);
+    config.crash_rate = 0.3;
+    config.max_crashes = 30;
+    check(config).await;
+}
+
+#[tokio::test]
+async fn runs_with_two_tries_end_cleanly() {
+    let mut config = Config::new("tries", 2000, 150);
+    config.tries = 2;
+    check(config).await;
+}